@@ -1,3 +1,5 @@
 fn main() {
-    println!("cargo::rustc-link-lib=pcap");
+    if std::env::var_os("CARGO_FEATURE_PCAP").is_some() {
+        println!("cargo::rustc-link-lib=pcap");
+    }
 }
@@ -1,20 +1,31 @@
 use std::{
-    net::Ipv4Addr,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     num::{ParseFloatError, ParseIntError},
     sync::{Arc, Mutex},
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use eui48::MacAddress;
-use log::{debug, warn};
+use log::{debug, error, warn};
 
 use crate::{
     crypto::AesCtx,
-    lib::validate,
+    lib::{
+        blacklist::{parse_prefix, Blacklist},
+        checkpoint::{self, Checkpoint},
+        ip_set::IpSet,
+        ipv6_target_file::Ipv6TargetFile,
+        rate_limiter::RateLimiter,
+        validate,
+    },
     net::{get_default_gw_mac, get_default_interface, get_interface_ip},
-    probe_modules::module_tcp_synscan,
-    state::{ReceiverState, SenderState},
+    output_modules::registry::{
+        get_output_module, print_output_fields, print_output_modules, select_fields,
+    },
+    probe_modules::probe_modules::{get_probe_module, print_probe_modules, ProbeModule},
+    state::{ReceiverState, SenderState, SenderThreads},
 };
 
 fn parse_duration(arg: &str) -> Result<Duration, ParseIntError> {
@@ -55,6 +66,73 @@ fn parse_max_targets(arg: &str) -> Result<u32, ParseFloatError> {
     Ok(targets as u32)
 }
 
+/// Ports given with --target-ports, sorted and without duplicates
+#[derive(Debug, Clone)]
+pub struct PortList(Vec<u16>);
+
+fn parse_port_list(arg: &str) -> Result<PortList, String> {
+    let parse_port = |port: &str| {
+        port.trim()
+            .parse::<u16>()
+            .map_err(|e| format!("invalid port '{}': {}", port, e))
+    };
+
+    let mut ports = vec![];
+    for range in arg.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_port(first)?, parse_port(last)?);
+                if first > last {
+                    return Err(format!("invalid port range '{}'", range));
+                }
+                ports.extend(first..=last);
+            }
+            None => ports.push(parse_port(range)?),
+        }
+    }
+
+    ports.sort_unstable();
+    ports.dedup();
+    Ok(PortList(ports))
+}
+
+fn parse_probe_module(arg: &str) -> Result<String, String> {
+    match get_probe_module(arg) {
+        Some(_) => Ok(arg.to_string()),
+        None => Err(String::from(
+            "unknown probe module (see --list-probe-modules for available modules)",
+        )),
+    }
+}
+
+fn parse_output_module(arg: &str) -> Result<String, String> {
+    match get_output_module(arg) {
+        Some(_) => Ok(arg.to_string()),
+        None => Err(String::from(
+            "unknown output module (see --list-output-modules for available modules)",
+        )),
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendBackend {
+    /// Raw socket, sending batches with sendmmsg
+    Socket,
+    /// Memory mapped transmit ring (PACKET_TX_RING), without a copy per packet
+    TxRing,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RecvBackend {
+    /// libpcap capture, one packet at a time
+    #[cfg(feature = "pcap")]
+    #[default]
+    Pcap,
+    /// Memory mapped receive ring (PACKET_RX_RING, TPACKET_V3), read a block at a time in place
+    #[cfg_attr(not(feature = "pcap"), default)]
+    RxRing,
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 pub struct Config {
@@ -62,10 +140,36 @@ pub struct Config {
     #[arg(short = 'p', long, default_value_t = 443)]
     pub target_port: u16,
 
+    /// Ports to scan instead of --target-port, e.g. 22,80,443,8000-8100
+    #[arg(long, value_parser = parse_port_list)]
+    pub target_ports: Option<PortList>,
+
     /// Output file
     #[arg(short, long, default_value_t = String::from("recv.log"))]
     pub output_file: String,
 
+    /// Select output module
+    #[arg(short = 'O', long, value_parser = parse_output_module, default_value = "plain")]
+    pub output_module: String,
+
+    /// Comma separated list of fields to output (saddr by default, sport when scanning several
+    /// ports and classification with --output-unsuccessful)
+    #[arg(short = 'f', long, value_delimiter = ',')]
+    pub output_fields: Vec<String>,
+
+    /// Also write replies that aren't successes, e.g. RSTs or ICMP unreachables, which are
+    /// otherwise only counted
+    #[arg(long)]
+    pub output_unsuccessful: bool,
+
+    /// Print all available output modules and exit
+    #[arg(long)]
+    pub list_output_modules: bool,
+
+    /// Print all output fields available with the selected probe module and exit
+    #[arg(long)]
+    pub list_output_fields: bool,
+
     /// File of subnets to exclude, in CIDR notation
     #[arg(short, long)]
     pub blacklist_file: Option<String>,
@@ -74,7 +178,16 @@ pub struct Config {
     #[arg(short, long)]
     pub whitelist_file: Option<String>,
 
-    /// Cap number of targets to probe as a percentage of the address space
+    /// File of individual addresses to scan, one per line, for lists too large for a whitelist
+    #[arg(long)]
+    pub list_of_ips: Option<String>,
+
+    /// Addresses or subnets in CIDR notation to scan, in addition to any --whitelist-file
+    #[arg(value_parser = parse_prefix)]
+    pub targets: Vec<(Ipv4Addr, i32)>,
+
+    /// Cap number of targets to probe as a percentage of the address space, or of the targets in
+    /// --ipv6-target-file for IPv6 probe modules
     #[arg(short = 'n', long, value_parser = parse_max_targets, default_value = "100%")]
     pub max_targets: u32,
 
@@ -90,22 +203,91 @@ pub struct Config {
     #[arg(short, long, default_value_t = 0)]
     pub rate: i32,
 
+    /// Packets that may be sent back to back to catch up with --rate or --bandwidth
+    #[arg(long, default_value_t = 64)]
+    pub rate_burst: u32,
+
     /// Set send rate in bits/second (supports suffixes G, M and K)
     #[arg(short = 'B', long, value_parser = parse_bandwidth, default_value = "0K")]
     pub bandwidth: u64,
 
+    /// Lower the send rate while the receiver drops packets or sends fail, and raise it back
+    /// once they clear (requires --rate or --bandwidth)
+    #[arg(long)]
+    pub adaptive_rate: bool,
+
+    /// Lowest send rate in packets/sec for --adaptive-rate (1% of the rate by default)
+    #[arg(long)]
+    pub min_rate: Option<u32>,
+
+    /// Highest send rate in packets/sec for --adaptive-rate (the rate by default)
+    #[arg(long)]
+    pub max_rate: Option<u32>,
+
     /// How long to continue receiving after sending last probe
     #[arg(short, long, value_parser = parse_duration, default_value = "8")]
     pub cooldown_secs: Duration,
 
-    /// Seed used to select address permutation
-    #[arg(short = 'e', long, default_value_t = 0)]
-    pub seed: u32,
+    /// Seed used to select address permutation (random if not given)
+    #[arg(short = 'e', long)]
+    pub seed: Option<u64>,
+
+    /// Also derive the validation key from the seed, so that probes are identical across runs
+    #[arg(long)]
+    pub seed_validation: bool,
+
+    /// File with the validation key as hex, created with a random key if it doesn't exist, so
+    /// that replies can be validated later or by another process (e.g. with --replay-pcap)
+    #[arg(long)]
+    pub validation_key_file: Option<String>,
+
+    /// Total number of shards the scan is split into
+    #[arg(long, default_value_t = 1)]
+    pub shards: u32,
+
+    /// Shard this instance scans, from 0 to shards - 1 (all shards must use the same --seed)
+    #[arg(long, default_value_t = 0)]
+    pub shard: u32,
+
+    /// Periodically save the sender's progress to this file, see --resume
+    #[arg(long)]
+    pub checkpoint_file: Option<String>,
+
+    /// How often to save a checkpoint, in seconds
+    #[arg(long, value_parser = parse_duration, default_value = "60")]
+    pub checkpoint_interval: Duration,
+
+    /// Continue an interrupted scan from a checkpoint, which is then also kept up to date. After a
+    /// crash, up to --batch targets per sender thread may be skipped.
+    #[arg(long)]
+    pub resume: Option<String>,
 
     /// Threads used to send packets
     #[arg(short = 'T', long, default_value_t = 1)]
     pub sender_threads: i32,
 
+    /// Threads used to receive replies, each capturing a share of the interface's traffic
+    /// (PACKET_FANOUT). More than one needs --recv-backend rx-ring.
+    #[arg(long, default_value_t = 1)]
+    pub receiver_threads: u32,
+
+    /// How probes are handed to the kernel
+    #[arg(long, value_enum, default_value_t = SendBackend::Socket)]
+    pub send_backend: SendBackend,
+
+    /// How replies are captured
+    #[arg(long, value_enum, default_value_t = RecvBackend::default())]
+    pub recv_backend: RecvBackend,
+
+    /// Read replies from a pcap or pcapng file instead of scanning, validating them with the key
+    /// of the original scan (its --validation-key-file, or its --seed with --seed-validation)
+    #[arg(long)]
+    pub replay_pcap: Option<String>,
+
+    /// Number of packets each sender thread hands to the kernel in a single system call
+    #[arg(long, default_value_t = 64)]
+    pub batch: u32,
+
     /// Number of probes to send to each IP
     #[arg(short = 'P', long, default_value_t = 1)]
     pub probes: u32,
@@ -130,6 +312,14 @@ pub struct Config {
     #[arg(long, default_value = "0.0.0.0")]
     pub source_ip_last: Ipv4Addr,
 
+    /// Source address for IPv6 scan packets
+    #[arg(long)]
+    pub ipv6_source_ip: Option<Ipv6Addr>,
+
+    /// File of IPv6 addresses to scan, one per line (for IPv6 probe modules)
+    #[arg(long)]
+    pub ipv6_target_file: Option<String>,
+
     /// Specify network interface to use
     #[arg(short, long, default_value = "")]
     pub interface: String,
@@ -145,26 +335,156 @@ pub struct Config {
     /// Whether to use naive probe generator
     #[arg(short = 'N', long)]
     pub naive_probes: bool,
+
+    /// Select probe module
+    #[arg(short = 'M', long, value_parser = parse_probe_module, default_value = "tcp_synscan")]
+    pub probe_module: String,
+
+    /// Arguments to pass to the probe module
+    #[arg(long)]
+    pub probe_args: Option<String>,
+
+    /// Print all available probe modules and exit
+    #[arg(long)]
+    pub list_probe_modules: bool,
+}
+
+impl Config {
+    /// Ports to scan in ascending order, from --target-ports or else --target-port
+    pub fn target_ports(&self) -> &[u16] {
+        match &self.target_ports {
+            Some(PortList(ports)) => ports,
+            None => std::slice::from_ref(&self.target_port),
+        }
+    }
+
+    pub fn is_target_port(&self, port: u16) -> bool {
+        self.target_ports().binary_search(&port).is_ok()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Context {
     pub config: Config,
     pub validate_ctx: AesCtx,
+    pub probe_module: Arc<dyn ProbeModule>,
     pub sender_state: Arc<Mutex<SenderState>>,
+    pub sender_threads: Arc<SenderThreads>,
+    // Shared by all sender threads, if the rate is limited
+    pub rate_limiter: Option<Arc<RateLimiter>>,
     pub receiver_state: Arc<Mutex<ReceiverState>>,
+    pub checkpoint: Option<Checkpoint>,
+    pub ip_list: Option<Arc<IpSet>>,
+    // Addresses to scan, after applying the whitelist, blacklist and --list-of-ips
+    pub num_addresses: u64,
+    // See checkpoint::blocklist_hash, only set when checkpointing
+    pub blocklist_hash: u64,
+    // Targets in --ipv6-target-file, which IPv6 probe modules scan instead of the cyclic
+    pub num_ipv6_targets: u64,
 }
 
 impl Context {
-    pub fn new(config: Config) -> Self {
-        let validate_ctx = validate::new_context();
+    pub fn new(config: Config, probe_module: Box<dyn ProbeModule>) -> Self {
+        let validate_ctx = match config.seed {
+            Some(seed) if config.seed_validation => validate::new_context_from_seed(seed),
+            _ => validate::new_context(),
+        };
         let sender_stats = Arc::new(Mutex::new(SenderState::default()));
         let receiver_stats = Arc::new(Mutex::new(ReceiverState::default()));
+        let sender_threads = Arc::new(SenderThreads::new(config.sender_threads as usize));
+        let rate_limiter = (config.rate > 0)
+            .then(|| Arc::new(RateLimiter::new(config.rate as f64, config.rate_burst)));
         Self {
             config,
             validate_ctx,
+            probe_module: probe_module.into(),
             sender_state: sender_stats,
+            sender_threads,
+            rate_limiter,
             receiver_state: receiver_stats,
+            checkpoint: None,
+            ip_list: None,
+            num_addresses: 0,
+            blocklist_hash: 0,
+            num_ipv6_targets: 0,
+        }
+    }
+
+    /// Continue the scan from a checkpoint loaded with --resume
+    pub fn with_checkpoint(mut self, checkpoint: Option<Checkpoint>) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// Validate probes with this key instead of a random one, see --validation-key-file
+    pub fn with_validation_key(mut self, key: Option<[u8; 16]>) -> Self {
+        if let Some(key) = key {
+            self.validate_ctx = AesCtx::new(&key);
+        }
+        self
+    }
+
+    /// Only scan the addresses loaded with --list-of-ips, which must all be allowed by the blacklist
+    pub fn with_ip_list(mut self, ip_list: Option<IpSet>) -> Self {
+        self.ip_list = ip_list.map(Arc::new);
+        self
+    }
+
+    pub fn with_num_addresses(mut self, num_addresses: u64) -> Self {
+        self.num_addresses = num_addresses;
+        self
+    }
+
+    pub fn with_blocklist_hash(mut self, blocklist_hash: u64) -> Self {
+        self.blocklist_hash = blocklist_hash;
+        self
+    }
+
+    pub fn with_num_ipv6_targets(mut self, num_ipv6_targets: u64) -> Self {
+        self.num_ipv6_targets = num_ipv6_targets;
+        self
+    }
+
+    /// Number of (address, port) targets permuted by the cyclic
+    pub fn num_targets(&self) -> u64 {
+        self.num_addresses * self.config.target_ports().len() as u64
+    }
+
+    /// Cap on the targets of the whole scan. --max-targets is a share of the IPv4 address space,
+    /// so it covers every port, and of the target file for IPv6 probe modules.
+    pub fn max_targets(&self) -> u64 {
+        let max_targets = self.config.max_targets as u64;
+        if !self.probe_module.ipv6() {
+            max_targets * self.config.target_ports().len() as u64
+        } else if self.config.max_targets == u32::MAX {
+            self.num_ipv6_targets
+        } else {
+            ((self.num_ipv6_targets as u128 * max_targets as u128) >> 32) as u64
+        }
+    }
+
+    /// The `index`-th address to scan: from --list-of-ips if given, otherwise from the addresses
+    /// allowed by the blacklist
+    pub fn address(&self, blacklist: &Blacklist, index: u64) -> Ipv4Addr {
+        match &self.ip_list {
+            Some(ip_list) => ip_list.nth(index),
+            None => blacklist.lookup_index(index),
+        }
+    }
+
+    /// File checkpoints are written to, if any
+    pub fn checkpoint_file(&self) -> Option<&str> {
+        self.config
+            .checkpoint_file
+            .as_deref()
+            .or(self.config.resume.as_deref())
+    }
+
+    /// Address probes are sent from, which is an IPv6 address for IPv6 probe modules
+    pub fn source_ip(&self) -> IpAddr {
+        match self.config.ipv6_source_ip {
+            Some(ip) if self.probe_module.ipv6() => ip.into(),
+            _ => self.config.source_ip_first.into(),
         }
     }
 }
@@ -172,24 +492,267 @@ impl Context {
 pub fn create_context() -> Context {
     let mut config = Config::parse();
 
-    if config.interface.is_empty() {
-        config.interface = get_default_interface().unwrap();
+    if config.list_probe_modules {
+        print_probe_modules();
+        std::process::exit(0);
+    }
+
+    if config.list_output_modules {
+        print_output_modules();
+        std::process::exit(0);
     }
 
-    if config.source_ip_first == Ipv4Addr::new(0, 0, 0, 0) {
-        let ip = get_interface_ip(&config.interface).unwrap();
-        config.source_ip_first = ip;
-        config.source_ip_last = ip;
+    let mut probe_module = get_probe_module(&config.probe_module).unwrap();
+    if let Err(e) = probe_module.global_initialize(&config) {
+        error!(
+            "Failed to initialize probe module {}: {}",
+            probe_module.name(),
+            e
+        );
+        std::process::exit(1);
+    }
+    debug!("Using probe module {}", probe_module.name());
+
+    if config.list_output_fields {
+        print_output_fields(probe_module.as_ref());
+        std::process::exit(0);
+    }
+
+    if config.output_fields.is_empty() {
+        config.output_fields.push("saddr".to_string());
+        if config.target_ports().len() > 1 {
+            config.output_fields.push("sport".to_string());
+        }
+        // Otherwise failures couldn't be told apart from successes
+        if config.output_unsuccessful {
+            config.output_fields.push("classification".to_string());
+        }
+    }
+
+    if let Err(e) = select_fields(&config.output_fields, probe_module.as_ref()) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Replays only need the scan's source address to tell which replies were addressed to it
+    if probe_module.ipv6()
+        && (config.ipv6_source_ip.is_none()
+            || (config.ipv6_target_file.is_none() && config.replay_pcap.is_none()))
+    {
+        error!(
+            "Probe module {} requires --ipv6-source-ip and --ipv6-target-file",
+            probe_module.name()
+        );
+        std::process::exit(1);
+    }
+
+    // The target file is read as it is scanned, so there is no position to save
+    if probe_module.ipv6() && (config.checkpoint_file.is_some() || config.resume.is_some()) {
+        error!(
+            "Probe module {} can't be used with --checkpoint-file or --resume",
+            probe_module.name()
+        );
+        std::process::exit(1);
+    }
+
+    // Counted up front, so that the monitor can show progress and --max-targets can be applied
+    let num_ipv6_targets = match &config.ipv6_target_file {
+        Some(file) if probe_module.ipv6() && config.replay_pcap.is_none() => {
+            match Ipv6TargetFile::open(file) {
+                Ok(targets) => targets.quiet().count() as u64,
+                Err(e) => {
+                    error!("Could not read IPv6 target file {}: {}", file, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => 0,
+    };
+
+    if config.sender_threads < 1 {
+        error!("--sender-threads must be at least 1");
+        std::process::exit(1);
+    }
+
+    if config.receiver_threads < 1 {
+        error!("--receiver-threads must be at least 1");
+        std::process::exit(1);
+    }
+
+    if config.receiver_threads > 1 && config.recv_backend != RecvBackend::RxRing {
+        error!("--receiver-threads above 1 needs --recv-backend rx-ring");
+        std::process::exit(1);
+    }
+
+    if config.receiver_threads > 1 && config.replay_pcap.is_some() {
+        error!("--replay-pcap reads the file on a single receiver thread, --receiver-threads must be 1");
+        std::process::exit(1);
+    }
+
+    if config.batch < 1 {
+        error!("--batch must be at least 1");
+        std::process::exit(1);
+    }
+
+    if config.shards == 0 || config.shard >= config.shards {
+        error!(
+            "Invalid shard {} of {}, --shard must be between 0 and --shards - 1",
+            config.shard, config.shards
+        );
+        std::process::exit(1);
+    }
+
+    if config.shards > 1 {
+        // Shards only partition the address space if they all walk the same permutation
+        if config.seed.is_none() && config.resume.is_none() {
+            error!("--seed is required when sharding a scan");
+            std::process::exit(1);
+        }
+        if probe_module.ipv6() {
+            error!("Sharding is not supported for IPv6 scans");
+            std::process::exit(1);
+        }
+    }
+
+    if probe_module.ipv6() && !config.targets.is_empty() {
+        error!("IPv6 targets must be given with --ipv6-target-file");
+        std::process::exit(1);
+    }
+
+    if probe_module.ipv6() && config.target_ports().len() > 1 {
+        error!("Scanning several ports is not supported for IPv6 scans");
+        std::process::exit(1);
+    }
+
+    if probe_module.ipv6() && (config.checkpoint_file.is_some() || config.resume.is_some()) {
+        error!("Checkpoints are not supported for IPv6 scans");
+        std::process::exit(1);
+    }
+
+    if probe_module.ipv6() && config.list_of_ips.is_some() {
+        error!("IPv6 targets must be given with --ipv6-target-file");
+        std::process::exit(1);
+    }
+
+    // Count the addresses to scan up front, so that the cyclic only permutes those
+    let blacklist = Blacklist::new(
+        config.whitelist_file.clone(),
+        config.blacklist_file.clone(),
+        &config.targets,
+    );
+    let ip_list = config.list_of_ips.as_ref().map(|file| {
+        let mut ip_list = IpSet::open(file).unwrap_or_else(|e| {
+            error!("Could not read list of IPs {}: {}", file, e);
+            std::process::exit(1);
+        });
+        let listed = ip_list.len();
+        ip_list.retain(|ip| blacklist.is_allowed(ip));
+        debug!(
+            "Loaded {} addresses from {}, {} of them allowed",
+            listed,
+            file,
+            ip_list.len()
+        );
+        ip_list
+    });
+    let num_addresses = match &ip_list {
+        Some(ip_list) => ip_list.len(),
+        None => blacklist.count_allowed(),
+    };
+    let num_targets = num_addresses * config.target_ports().len() as u64;
+
+    // Hashed once here rather than for every checkpoint, as the files can be large
+    let blocklist_hash = if config.checkpoint_file.is_some() || config.resume.is_some() {
+        checkpoint::blocklist_hash(&config).unwrap_or_else(|e| {
+            error!("Could not hash the blocklist for checkpoints: {}", e);
+            std::process::exit(1);
+        })
+    } else {
+        0
+    };
+
+    let checkpoint = config.resume.clone().map(|file| {
+        let checkpoint = Checkpoint::read(&file).unwrap_or_else(|e| {
+            error!("Could not resume scan: {}", e);
+            std::process::exit(1);
+        });
+        config.seed.get_or_insert(checkpoint.seed);
+        if let Err(e) = checkpoint.verify(&config, num_targets, blocklist_hash) {
+            error!("Could not resume scan from {}: {}", file, e);
+            std::process::exit(1);
+        }
+        checkpoint
+    });
+
+    if config.seed_validation && config.validation_key_file.is_some() {
+        error!("--seed-validation and --validation-key-file can't be used together");
+        std::process::exit(1);
     }
 
-    if config.gw_mac == MacAddress::parse_str("00:00:00:00:00:00").unwrap() {
-        config.gw_mac = get_default_gw_mac().unwrap();
+    if config.replay_pcap.is_some() {
+        if config.validation_key_file.is_none()
+            && (config.seed.is_none() || !config.seed_validation)
+        {
+            error!("--replay-pcap needs the validation key of the original scan, pass its --validation-key-file or its --seed with --seed-validation");
+            std::process::exit(1);
+        }
+        if !probe_module.ipv6() && config.source_ip_first == Ipv4Addr::new(0, 0, 0, 0) {
+            error!("--replay-pcap needs the source addresses of the original scan, pass its --source-ip-first");
+            std::process::exit(1);
+        }
+        if config.source_ip_last == Ipv4Addr::new(0, 0, 0, 0) {
+            config.source_ip_last = config.source_ip_first;
+        }
+    }
+
+    let validation_key = config.validation_key_file.as_deref().map(|file| {
+        match validate::read_key_file(file) {
+            Ok(key) => {
+                debug!("Loaded validation key from {}", file);
+                key
+            }
+            // Replays need the key of the original scan, not a new one
+            Err(e) if e.kind() == io::ErrorKind::NotFound && config.replay_pcap.is_none() => {
+                let key = validate::new_key();
+                validate::write_key_file(file, &key).unwrap_or_else(|e| {
+                    error!("Could not write validation key to {}: {}", file, e);
+                    std::process::exit(1);
+                });
+                debug!("Saved new validation key to {}", file);
+                key
+            }
+            Err(e) => {
+                error!("Could not read validation key from {}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    // Pick a seed if none was given, so that the scan can still be reproduced from the summary
+    let seed = *config.seed.get_or_insert_with(rand::random);
+    debug!("Using seed {}", seed);
+
+    // Nothing is sent or captured when replaying, so the network setup doesn't matter
+    if config.replay_pcap.is_none() {
+        if config.interface.is_empty() {
+            config.interface = get_default_interface().unwrap();
+        }
+
+        if !probe_module.ipv6() && config.source_ip_first == Ipv4Addr::new(0, 0, 0, 0) {
+            let ip = get_interface_ip(&config.interface).unwrap();
+            config.source_ip_first = ip;
+            config.source_ip_last = ip;
+        }
+
+        if config.gw_mac == MacAddress::parse_str("00:00:00:00:00:00").unwrap() {
+            config.gw_mac = get_default_gw_mac().unwrap();
+        }
     }
 
     // From send.c (sender rate config)
     if config.bandwidth > 0 {
-        // Packet length will be 84 bytes or 672 bits
-        let mut packet_len = module_tcp_synscan::PACKET_LENGTH;
+        // Packets are padded to at least 84 bytes or 672 bits on the wire
+        let mut packet_len = probe_module.packet_length();
         packet_len *= 8;
         packet_len += 8 * 24; // 7 byte MAC preamble, 1 byte Start frame,
                               // 4 byte CRC, 12 byte inter-frame gap
@@ -215,5 +778,70 @@ pub fn create_context() -> Context {
         );
     }
 
-    Context::new(config)
+    if config.adaptive_rate {
+        if config.rate <= 0 {
+            error!("--adaptive-rate requires --rate or --bandwidth");
+            std::process::exit(1);
+        }
+        let max_rate = *config.max_rate.get_or_insert(config.rate as u32);
+        let min_rate = *config
+            .min_rate
+            .get_or_insert((max_rate.min(config.rate as u32) / 100).max(1));
+        if min_rate < 1 || min_rate > max_rate {
+            error!(
+                "Invalid adaptive rate bounds {} to {} pkt/s, --min-rate must be between 1 and --max-rate",
+                min_rate, max_rate
+            );
+            std::process::exit(1);
+        }
+        config.rate = config.rate.clamp(min_rate as i32, max_rate as i32);
+        debug!(
+            "Adaptive send rate between {} and {} pkt/s, starting at {} pkt/s",
+            min_rate, max_rate, config.rate
+        );
+    }
+
+    Context::new(config, probe_module)
+        .with_checkpoint(checkpoint)
+        .with_ip_list(ip_list)
+        .with_num_addresses(num_addresses)
+        .with_blocklist_hash(blocklist_hash)
+        .with_num_ipv6_targets(num_ipv6_targets)
+        .with_validation_key(validation_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_ports() {
+        let config = Config::parse_from(["zmap-rs", "-p", "80"]);
+        assert_eq!(config.target_ports(), [80]);
+
+        let config = Config::parse_from(["zmap-rs", "--target-ports", "8000-8002,22,443,22"]);
+        assert_eq!(config.target_ports(), [22, 443, 8000, 8001, 8002]);
+        assert!(config.is_target_port(8001));
+        assert!(!config.is_target_port(80));
+
+        assert!(parse_port_list("443-80").is_err());
+        assert!(parse_port_list("22,http").is_err());
+    }
+
+    #[test]
+    fn test_max_targets() {
+        let config = Config::parse_from(["zmap-rs", "--target-ports", "80,443", "-n", "50%"]);
+        let ctx = Context::new(config, get_probe_module("tcp_synscan").unwrap());
+        assert_eq!(ctx.max_targets(), 2 << 31);
+
+        // IPv6 probe modules take a share of the target file, for a single port
+        let config = Config::parse_from(["zmap-rs", "--target-ports", "80,443", "-n", "50%"]);
+        let ctx = Context::new(config, get_probe_module("ipv6_tcp_synscan").unwrap())
+            .with_num_ipv6_targets(1001);
+        assert_eq!(ctx.max_targets(), 500);
+        let config = Config::parse_from(["zmap-rs", "-p", "80"]);
+        let ctx = Context::new(config, get_probe_module("ipv6_tcp_synscan").unwrap())
+            .with_num_ipv6_targets(1001);
+        assert_eq!(ctx.max_targets(), 1001);
+    }
 }
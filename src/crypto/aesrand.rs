@@ -1,5 +1,3 @@
-use rand::prelude::*;
-
 use super::AesCtx;
 
 pub struct AesRand {
@@ -8,9 +6,10 @@ pub struct AesRand {
 }
 
 impl AesRand {
-    pub fn new() -> Self {
-        let mut rng = rand::thread_rng();
-        let key: [u8; 16] = rng.gen();
+    // The same seed always produces the same stream of words
+    pub fn from_seed(seed: u64) -> Self {
+        let mut key = [0u8; 16];
+        key[0..8].copy_from_slice(&seed.to_be_bytes());
         Self {
             ctx: AesCtx::new(&key),
             counter: 0,
@@ -1,82 +1,180 @@
-use std::net::Ipv4Addr;
-
 use log::debug;
-use num::BigInt;
 
 use super::AesRand;
 
-const PRIME: u64 = 4294967311;
-const KNOWN_GENERATOR: u64 = 3;
-const psub1_f: [u64; 5] = [2, 3, 5, 131, 364289];
-
-fn check_coprime(check: u64) -> bool {
-    for &f in psub1_f.iter() {
-        if f > check && (f % check == 0) {
-            return false;
-        } else if f < check && (check % f == 0) {
-            return false;
-        } else if f == check {
-            return false;
+/// A multiplicative group modulo a prime: the prime, a known generator, and the distinct prime
+/// factors of the order of the group (prime - 1)
+type Group = (u64, u64, &'static [u64]);
+
+// The smallest prime above 2^(8 + i), so that the walk over GROUPS[i] visits at most twice as
+// many elements as there are targets
+const GROUPS: [Group; 41] = [
+    (257, 3, &[2]),
+    (521, 3, &[2, 5, 13]),
+    (1031, 14, &[2, 5, 103]),
+    (2053, 2, &[2, 3, 19]),
+    (4099, 2, &[2, 3, 683]),
+    (8209, 7, &[2, 3, 19]),
+    (16411, 3, &[2, 3, 5, 547]),
+    (32771, 2, &[2, 5, 29, 113]),
+    (65537, 3, &[2]),
+    (131101, 17, &[2, 3, 5, 19, 23]),
+    (262147, 2, &[2, 3, 43691]),
+    (524309, 2, &[2, 23, 41, 139]),
+    (1048583, 5, &[2, 29, 101, 179]),
+    (2097169, 47, &[2, 3, 43691]),
+    (4194319, 3, &[2, 3, 699053]),
+    (8388617, 3, &[2, 17, 61681]),
+    (16777259, 2, &[2, 23, 103, 3541]),
+    (33554467, 2, &[2, 3, 11, 56489]),
+    (67108879, 3, &[2, 3, 1242757]),
+    (134217757, 5, &[2, 3, 1242757]),
+    (268435459, 2, &[2, 3, 19, 87211]),
+    (536870923, 3, &[2, 3, 7, 23, 555767]),
+    (1073741827, 2, &[2, 3, 59, 3033169]),
+    (2147483659, 2, &[2, 3, 149, 2402107]),
+    (4294967311, 3, &[2, 3, 5, 131, 364289]),
+    (8589934609, 19, &[2, 3, 59, 3033169]),
+    (17179869209, 3, &[2, 83, 1277, 20261]),
+    (34359738421, 2, &[2, 3, 5, 7, 81808901]),
+    (68719476767, 5, &[2, 163, 883, 238727]),
+    (137438953481, 3, &[2, 5, 137, 953, 26317]),
+    (274877906951, 7, &[2, 5, 35573, 154543]),
+    (549755813911, 3, &[2, 3, 5, 383, 47846459]),
+    (1099511627791, 3, &[2, 3, 5, 36650387593]),
+    (2199023255579, 2, &[2, 277, 3969356057]),
+    (4398046511119, 7, &[2, 3, 13, 71, 227, 3498493]),
+    (8796093022237, 5, &[2, 3, 13, 71, 227, 3498493]),
+    (17592186044423, 5, &[2, 11, 53, 97, 155542661]),
+    (35184372088891, 3, &[2, 3, 5, 19, 120739, 511243]),
+    (70368744177679, 3, &[2, 3, 1947973, 6020681]),
+    (140737488355333, 6, &[2, 3, 11, 19, 331, 18837001]),
+    (281474976710677, 6, &[2, 3, 7, 1361, 2462081249]),
+];
+
+fn mod_mul(a: u64, b: u64, prime: u64) -> u64 {
+    (a as u128 * b as u128 % prime as u128) as u64
+}
+
+fn mod_pow(base: u64, mut exp: u64, prime: u64) -> u64 {
+    let mut base = base % prime;
+    let mut result = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mod_mul(result, base, prime);
         }
+        base = mod_mul(base, base, prime);
+        exp >>= 1;
     }
-    true
+    result
 }
 
-fn find_generator(aes: &mut AesRand) -> u64 {
+/// Size of a shard when splitting `total` items as evenly as possible between `num_shards`
+pub fn shard_size(total: u64, shard: u32, num_shards: u32) -> u64 {
+    let num_shards = num_shards as u64;
+    total / num_shards + ((shard as u64) < total % num_shards) as u64
+}
+
+// known_generator^k is a generator for any k coprime to the order of the group
+fn find_generator(group: &Group, aes: &mut AesRand) -> u64 {
+    let (prime, known_generator, factors) = *group;
     let mut candidate = (aes.get_word() & 0xFFFF) as u64;
-    while !check_coprime(candidate) {
+    while factors.iter().any(|f| candidate.is_multiple_of(*f)) {
         candidate += 1;
     }
-
-    // KNOWN_GENERATOR ^ candidate mod PRIME
-    let base = BigInt::from(KNOWN_GENERATOR);
-    let exp = BigInt::from(candidate);
-    let prime = BigInt::from(PRIME);
-    let result = base.modpow(&exp, &prime);
-
-    let digits = result.to_u64_digits().1;
-    assert_eq!(digits.len(), 1);
-    digits[0]
+    mod_pow(known_generator, candidate, prime)
 }
 
+/// Pseudorandom permutation of the indexes of `num_targets` targets, walking the smallest cyclic
+/// group with at least as many elements. Element `e` stands for index `e - 1`, and elements past
+/// the last target are skipped.
+#[derive(Clone)]
 pub struct Cyclic {
+    prime: u64,
+    num_targets: u64,
     generator: u64,
+    // Step between consecutive elements, generator^num_shards
+    factor: u64,
     current: u64,
+    // Elements left to visit in this shard
+    remaining: u64,
 }
 
 impl Cyclic {
-    pub fn new() -> Self {
-        let mut aes = AesRand::new();
-        let current = (aes.get_word() & 0xFFFFFFFF) as u64;
-        let mut generator;
-        loop {
-            generator = find_generator(&mut aes);
-            if generator < (1u64 << 32) {
-                break;
-            }
-        }
+    /// The seed determines both the generator and the starting point, so scans with the same seed
+    /// and targets probe them in the same order
+    pub fn new(seed: u64, num_targets: u64) -> Self {
+        let group = GROUPS
+            .iter()
+            .find(|group| group.0 > num_targets)
+            .unwrap_or_else(|| panic!("Too many targets: {}", num_targets));
+        let prime = group.0;
+        let mut aes = AesRand::from_seed(seed);
+
+        // Zero isn't an element of the group
+        let current = (aes.get_word() % (prime - 1) as u128) as u64 + 1;
+        let generator = find_generator(group, &mut aes);
 
         debug!(
-            "Cyclic initialized with generator: {} and starting point: {}",
+            "Cyclic initialized with prime: {}, generator: {} and starting point: {}",
+            prime, generator, current
+        );
+        Self {
+            prime,
+            num_targets,
             generator,
-            Ipv4Addr::from((current as u32).to_be())
+            factor: generator,
+            current,
+            remaining: prime - 1,
+        }
+    }
+
+    /// Restrict the walk to one of `num_shards` disjoint slices of the permutation. Shard `i` visits
+    /// every `num_shards`-th element starting from the `i`-th, so instances using the same seed
+    /// together visit every element exactly once.
+    pub fn with_shard(mut self, shard: u32, num_shards: u32) -> Self {
+        self.current = mod_mul(
+            self.current,
+            mod_pow(self.generator, shard as u64, self.prime),
+            self.prime,
         );
-        Self { generator, current }
+        self.factor = mod_pow(self.generator, num_shards as u64, self.prime);
+        self.remaining = shard_size(self.prime - 1, shard, num_shards);
+        self
     }
 
-    pub fn current_ip(&self) -> Ipv4Addr {
-        Ipv4Addr::from((self.current as u32).to_be())
+    /// Continue a walk from a position previously returned by position()
+    pub fn with_position(mut self, current: u64, remaining: u64) -> Self {
+        assert!(
+            current > 0 && current < self.prime && remaining < self.prime,
+            "Invalid cyclic position"
+        );
+        self.current = current;
+        self.remaining = remaining;
+        self
     }
 
-    // TODO: support blocklist
-    pub fn next_ip(&mut self) -> Ipv4Addr {
-        loop {
-            self.current = (self.current * self.generator) % PRIME;
-            if self.current < (1u64 << 32) {
-                break;
+    pub fn generator(&self) -> u64 {
+        self.generator
+    }
+
+    /// The current element and the number of elements left to visit
+    pub fn position(&self) -> (u64, u64) {
+        (self.current, self.remaining)
+    }
+
+    /// Returns the index of the next target, or None once every element has been visited
+    pub fn next_index(&mut self) -> Option<u64> {
+        while self.remaining > 0 {
+            let element = self.current;
+            self.current = mod_mul(element, self.factor, self.prime);
+            self.remaining -= 1;
+
+            if element <= self.num_targets {
+                return Some(element - 1);
             }
         }
-        Ipv4Addr::from((self.current as u32).to_be())
+        None
     }
 }
 
@@ -84,6 +182,57 @@ impl Cyclic {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_cyclic_seed() {
+        let mut first = Cyclic::new(42, 1 << 32);
+        let mut second = Cyclic::new(42, 1 << 32);
+        assert_eq!(first.generator, second.generator);
+        for _ in 0..1000 {
+            assert_eq!(first.next_index(), second.next_index());
+        }
+
+        let other = Cyclic::new(43, 1 << 32);
+        assert!(other.generator != first.generator || other.current != first.current);
+    }
+
+    #[test]
+    fn test_cyclic_shards() {
+        let num_shards = 3;
+        let mut shards: Vec<Cyclic> = (0..num_shards)
+            .map(|shard| Cyclic::new(42, 1 << 32).with_shard(shard, num_shards))
+            .collect();
+        assert_eq!(
+            shards.iter().map(|shard| shard.remaining).sum::<u64>(),
+            GROUPS[24].0 - 1
+        );
+
+        // Interleaving the shards gives back the unsharded permutation
+        let mut cyclic = Cyclic::new(42, 1 << 32);
+        for i in 0..3000 {
+            let shard = &mut shards[i % num_shards as usize];
+            assert_eq!(shard.current, cyclic.current);
+            shard.next_index();
+            cyclic.next_index();
+        }
+    }
+
+    #[test]
+    fn test_cyclic_small() {
+        // The group is sized to the targets, so a small scan only walks a small group
+        for num_targets in [1, 255, 256, 1000, 65536] {
+            let mut cyclic = Cyclic::new(42, num_targets);
+            assert!(cyclic.prime - 1 < 2 * num_targets.max(256));
+
+            let mut seen = vec![false; num_targets as usize];
+            while let Some(index) = cyclic.next_index() {
+                assert!(!std::mem::replace(&mut seen[index as usize], true));
+            }
+            assert!(seen.iter().all(|&seen| seen), "{} targets", num_targets);
+        }
+
+        assert_eq!(Cyclic::new(42, 0).next_index(), None);
+    }
+
     // Run with `cargo test --release -- --include-ignored`
     //
     // We don't check every possible generator, but we can be reasonably sure that the overall
@@ -91,32 +240,26 @@ mod tests {
     #[ignore]
     #[test]
     fn test_cyclic_coverage() {
-        let mut ips: Vec<u64> = vec![0; (1u64 << 32) as usize / 64];
-        let mut cyclic = Cyclic::new();
-        let starting_point: u32 = cyclic.current_ip().into();
-
-        // We should only need to loop 2^32 - 1 times to hit every IP
-        for _ in 0..(1u64 << 32) {
-            let ip: u32 = cyclic.next_ip().into();
+        let mut indexes: Vec<u64> = vec![0; (1u64 << 32) as usize / 64];
+        let mut cyclic = Cyclic::new(rand::random(), 1 << 32);
+        let generator = cyclic.generator;
 
-            // Set the bit corresponding to this IP
-            let mask = 1u64 << (ip & 0x3F);
+        // The walk ends after visiting all 2^32 indexes
+        while let Some(index) = cyclic.next_index() {
+            // Set the bit corresponding to this index
+            let mask = 1u64 << (index & 0x3F);
 
             // If the bit is already set, then the generator is incorrect
-            assert_eq!(ips[(ip >> 6) as usize] & mask, 0);
-            ips[(ip >> 6) as usize] |= mask;
-
-            if ip == starting_point {
-                break;
-            }
+            assert_eq!(indexes[(index >> 6) as usize] & mask, 0);
+            indexes[(index >> 6) as usize] |= mask;
         }
 
-        let num_ips = ips.iter().map(|x| x.count_ones() as u64).sum::<u64>();
+        let num_indexes = indexes.iter().map(|x| x.count_ones() as u64).sum::<u64>();
         assert_eq!(
-            num_ips,
-            (1u64 << 32) - 1,
+            num_indexes,
+            1u64 << 32,
             "Failed with generator: {}",
-            cyclic.generator
+            generator
         );
     }
 }
@@ -3,5 +3,5 @@ mod cyclic;
 mod rijndael_alg_fast;
 
 pub use aesrand::AesRand;
-pub use cyclic::Cyclic;
+pub use cyclic::{shard_size, Cyclic};
 pub use rijndael_alg_fast::AesCtx;
@@ -30,6 +30,18 @@ fn init(file: &str, value: i32, constraint: &mut Constraint) -> io::Result<()> {
     Ok(())
 }
 
+/// Parse an address or a network in CIDR notation, e.g. 10.0.0.0/8
+pub fn parse_prefix(arg: &str) -> Result<(Ipv4Addr, i32), String> {
+    let (ip, prefix_len) = arg.split_once('/').unwrap_or((arg, "32"));
+    let addr = ip
+        .parse::<Ipv4Addr>()
+        .map_err(|e| format!("invalid address '{}': {}", ip, e))?;
+    match prefix_len.parse::<i32>() {
+        Ok(prefix_len) if (0..=32).contains(&prefix_len) => Ok((addr, prefix_len)),
+        _ => Err(format!("invalid prefix length '{}'", prefix_len)),
+    }
+}
+
 pub struct Blacklist {
     constraint: Constraint,
 }
@@ -38,8 +50,14 @@ impl Blacklist {
     const ADDR_DISALLOWED: i32 = 0;
     const ADDR_ALLOWED: i32 = 1;
 
-    pub fn new(whitelist_filename: Option<String>, blacklist_filename: Option<String>) -> Self {
-        let mut constraint = if whitelist_filename.is_some() {
+    /// Only addresses in the whitelist file or in `targets` are allowed, if either is given. The
+    /// blacklist file always takes precedence.
+    pub fn new(
+        whitelist_filename: Option<String>,
+        blacklist_filename: Option<String>,
+        targets: &[(Ipv4Addr, i32)],
+    ) -> Self {
+        let mut constraint = if whitelist_filename.is_some() || !targets.is_empty() {
             let root = Rc::new(RefCell::new(TreeNode::new(0)));
             Constraint::new(root)
         } else {
@@ -51,11 +69,21 @@ impl Blacklist {
             init(&filename, Blacklist::ADDR_ALLOWED, &mut constraint).unwrap();
         }
 
+        for &(prefix, prefix_len) in targets {
+            set_recurse(
+                &constraint.root,
+                prefix.into(),
+                prefix_len,
+                Blacklist::ADDR_ALLOWED,
+            );
+        }
+
         if let Some(filename) = blacklist_filename {
             init(&filename, Blacklist::ADDR_DISALLOWED, &mut constraint).unwrap();
         }
 
         constraint.optimize();
+        constraint.paint_value(Blacklist::ADDR_ALLOWED);
         let allowed = constraint.count_ips(Blacklist::ADDR_ALLOWED);
         debug!(
             "Constructed blacklist with {} addresses allowed to be scanned ({:.2}% of address space)",
@@ -78,15 +106,50 @@ impl Blacklist {
         self.constraint.lookup(s_addr.into()) == Blacklist::ADDR_ALLOWED
     }
 
+    /// Returns the `index`-th allowed address, counting from 0.0.0.0, so that scans only ever
+    /// visit allowed addresses
+    pub fn lookup_index(&self, index: u64) -> Ipv4Addr {
+        self.constraint.lookup_index(index).into()
+    }
+
     pub fn blacklist_prefix(&mut self, prefix: Ipv4Addr, prefix_len: i32) {
         let root = &mut self.constraint.root;
         set_recurse(root, prefix.into(), prefix_len, Blacklist::ADDR_DISALLOWED);
         self.constraint.optimized = false;
+        self.constraint.paint_value(Blacklist::ADDR_ALLOWED);
     }
 
     pub fn whitelist_prefix(&mut self, prefix: Ipv4Addr, prefix_len: i32) {
         let root = &mut self.constraint.root;
         set_recurse(root, prefix.into(), prefix_len, Blacklist::ADDR_ALLOWED);
         self.constraint.optimized = false;
+        self.constraint.paint_value(Blacklist::ADDR_ALLOWED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blacklist_targets() {
+        let targets = [
+            parse_prefix("10.0.0.0/8").unwrap(),
+            parse_prefix("192.0.2.7").unwrap(),
+        ];
+        let mut blacklist = Blacklist::new(None, None, &targets);
+        assert_eq!(blacklist.count_allowed(), (1 << 24) + 1);
+        assert!(blacklist.is_allowed(Ipv4Addr::new(10, 1, 2, 3)));
+        assert!(blacklist.is_allowed(Ipv4Addr::new(192, 0, 2, 7)));
+        assert!(!blacklist.is_allowed(Ipv4Addr::new(192, 0, 2, 8)));
+        assert_eq!(blacklist.lookup_index(0), Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(blacklist.lookup_index(1 << 24), Ipv4Addr::new(192, 0, 2, 7));
+
+        blacklist.blacklist_prefix(Ipv4Addr::new(10, 1, 0, 0), 16);
+        assert!(!blacklist.is_allowed(Ipv4Addr::new(10, 1, 2, 3)));
+        assert_eq!(blacklist.lookup_index(1 << 16), Ipv4Addr::new(10, 2, 0, 0));
+
+        assert!(parse_prefix("10.0.0.0/33").is_err());
+        assert!(parse_prefix("10.0.0/8").is_err());
     }
 }
@@ -0,0 +1,304 @@
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::Ordering;
+
+use crate::config::{Config, Context};
+use crate::crypto::Cyclic;
+use crate::state::{SenderState, SenderThreads};
+
+// FNV-1a, which unlike std's DefaultHasher is stable across builds
+fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Hash of the options that decide which addresses are probed, and how
+pub fn config_hash(config: &Config) -> u64 {
+    let options = format!(
+        "{} {} {} {} {:?} {:?} {} {}",
+        config.shard,
+        config.shards,
+        config.sender_threads,
+        config.probe_module,
+        config.probe_args,
+        config.target_ports(),
+        config.max_targets,
+        config.probes,
+    );
+    fnv1a(FNV_OFFSET, options.as_bytes())
+}
+
+/// Hash of the targets given on the command line, and the whitelist, blacklist and --list-of-ips
+/// files, which decide which addresses are allowed. The files can be large, so this is computed
+/// once per scan, see Context::blocklist_hash.
+pub fn blocklist_hash(config: &Config) -> io::Result<u64> {
+    let targets = fnv1a(FNV_OFFSET, format!("{:?}", config.targets).as_bytes());
+    [
+        &config.whitelist_file,
+        &config.blacklist_file,
+        &config.list_of_ips,
+    ]
+    .iter()
+    .try_fold(targets, |hash, file| match file {
+        Some(file) => {
+            let contents =
+                fs::read(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+            Ok(fnv1a(fnv1a(hash, &[1]), &contents))
+        }
+        None => Ok(fnv1a(hash, &[0])),
+    })
+}
+
+/// Progress of one sender thread: the position of its cyclic and the targets it sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadPosition {
+    pub current: u64,
+    pub remaining: u64,
+    pub sent: u64,
+}
+
+/// Sender progress persisted with --checkpoint-file so that an interrupted scan can be continued
+/// with --resume. Targets are counted as sent once they are queued for sending, so up to --batch
+/// targets per sender thread may be skipped when resuming after a crash, along with any frames
+/// still waiting in the transmit ring with --send-backend tx-ring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub seed: u64,
+    pub config_hash: u64,
+    pub blocklist_hash: u64,
+    pub generator: u64,
+    pub threads: Vec<ThreadPosition>,
+    pub blacklisted: u64,
+    pub sendto_failures: u32,
+    pub first_scanned: Ipv4Addr,
+}
+
+impl Checkpoint {
+    pub fn capture(ctx: &Context) -> Self {
+        let config = &ctx.config;
+        let seed = config.seed.expect("Seed is chosen before scanning");
+        let threads = ctx
+            .sender_threads
+            .threads
+            .iter()
+            .map(|thread| {
+                let (current, remaining) = thread.position.load();
+                ThreadPosition {
+                    current,
+                    remaining,
+                    sent: thread.sent.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        let zsend = ctx.sender_state.lock().unwrap();
+        Self {
+            seed,
+            config_hash: config_hash(config),
+            blocklist_hash: ctx.blocklist_hash,
+            generator: Cyclic::new(seed, ctx.num_targets()).generator(),
+            threads,
+            blacklisted: zsend.blacklisted,
+            sendto_failures: ctx.sender_threads.sendto_failures(),
+            first_scanned: zsend.first_scanned,
+        }
+    }
+
+    /// Targets sent by all threads
+    pub fn sent(&self) -> u64 {
+        self.threads.iter().map(|thread| thread.sent).sum()
+    }
+
+    pub fn read(file: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(file).map_err(|e| format!("could not read {}: {}", file, e))?;
+        Self::parse(&contents)
+    }
+
+    /// Write the checkpoint to a temporary file first, so that a crash while writing never leaves
+    /// a truncated checkpoint behind
+    pub fn write(&self, file: &str) -> io::Result<()> {
+        let tmp_file = format!("{}.tmp", file);
+        fs::write(&tmp_file, self.to_string())?;
+        fs::rename(&tmp_file, file)
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let value = |key: &str| -> Result<&str, String> {
+            contents
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+                .ok_or_else(|| format!("checkpoint is missing {}", key))
+        };
+        let number = |key: &str| -> Result<u64, String> {
+            value(key)?
+                .parse()
+                .map_err(|e| format!("invalid {} in checkpoint: {}", key, e))
+        };
+
+        // One "thread <current> <remaining> <sent>" line per sender thread, in order
+        let threads = contents
+            .lines()
+            .filter_map(|line| line.strip_prefix("thread "))
+            .map(|line| {
+                let numbers = line
+                    .split_whitespace()
+                    .map(|number| number.parse())
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(|e| format!("invalid thread in checkpoint: {}", e))?;
+                match numbers[..] {
+                    [current, remaining, sent] => Ok(ThreadPosition {
+                        current,
+                        remaining,
+                        sent,
+                    }),
+                    _ => Err(format!("invalid thread in checkpoint: '{}'", line)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            seed: number("seed")?,
+            config_hash: number("config-hash")?,
+            blocklist_hash: number("blocklist-hash")?,
+            generator: number("generator")?,
+            threads,
+            blacklisted: number("blacklisted")?,
+            sendto_failures: number("sendto-failures")? as u32,
+            first_scanned: value("first-scanned")?
+                .parse()
+                .map_err(|e| format!("invalid first-scanned in checkpoint: {}", e))?,
+        })
+    }
+
+    /// Check that the checkpoint was written by a scan with the same seed, options and blocklist,
+    /// and so the same `num_targets`
+    pub fn verify(
+        &self,
+        config: &Config,
+        num_targets: u64,
+        blocklist_hash: u64,
+    ) -> Result<(), String> {
+        if config.seed != Some(self.seed) {
+            return Err(format!(
+                "checkpoint was written with seed {}, not {:?}",
+                self.seed, config.seed
+            ));
+        }
+        if Cyclic::new(self.seed, num_targets).generator() != self.generator {
+            return Err("checkpoint generator doesn't match its seed".to_string());
+        }
+        if config_hash(config) != self.config_hash {
+            return Err("scan options differ from the checkpointed scan".to_string());
+        }
+        if blocklist_hash != self.blocklist_hash {
+            return Err("whitelist or blacklist changed since the checkpoint".to_string());
+        }
+        if self.threads.len() != config.sender_threads as usize {
+            return Err(format!(
+                "checkpoint has {} sender threads, not {}",
+                self.threads.len(),
+                config.sender_threads
+            ));
+        }
+        Ok(())
+    }
+
+    /// Continue the sender counters from the checkpoint
+    pub fn restore(&self, zsend: &mut SenderState, sender_threads: &SenderThreads) {
+        for (thread, position) in sender_threads.threads.iter().zip(&self.threads) {
+            thread.sent.store(position.sent, Ordering::Relaxed);
+        }
+        // Only the total matters for failures
+        sender_threads.threads[0]
+            .sendto_failures
+            .store(self.sendto_failures, Ordering::Relaxed);
+        zsend.resumed = self.sent();
+        zsend.blacklisted = self.blacklisted;
+        zsend.first_scanned = self.first_scanned;
+    }
+}
+
+impl std::fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "config-hash {}", self.config_hash)?;
+        writeln!(f, "blocklist-hash {}", self.blocklist_hash)?;
+        writeln!(f, "generator {}", self.generator)?;
+        for thread in &self.threads {
+            writeln!(
+                f,
+                "thread {} {} {}",
+                thread.current, thread.remaining, thread.sent
+            )?;
+        }
+        writeln!(f, "blacklisted {}", self.blacklisted)?;
+        writeln!(f, "sendto-failures {}", self.sendto_failures)?;
+        writeln!(f, "first-scanned {}", self.first_scanned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::probe_modules::probe_modules::get_probe_module;
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let config = Config::parse_from(["zmap-rs", "--seed", "42", "-p", "443", "-T", "2"]);
+        let hash = blocklist_hash(&config).unwrap();
+        let ctx = Context::new(config.clone(), get_probe_module("tcp_synscan").unwrap())
+            .with_num_addresses(1 << 24)
+            .with_blocklist_hash(hash);
+
+        // Each sender thread walks its own part of the permutation
+        let mut cyclics: Vec<Cyclic> = (0..2)
+            .map(|thread| Cyclic::new(42, 1 << 24).with_shard(thread, 2))
+            .collect();
+        for (cyclic, thread) in cyclics.iter_mut().zip(ctx.sender_threads.threads.iter()) {
+            for _ in 0..50 {
+                cyclic.next_index();
+            }
+            let (current, remaining) = cyclic.position();
+            thread.position.store(current, remaining);
+            thread.sent.store(50, Ordering::Relaxed);
+        }
+        ctx.sender_state.lock().unwrap().first_scanned = Ipv4Addr::new(10, 0, 0, 1);
+
+        let checkpoint = Checkpoint::capture(&ctx);
+        let parsed = Checkpoint::parse(&checkpoint.to_string()).unwrap();
+        assert_eq!(parsed, checkpoint);
+        assert_eq!(parsed.sent(), 100);
+        assert!(parsed.verify(&config, 1 << 24, hash).is_ok());
+
+        // The resumed walks continue where the checkpointed ones stopped
+        for (cyclic, position) in cyclics.iter_mut().zip(&parsed.threads) {
+            let mut resumed = Cyclic::new(42, 1 << 24)
+                .with_shard(0, 2)
+                .with_position(position.current, position.remaining);
+            for _ in 0..100 {
+                assert_eq!(resumed.next_index(), cyclic.next_index());
+            }
+        }
+
+        let other = Config::parse_from(["zmap-rs", "--seed", "42", "-p", "80", "-T", "2"]);
+        assert!(parsed.verify(&other, 1 << 24, hash).is_err());
+        let other = Config::parse_from(["zmap-rs", "--seed", "43", "-p", "443", "-T", "2"]);
+        assert!(parsed.verify(&other, 1 << 24, hash).is_err());
+        let other = Config::parse_from(["zmap-rs", "--seed", "42", "-p", "443"]);
+        assert!(parsed.verify(&other, 1 << 24, hash).is_err());
+
+        // A different number of targets would give a different permutation
+        assert!(parsed.verify(&config, 1 << 16, hash).is_err());
+        assert!(parsed.verify(&config, 1 << 24, hash + 1).is_err());
+
+        // Unreadable files can't be told apart from changed ones
+        let missing = Config::parse_from(["zmap-rs", "-p", "443", "-b", "/nonexistent/blacklist"]);
+        assert!(blocklist_hash(&missing).is_err());
+    }
+}
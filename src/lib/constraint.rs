@@ -5,6 +5,8 @@ use log::debug;
 #[derive(Debug, Clone)]
 pub struct TreeNode {
     val: i32,
+    // Number of addresses under this node with the value last painted, see paint_value
+    count: u64,
     left: Option<TreeNodeRef>,
     right: Option<TreeNodeRef>,
 }
@@ -15,6 +17,7 @@ impl TreeNode {
     pub fn new(val: i32) -> Self {
         TreeNode {
             val,
+            count: 0,
             left: None,
             right: None,
         }
@@ -106,6 +109,41 @@ impl Constraint {
         }
     }
 
+    /// Count the addresses with `value` under every node, so that they can be indexed with
+    /// lookup_index. Has to be called again after the tree is modified.
+    pub fn paint_value(&mut self, value: i32) {
+        paint_recurse(&self.root, value, 1u64 << 32);
+    }
+
+    /// Returns the `index`-th address (in ascending order) with the value last painted
+    pub fn lookup_index(&self, mut index: u64) -> u32 {
+        assert!(
+            index < self.root.borrow().count,
+            "Constraint index {} out of range",
+            index
+        );
+
+        let mut node = self.root.clone();
+        let mut prefix: u64 = 0;
+        let mut size: u64 = 1 << 32;
+        while !node.borrow().is_leaf() {
+            let left = node.borrow().left.clone().unwrap();
+            let right = node.borrow().right.clone().unwrap();
+            size >>= 1;
+            let left_count = left.borrow().count;
+            if index < left_count {
+                node = left;
+            } else {
+                index -= left_count;
+                prefix += size;
+                node = right;
+            }
+        }
+
+        // Every address under a leaf with the painted value has it
+        (prefix + index) as u32
+    }
+
     pub fn lookup_node(&self, node: &TreeNodeRef, addr: u32, prefix_len: i32) -> TreeNodeRef {
         if node.borrow().is_leaf() {
             return node.clone();
@@ -132,6 +170,21 @@ impl Constraint {
     }
 }
 
+fn paint_recurse(node: &TreeNodeRef, value: i32, size: u64) -> u64 {
+    let count = if node.borrow().is_leaf() {
+        if node.borrow().val == value {
+            size
+        } else {
+            0
+        }
+    } else {
+        paint_recurse(node.borrow().left.as_ref().unwrap(), value, size >> 1)
+            + paint_recurse(node.borrow().right.as_ref().unwrap(), value, size >> 1)
+    };
+    node.borrow_mut().count = count;
+    count
+}
+
 pub fn set_recurse(node: &TreeNodeRef, prefix: u32, len: i32, value: i32) {
     if len == 0 {
         if !node.borrow().is_leaf() {
@@ -194,13 +247,13 @@ mod tests {
         let mut constraint = Constraint::new(root);
 
         let ip1 = Ipv4Addr::new(0, 0, 0, 0);
-        set_recurse(&mut constraint.root, ip1.into(), 8, ADDR_ALLOWED);
+        set_recurse(&constraint.root, ip1.into(), 8, ADDR_ALLOWED);
 
         let count = constraint.count_ips(ADDR_ALLOWED);
         assert_eq!(count, 1 << 24);
 
         let ip2 = Ipv4Addr::new(192, 168, 1, 1);
-        set_recurse(&mut constraint.root, ip2.into(), 32, ADDR_DISALLOWED);
+        set_recurse(&constraint.root, ip2.into(), 32, ADDR_DISALLOWED);
 
         constraint.optimize();
 
@@ -209,5 +262,32 @@ mod tests {
 
         assert!(constraint.lookup(ip1.into()) == ADDR_ALLOWED);
         assert!(constraint.lookup(ip2.into()) == ADDR_DISALLOWED);
+
+        // Indexes walk the allowed addresses in ascending order
+        constraint.paint_value(ADDR_ALLOWED);
+        assert_eq!(constraint.lookup_index(0), 0);
+        assert_eq!(
+            constraint.lookup_index(1 << 23),
+            u32::from(Ipv4Addr::new(0, 128, 0, 0))
+        );
+
+        // Disallowed addresses are skipped
+        set_recurse(&constraint.root, ip1.into(), 8, ADDR_DISALLOWED);
+        set_recurse(&constraint.root, ip2.into(), 24, ADDR_ALLOWED);
+        set_recurse(&constraint.root, ip2.into(), 32, ADDR_DISALLOWED);
+        constraint.paint_value(ADDR_ALLOWED);
+        assert_eq!(constraint.count_ips(ADDR_ALLOWED), 255);
+        let indexed: Vec<Ipv4Addr> = [0, 1, 254]
+            .iter()
+            .map(|&index| constraint.lookup_index(index).into())
+            .collect();
+        assert_eq!(
+            indexed,
+            vec![
+                Ipv4Addr::new(192, 168, 1, 0),
+                Ipv4Addr::new(192, 168, 1, 2),
+                Ipv4Addr::new(192, 168, 1, 255)
+            ]
+        );
     }
 }
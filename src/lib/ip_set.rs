@@ -0,0 +1,210 @@
+use log::warn;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::Ipv4Addr;
+use std::sync::OnceLock;
+
+const PAGE_BITS: u32 = 16;
+const WORDS_PER_PAGE: usize = (1 << PAGE_BITS) / 64;
+
+/// Set of IPv4 addresses, stored as a bitmap split into pages of 2^16 addresses that are only
+/// allocated once they hold an address. Used for --list-of-ips, as a /32 node per address in the
+/// blacklist's constraint tree would take far too much memory for millions of addresses.
+pub struct IpSet {
+    pages: Vec<Option<Box<[u64]>>>,
+    len: u64,
+    // Number of addresses before each page, computed on the first call to nth
+    offsets: OnceLock<Vec<u64>>,
+}
+
+impl Default for IpSet {
+    fn default() -> Self {
+        Self {
+            pages: vec![None; 1 << (32 - PAGE_BITS)],
+            len: 0,
+            offsets: OnceLock::new(),
+        }
+    }
+}
+
+// The pages are far too large to print
+impl std::fmt::Debug for IpSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IpSet").field("len", &self.len).finish()
+    }
+}
+
+impl IpSet {
+    pub fn open(file: &str) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(file)?))
+    }
+
+    /// Read one address per line, skipping blank lines and comments starting with '#'
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut set = Self::default();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match line.parse() {
+                Ok(ip) => {
+                    set.insert(ip);
+                }
+                Err(_) => warn!("Skipping invalid address '{}'", line),
+            }
+        }
+        Ok(set)
+    }
+
+    /// Returns whether the address was newly inserted
+    pub fn insert(&mut self, ip: Ipv4Addr) -> bool {
+        let ip = u32::from(ip);
+        let page = self.pages[(ip >> PAGE_BITS) as usize]
+            .get_or_insert_with(|| vec![0; WORDS_PER_PAGE].into_boxed_slice());
+        let word = &mut page[(ip as usize & ((1 << PAGE_BITS) - 1)) / 64];
+        let mask = 1u64 << (ip & 0x3F);
+        if *word & mask != 0 {
+            return false;
+        }
+
+        *word |= mask;
+        self.len += 1;
+        self.offsets = OnceLock::new();
+        true
+    }
+
+    /// Remove the addresses for which `f` returns false
+    pub fn retain(&mut self, mut f: impl FnMut(Ipv4Addr) -> bool) {
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            let Some(words) = page else { continue };
+            for (j, word) in words.iter_mut().enumerate() {
+                let mut bits = *word;
+                while bits != 0 {
+                    let bit = bits.trailing_zeros();
+                    bits &= bits - 1;
+                    let ip = ((i as u32) << PAGE_BITS) | (j as u32 * 64) | bit;
+                    if !f(Ipv4Addr::from(ip)) {
+                        *word &= !(1 << bit);
+                        self.len -= 1;
+                    }
+                }
+            }
+            if words.iter().all(|&word| word == 0) {
+                *page = None;
+            }
+        }
+        self.offsets = OnceLock::new();
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let ip = u32::from(ip);
+        match &self.pages[(ip >> PAGE_BITS) as usize] {
+            Some(page) => {
+                (page[(ip as usize & ((1 << PAGE_BITS) - 1)) / 64] >> (ip & 0x3F)) & 1 != 0
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the `index`-th address in ascending order
+    pub fn nth(&self, mut index: u64) -> Ipv4Addr {
+        assert!(index < self.len, "IpSet index {} out of range", index);
+        let offsets = self.offsets.get_or_init(|| {
+            let mut offset = 0;
+            self.pages
+                .iter()
+                .map(|page| {
+                    let start = offset;
+                    offset += page
+                        .iter()
+                        .flat_map(|words| words.iter())
+                        .map(|word| word.count_ones() as u64)
+                        .sum::<u64>();
+                    start
+                })
+                .collect()
+        });
+
+        // The last page starting at or before the index holds it
+        let i = offsets.partition_point(|&offset| offset <= index) - 1;
+        index -= offsets[i];
+        let page = self.pages[i].as_ref().unwrap();
+        for (j, &word) in page.iter().enumerate() {
+            let ones = word.count_ones() as u64;
+            if index < ones {
+                let mut bits = word;
+                for _ in 0..index {
+                    bits &= bits - 1;
+                }
+                return Ipv4Addr::from(
+                    ((i as u32) << PAGE_BITS) | (j as u32 * 64) | bits.trailing_zeros(),
+                );
+            }
+            index -= ones;
+        }
+        unreachable!("IpSet page offsets are out of date")
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the addresses in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, page)| Some((i as u32, page.as_ref()?)))
+            .flat_map(|(i, page)| {
+                page.iter().enumerate().flat_map(move |(j, &word)| {
+                    (0..64)
+                        .filter(move |bit| (word >> bit) & 1 != 0)
+                        .map(move |bit| Ipv4Addr::from((i << PAGE_BITS) | (j as u32 * 64) | bit))
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_set() {
+        let input =
+            "10.0.0.1\n\n# comment\n192.0.2.7 # trailing comment\nnot-an-address\n10.0.0.1\n";
+        let set = IpSet::from_reader(input.as_bytes()).unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(set.contains(Ipv4Addr::new(192, 0, 2, 7)));
+        assert!(!set.contains(Ipv4Addr::new(10, 0, 0, 2)));
+        assert!(!set.contains(Ipv4Addr::new(8, 8, 8, 8)));
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(192, 0, 2, 7)]
+        );
+        assert_eq!(set.nth(1), Ipv4Addr::new(192, 0, 2, 7));
+    }
+
+    #[test]
+    fn test_ip_set_retain() {
+        let mut set = IpSet::default();
+        for i in 0..1000u32 {
+            set.insert(Ipv4Addr::from(i * 97));
+        }
+        set.retain(|ip| u32::from(ip) % 2 == 0);
+        assert_eq!(set.len(), 500);
+        for i in 0..500 {
+            assert_eq!(set.nth(i), Ipv4Addr::from(i as u32 * 2 * 97));
+        }
+        assert_eq!(set.iter().count(), 500);
+    }
+}
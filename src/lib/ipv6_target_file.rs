@@ -0,0 +1,82 @@
+use log::warn;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines};
+use std::net::Ipv6Addr;
+
+/// IPv6 targets read one address per line from a file, as the address space is far too large to
+/// walk with the cyclic group. Blank lines and comments starting with '#' are skipped.
+pub struct Ipv6TargetFile<R: BufRead> {
+    lines: Lines<R>,
+    quiet: bool,
+}
+
+impl Ipv6TargetFile<BufReader<File>> {
+    pub fn open(file: &str) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(file)?)))
+    }
+}
+
+impl<R: BufRead> Ipv6TargetFile<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            quiet: false,
+        }
+    }
+
+    /// Skip invalid lines without a warning, e.g. when only counting the targets
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for Ipv6TargetFile<R> {
+    type Item = Ipv6Addr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Could not read IPv6 target file: {}", e);
+                    return None;
+                }
+            };
+
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match line.parse() {
+                Ok(addr) => return Some(addr),
+                Err(_) if !self.quiet => warn!("Skipping invalid IPv6 target '{}'", line),
+                Err(_) => {}
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv6_target_file() {
+        let input =
+            "2001:db8::1\n\n# comment\n2001:db8::2 # trailing comment\nnot-an-address\n::1\n";
+        let targets: Vec<Ipv6Addr> = Ipv6TargetFile::new(input.as_bytes()).collect();
+        assert_eq!(
+            targets,
+            vec![
+                "2001:db8::1".parse::<Ipv6Addr>().unwrap(),
+                "2001:db8::2".parse().unwrap(),
+                "::1".parse().unwrap(),
+            ]
+        );
+    }
+}
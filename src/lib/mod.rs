@@ -1,3 +1,8 @@
 pub mod blacklist;
+pub mod checkpoint;
 mod constraint;
+pub mod ip_set;
+pub mod ipv6_target_file;
+pub mod rate_limiter;
+pub mod seen_set;
 pub mod validate;
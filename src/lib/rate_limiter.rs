@@ -0,0 +1,313 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Waits shorter than this are spun rather than slept, as sleeping overshoots by tens of µs
+const MAX_SPIN: Duration = Duration::from_millis(1);
+
+// Times are kept in sixteenths of a nanosecond, fine enough that high rates stay exact to well
+// under 0.1% while a u64 still covers over 36 years
+const TICKS_PER_NANO: u128 = 16;
+const TICKS_PER_SEC: f64 = 16e9;
+
+/// Source of time for the rate limiter, so that tests can control it
+pub trait Clock: Send + Sync {
+    /// Time elapsed since some fixed point
+    fn now(&self) -> Duration;
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+#[derive(Debug)]
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Token bucket limiting the packets sent by all sender threads to `rate` per second. Each
+/// acquisition reserves the next free slot in the schedule (the generic cell rate algorithm), so
+/// the limiter is a single atomic and never drifts from the configured rate. Up to `burst`
+/// packets may be sent back to back when the senders fell behind the schedule, e.g. after being
+/// descheduled.
+#[derive(Debug)]
+pub struct RateLimiter<C: Clock = MonotonicClock> {
+    clock: C,
+    // Time between two packets, in ticks
+    interval: AtomicU64,
+    burst: u64,
+    // Time at which the next packet may be sent, in ticks
+    next: AtomicU64,
+}
+
+fn ticks(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos() * TICKS_PER_NANO).unwrap_or(u64::MAX)
+}
+
+// Very low rates saturate rather than wrap around, as float to int casts do
+fn interval(rate: f64) -> u64 {
+    (TICKS_PER_SEC / rate) as u64
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self::with_clock(rate, burst, MonotonicClock::default())
+    }
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn with_clock(rate: f64, burst: u32, clock: C) -> Self {
+        assert!(rate > 0.0, "Invalid rate {}", rate);
+        let next = AtomicU64::new(ticks(clock.now()));
+        Self {
+            clock,
+            interval: AtomicU64::new(interval(rate)),
+            burst: burst.max(1) as u64,
+            next,
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        TICKS_PER_SEC / self.interval.load(Ordering::Relaxed) as f64
+    }
+
+    /// Change the rate, e.g. for --adaptive-rate. Packets that were already reserved keep their
+    /// slot.
+    pub fn set_rate(&self, rate: f64) {
+        assert!(rate > 0.0, "Invalid rate {}", rate);
+        self.interval.store(interval(rate), Ordering::Relaxed);
+    }
+
+    /// Reserve `packets` and return how long to wait before sending them
+    pub fn acquire(&self, packets: u64) -> Duration {
+        let now = ticks(self.clock.now());
+        let interval = self.interval.load(Ordering::Relaxed);
+        let tolerance = (self.burst - 1).saturating_mul(interval);
+
+        let mut start = 0;
+        self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                // Time not used while the senders were behind only carries over up to the burst
+                start = next.max(now.saturating_sub(tolerance));
+                Some(start.saturating_add(packets.saturating_mul(interval)))
+            })
+            .unwrap();
+        Duration::from_nanos(start.saturating_sub(now) / TICKS_PER_NANO as u64)
+    }
+
+    /// Block until `packets` may be sent. If that means sleeping, `idle` is called first, so that
+    /// senders can hand over the probes they queued rather than hold them back for the whole wait.
+    pub fn wait(&self, packets: u64, idle: impl FnOnce()) {
+        let delay = self.acquire(packets);
+        if delay.is_zero() {
+            return;
+        }
+
+        let deadline = self.clock.now() + delay;
+        if delay > MAX_SPIN {
+            idle();
+            self.clock.sleep(deadline.saturating_sub(self.clock.now()));
+            return;
+        }
+        while self.clock.now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Send rate for --adaptive-rate: backs off quickly while replies are dropped or sends fail, and
+/// ramps back up slowly once they clear, always staying between `min` and `max`
+#[derive(Debug, Clone)]
+pub struct AdaptiveRate {
+    rate: f64,
+    min: f64,
+    max: f64,
+}
+
+impl AdaptiveRate {
+    const DECREASE: f64 = 0.75;
+    const INCREASE: f64 = 1.05;
+
+    pub fn new(rate: f64, min: f64, max: f64) -> Self {
+        assert!(
+            0.0 < min && min <= max,
+            "Invalid rate bounds {} to {}",
+            min,
+            max
+        );
+        Self {
+            rate: rate.clamp(min, max),
+            min,
+            max,
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Adjust the rate after an interval with or without congestion, returning the new rate
+    pub fn update(&mut self, congested: bool) -> f64 {
+        let factor = if congested {
+            Self::DECREASE
+        } else {
+            Self::INCREASE
+        };
+        self.rate = (self.rate * factor).clamp(self.min, self.max);
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct ManualClock {
+        nanos: AtomicU64,
+    }
+
+    impl ManualClock {
+        fn advance(&self, duration: Duration) {
+            self.nanos
+                .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for &ManualClock {
+        fn now(&self) -> Duration {
+            Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration);
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_burst() {
+        let clock = ManualClock::default();
+        let limiter = RateLimiter::with_clock(1000.0, 10, &clock);
+        assert_eq!(limiter.acquire(1), Duration::ZERO);
+        assert_eq!(limiter.acquire(1), Duration::from_millis(1));
+        assert_eq!(limiter.acquire(2), Duration::from_millis(2));
+
+        // After being idle, up to a burst of packets can go out at once
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..10 {
+            assert_eq!(limiter.acquire(1), Duration::ZERO);
+        }
+        assert_eq!(limiter.acquire(1), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_rate_limiter_accuracy() {
+        // Rates that don't divide evenly, and rates below the number of sender threads
+        for (rate, threads) in [(3_000_000.0, 4), (7.0, 1), (1.0, 4), (2.5, 8)] {
+            let clock = ManualClock::default();
+            let limiter = RateLimiter::with_clock(rate, 64, &clock);
+
+            // Senders take turns and wait as told, plus some scheduling jitter
+            let packets = 1000;
+            let mut sent = 0;
+            while sent < packets {
+                for _ in 0..threads {
+                    clock.advance(limiter.acquire(1) + Duration::from_nanos(sent % 7));
+                    sent += 1;
+                }
+            }
+
+            // The first packet goes out right away, so measure the intervals between packets
+            let elapsed = (&clock).now().as_secs_f64();
+            assert!(
+                ((packets - 1) as f64 / elapsed - rate).abs() / rate < 0.01,
+                "{} packets in {}s at rate {}",
+                packets,
+                elapsed,
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_low_rate() {
+        let clock = ManualClock::default();
+        let limiter = RateLimiter::with_clock(10.0, 64, &clock);
+
+        // A sender batching up to 64 probes, flushed whenever it waits for the limiter
+        let mut queued = Vec::new();
+        let mut sent = Vec::new();
+        for _ in 0..200 {
+            limiter.wait(1, || {
+                sent.extend(queued.drain(..).map(|at| ((&clock).now(), at)))
+            });
+            queued.push((&clock).now());
+            if queued.len() == 64 {
+                sent.extend(queued.drain(..).map(|at| ((&clock).now(), at)));
+            }
+        }
+
+        // Probes go out at the rate instead of in bursts of a whole batch, as none is held back
+        // past the next probe's slot
+        assert_eq!(sent.len(), 199);
+        for (sent_at, queued_at) in sent {
+            assert!(sent_at - queued_at <= Duration::from_millis(100));
+        }
+        assert_eq!((&clock).now(), Duration::from_millis(19_900));
+    }
+
+    #[test]
+    fn test_rate_limiter_long_running() {
+        // Long past the 213 days that fit in a u64 of picoseconds
+        let clock = ManualClock::default();
+        clock.advance(Duration::from_secs(300 * 24 * 3600));
+        let limiter = RateLimiter::with_clock(1000.0, 1, &clock);
+        assert_eq!(limiter.acquire(1), Duration::ZERO);
+        assert_eq!(limiter.acquire(1), Duration::from_millis(1));
+
+        // Very low rates and large bursts saturate instead of overflowing
+        let limiter = RateLimiter::with_clock(1e-9, u32::MAX, &clock);
+        assert_eq!(limiter.acquire(1), Duration::ZERO);
+        assert_eq!(limiter.acquire(1000), Duration::from_secs(1_000_000_000));
+        assert!(limiter.acquire(1) > Duration::from_secs(1_000_000_000));
+    }
+
+    #[test]
+    fn test_adaptive_rate() {
+        let mut adaptive = AdaptiveRate::new(1000.0, 100.0, 1200.0);
+        assert_eq!(adaptive.update(true), 750.0);
+        assert_eq!(adaptive.update(true), 562.5);
+
+        // Backs off down to the minimum, and ramps back up to the maximum
+        for _ in 0..20 {
+            adaptive.update(true);
+        }
+        assert_eq!(adaptive.rate(), 100.0);
+        assert_eq!(adaptive.update(false), 105.0);
+        for _ in 0..100 {
+            adaptive.update(false);
+        }
+        assert_eq!(adaptive.rate(), 1200.0);
+
+        // The limiter picks up a new rate for the packets that follow
+        let clock = ManualClock::default();
+        let limiter = RateLimiter::with_clock(1000.0, 1, &clock);
+        limiter.acquire(1);
+        limiter.set_rate(adaptive.rate() / 2.0);
+        assert_eq!(limiter.acquire(1), Duration::from_millis(1));
+        assert_eq!(limiter.acquire(1), Duration::from_nanos(2_666_666));
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    alloc::Layout,
+    collections::HashSet,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Targets that already replied, so that repeated replies are only reported once. Shared by the
+/// receiver threads, which can see replies from the same target on different threads.
+pub struct SeenSet {
+    // When scanning a single port, IPv4 addresses are tracked in a bitmap covering the whole
+    // address space. That isn't possible for IPv6 targets or (address, port) pairs, which are
+    // kept in a set instead.
+    single_port: bool,
+    ipv4: Box<[AtomicU64]>,
+    targets: Mutex<HashSet<(IpAddr, u16)>>,
+}
+
+impl SeenSet {
+    const IPV4_WORDS: usize = 1 << 26;
+
+    pub fn new(single_port: bool) -> Self {
+        Self {
+            single_port,
+            ipv4: if single_port {
+                zeroed_words(Self::IPV4_WORDS)
+            } else {
+                Box::new([])
+            },
+            targets: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr, port: u16) -> bool {
+        match ip {
+            IpAddr::V4(ip) if self.single_port => {
+                let (word, bit) = Self::bit(ip.into());
+                self.ipv4[word].load(Ordering::Relaxed) & bit != 0
+            }
+            _ => self.targets.lock().unwrap().contains(&(ip, port)),
+        }
+    }
+
+    /// Marks the target as seen, returns whether it wasn't already, like HashSet::insert
+    pub fn insert(&self, ip: IpAddr, port: u16) -> bool {
+        match ip {
+            IpAddr::V4(ip) if self.single_port => {
+                let (word, bit) = Self::bit(ip.into());
+                self.ipv4[word].fetch_or(bit, Ordering::Relaxed) & bit == 0
+            }
+            _ => self.targets.lock().unwrap().insert((ip, port)),
+        }
+    }
+
+    fn bit(ip: u32) -> (usize, u64) {
+        ((ip >> 6) as usize, 1 << (ip & 0x3F))
+    }
+}
+
+// Zeroed allocations are mapped in lazily, so the bitmap only takes up memory where targets
+// replied
+fn zeroed_words(len: usize) -> Box<[AtomicU64]> {
+    let layout = Layout::array::<AtomicU64>(len).unwrap();
+    unsafe {
+        let words = std::alloc::alloc_zeroed(layout) as *mut AtomicU64;
+        if words.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Box::from_raw(std::ptr::slice_from_raw_parts_mut(words, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_seen_set() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+        let seen = SeenSet::new(true);
+        assert!(!seen.contains(ipv4, 80));
+        assert!(seen.insert(ipv4, 80));
+        assert!(!seen.insert(ipv4, 80));
+        assert!(seen.contains(ipv4, 80));
+        assert!(!seen.contains(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), 80));
+        assert!(seen.insert(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)), 80));
+        assert!(seen.insert(ipv6, 80));
+        assert!(seen.contains(ipv6, 80));
+
+        // With several ports, each port of a target is seen separately
+        let seen = SeenSet::new(false);
+        assert!(seen.insert(ipv4, 80));
+        assert!(!seen.contains(ipv4, 443));
+        assert!(seen.insert(ipv4, 443));
+        assert!(!seen.insert(ipv4, 80));
+    }
+}
@@ -1,10 +1,57 @@
 use crate::crypto::AesCtx;
 use rand::prelude::*;
-use std::net::Ipv4Addr;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::OpenOptionsExt;
+
+pub fn new_key() -> [u8; 16] {
+    rand::thread_rng().gen()
+}
 
 pub fn new_context() -> AesCtx {
-    let mut rng = rand::thread_rng();
-    let key: [u8; 16] = rng.gen();
+    AesCtx::new(&new_key())
+}
+
+// Keys are stored as 32 hex digits, see --validation-key-file
+pub fn parse_key(hex: &str) -> Result<[u8; 16], String> {
+    let hex = hex.trim();
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(format!("expected 32 hex digits, got {:?}", hex));
+    }
+    let mut key = [0u8; 16];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16)
+            .map_err(|_| format!("invalid hex digits {:?}", digits))?;
+    }
+    Ok(key)
+}
+
+pub fn format_key(key: &[u8; 16]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn read_key_file(file: &str) -> io::Result<[u8; 16]> {
+    parse_key(&std::fs::read_to_string(file)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Only readable by the owner, as anyone with the key can forge replies
+pub fn write_key_file(file: &str, key: &[u8; 16]) -> io::Result<()> {
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(file)?;
+    writeln!(out, "{}", format_key(key))
+}
+
+// Derive the key from the seed, keeping it distinct from the key used for the permutation
+pub fn new_context_from_seed(seed: u64) -> AesCtx {
+    let mut key = [0u8; 16];
+    key[0..8].copy_from_slice(&seed.to_be_bytes());
+    key[8..16].copy_from_slice(b"validate");
     AesCtx::new(&key)
 }
 
@@ -14,3 +61,55 @@ pub fn gen(ctx: &AesCtx, src: &Ipv4Addr, dst: &Ipv4Addr) -> [u8; 16] {
     input[4..8].copy_from_slice(&dst.octets());
     ctx.encrypt(&input)
 }
+
+// Two IPv6 addresses don't fit in a single AES block, so chain the blocks as in CBC-MAC
+pub fn gen_v6(ctx: &AesCtx, src: &Ipv6Addr, dst: &Ipv6Addr) -> [u8; 16] {
+    let mut input = ctx.encrypt(&src.octets());
+    for (byte, dst_byte) in input.iter_mut().zip(dst.octets()) {
+        *byte ^= dst_byte;
+    }
+    ctx.encrypt(&input)
+}
+
+// Probe modules only have room for the first two words of the validation. There is none
+// between addresses of different families, as no probe is ever sent between them.
+pub fn gen_words(ctx: &AesCtx, src: &IpAddr, dst: &IpAddr) -> Option<[u32; 2]> {
+    let validation = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => gen(ctx, src, dst),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => gen_v6(ctx, src, dst),
+        _ => return None,
+    };
+    Some([
+        u32::from_be_bytes(validation[0..4].try_into().unwrap()),
+        u32::from_be_bytes(validation[4..8].try_into().unwrap()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation_key() {
+        let key = new_key();
+        assert_eq!(parse_key(&format!("{}\n", format_key(&key))), Ok(key));
+        assert_eq!(
+            format_key(&parse_key("000102030405060708090a0b0c0d0eFF").unwrap()),
+            "000102030405060708090a0b0c0d0eff"
+        );
+        assert!(parse_key("0001").is_err());
+        assert!(parse_key("000102030405060708090a0b0c0d0eXX").is_err());
+        assert!(parse_key("ü00102030405060708090a0b0c0d0e0").is_err());
+    }
+
+    #[test]
+    fn test_gen_words() {
+        let ctx = new_context_from_seed(1);
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let ipv6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(gen_words(&ctx, &ipv4, &ipv4).is_some());
+        assert!(gen_words(&ctx, &ipv6, &ipv6).is_some());
+        assert_eq!(gen_words(&ctx, &ipv4, &ipv6), None);
+        assert_eq!(gen_words(&ctx, &ipv6, &ipv4), None);
+    }
+}
@@ -11,29 +11,32 @@ use std::sync::{Arc, Mutex};
 use affinity::{get_core_num, set_thread_affinity};
 use config::Context;
 use lib::blacklist::Blacklist;
-use log::{debug, info};
+use lib::checkpoint::Checkpoint;
+use lib::ipv6_target_file::Ipv6TargetFile;
+use log::{debug, info, warn};
 use monitor::Monitor;
-use probe_modules::module_tcp_synscan::PCAP_FILTER;
-use recv::Receiver;
+use recv::{Receiver, Results};
 use send::Sender;
 
 use crate::config::create_context;
-use crate::crypto::Cyclic;
 
 mod config;
 mod crypto;
 mod lib;
 mod monitor;
 mod net;
+mod output_modules;
 mod probe_modules;
 mod recv;
 mod send;
 mod state;
 
 fn dump_summary(ctx: &Context) {
+    let zsend_sendto_failures = ctx.sender_threads.sendto_failures();
+    let zsend_ring_full = ctx.sender_threads.ring_full();
     let zsend = ctx.sender_state.lock().unwrap();
-    let zsend_sent = zsend.sent;
-    let zsend_sendto_failures = zsend.sendto_failures;
+    let zsend_sent = ctx.sender_threads.sent();
+    let zsend_resumed = zsend.resumed;
     let zsend_blacklisted = zsend.blacklisted;
     let zsend_first_scanned = zsend.first_scanned;
     drop(zsend);
@@ -46,20 +49,42 @@ fn dump_summary(ctx: &Context) {
     let zrecv_failure_total = zrecv.failure_total;
     drop(zrecv);
 
-    let hitrate = ((zrecv_success_unique as f64) * 100.0) / (zsend_sent as f64);
-
-    println!("target-port {}", ctx.config.target_port);
+    // Results from before resuming a scan aren't counted, so neither are the probes
+    let hitrate =
+        ((zrecv_success_unique as f64) * 100.0) / (zsend_sent.saturating_sub(zsend_resumed) as f64);
+
+    println!("probe-module {}", ctx.probe_module.name());
+    println!("output-module {}", ctx.config.output_module);
+    let target_ports: Vec<String> = ctx
+        .config
+        .target_ports()
+        .iter()
+        .map(|port| port.to_string())
+        .collect();
+    println!("target-ports {}", target_ports.join(","));
     println!("source-port-range-begin {}", ctx.config.source_port_first);
     println!("source-port-range-end {}", ctx.config.source_port_last);
     println!("source-addr-range-begin {}", ctx.config.source_ip_first);
     println!("source-addr-range-end {}", ctx.config.source_ip_last);
+    if ctx.probe_module.ipv6() {
+        println!("ipv6-source-addr {}", ctx.source_ip());
+    }
     println!("maximum-targets {}", ctx.config.max_targets);
     println!("maximum-runtime {}", ctx.config.max_runtime);
     println!("maximum-results {}", ctx.config.max_results);
-    println!("permutation-seed {}", ctx.config.seed);
+    println!("permutation-seed {}", ctx.config.seed.unwrap());
+    println!("seed-validation {}", ctx.config.seed_validation);
+    if let Some(file) = &ctx.config.validation_key_file {
+        println!("validation-key-file {}", file);
+    }
+    println!("shard {}", ctx.config.shard);
+    println!("shards {}", ctx.config.shards);
     println!("cooldown-period {:?}", ctx.config.cooldown_secs);
     println!("send-interface {}", ctx.config.interface);
     println!("rate (packets per second) {}", ctx.config.rate);
+    if let (true, Some(rate_limiter)) = (ctx.config.adaptive_rate, &ctx.rate_limiter) {
+        println!("final-rate (packets per second) {:.0}", rate_limiter.rate());
+    }
     println!("bandwidth {}", ctx.config.bandwidth);
     println!("sent {}", zsend_sent);
     println!("blacklisted {}", zsend_blacklisted);
@@ -71,6 +96,19 @@ fn dump_summary(ctx: &Context) {
     println!("success-cooldown-unique {}", zrecv_cooldown_unique);
     println!("failure-total {}", zrecv_failure_total);
     println!("sendto-failures {}", zsend_sendto_failures);
+    println!("send-ring-full {}", zsend_ring_full);
+}
+
+// Replays only go through the receive path, so there are no sender statistics
+fn dump_replay_summary(ctx: &Context) {
+    let zrecv = ctx.receiver_state.lock().unwrap();
+    println!("probe-module {}", ctx.probe_module.name());
+    println!("output-module {}", ctx.config.output_module);
+    println!("replay-file {}", ctx.config.replay_pcap.as_deref().unwrap());
+    println!("packets {}", zrecv.pcap_recv);
+    println!("success-total {}", zrecv.success_total);
+    println!("success-unique {}", zrecv.success_unique);
+    println!("failure-total {}", zrecv.failure_total);
 }
 
 fn main() {
@@ -81,15 +119,30 @@ fn main() {
 
     let ctx = create_context();
 
+    if ctx.config.replay_pcap.is_some() {
+        Receiver::new(ctx.clone(), Arc::new(Results::new(&ctx))).run();
+        dump_replay_summary(&ctx);
+        return;
+    }
+
     let num_cores = get_core_num();
 
-    // Spawn a packet capture thread
-    let ctx_clone = ctx.clone();
-    let recv_thread = std::thread::spawn(move || {
-        set_thread_affinity([0]).unwrap();
-        let receiver = Receiver::new(PCAP_FILTER, ctx_clone);
-        receiver.run();
-    });
+    // Spawn the packet capture threads
+    let receiver_threads = ctx.config.receiver_threads as usize;
+    let results = Arc::new(Results::new(&ctx));
+    let mut recv_threads = vec![];
+    for thread in 0..receiver_threads {
+        let ctx = ctx.clone();
+        let results = results.clone();
+
+        let recv_thread = std::thread::spawn(move || {
+            set_thread_affinity([thread % num_cores]).unwrap();
+            let receiver = Receiver::new(ctx, results);
+            receiver.run();
+        });
+
+        recv_threads.push(recv_thread);
+    }
 
     loop {
         if ctx.receiver_state.lock().unwrap().ready {
@@ -100,11 +153,17 @@ fn main() {
 
     // Create sender threads
     let mut send_threads = vec![];
-    let mut core = 1;
-    let cyclic = Arc::new(Mutex::new(Cyclic::new()));
-    for _ in 0..ctx.config.sender_threads {
+    let ipv6_targets = match &ctx.config.ipv6_target_file {
+        Some(file) if ctx.probe_module.ipv6() => Some(Arc::new(Mutex::new(
+            Ipv6TargetFile::open(file).expect("Could not open IPv6 target file"),
+        ))),
+        _ => None,
+    };
+    for thread in 0..ctx.config.sender_threads as usize {
         let ctx = ctx.clone();
-        let cyclic = cyclic.clone();
+        // The first cores are used by the receivers
+        let core = receiver_threads + thread;
+        let ipv6_targets = ipv6_targets.clone();
 
         let send_thread = std::thread::spawn(move || {
             set_thread_affinity([core % num_cores]).unwrap();
@@ -112,20 +171,23 @@ fn main() {
             let blacklist = Blacklist::new(
                 ctx.config.whitelist_file.clone(),
                 ctx.config.blacklist_file.clone(),
+                &ctx.config.targets,
             );
 
-            let mut sender = Sender::new(ctx, cyclic, blacklist);
+            let mut sender = Sender::new(ctx, thread, blacklist);
+            if let Some(targets) = ipv6_targets {
+                sender = sender.with_ipv6_targets(targets);
+            }
             sender.run();
         });
 
         send_threads.push(send_thread);
-        core += 1;
     }
 
     // Create monitor thread
     let ctx_clone = ctx.clone();
     let monitor_thread = std::thread::spawn(move || {
-        let core = (1 + ctx.config.sender_threads as usize) % num_cores;
+        let core = (receiver_threads + ctx.config.sender_threads as usize) % num_cores;
         set_thread_affinity([core]).unwrap();
         let mut monitor = Monitor::new(ctx_clone);
         monitor.run();
@@ -136,7 +198,17 @@ fn main() {
         send_thread.join().expect("Unable to join sender thread");
     }
 
-    recv_thread.join().expect("Unable to join receiver thread");
+    // Record where sending stopped, so that resuming a finished scan doesn't probe anything again
+    if let Some(file) = ctx.checkpoint_file() {
+        let checkpoint = Checkpoint::capture(&ctx);
+        if let Err(e) = checkpoint.write(file) {
+            warn!("Could not write checkpoint {}: {}", file, e);
+        }
+    }
+
+    for recv_thread in recv_threads {
+        recv_thread.join().expect("Unable to join receiver thread");
+    }
     monitor_thread
         .join()
         .expect("Unable to join monitor thread");
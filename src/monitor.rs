@@ -1,22 +1,36 @@
 use std::time::{Duration, Instant};
 
-use log::{info, warn};
+use log::{debug, info, warn};
 
 use crate::config::Context;
+use crate::lib::checkpoint::Checkpoint;
+use crate::lib::rate_limiter::AdaptiveRate;
 
 pub struct Monitor {
     ctx: Context,
     last_now: Instant,
-    last_sent: u32,
+    last_sent: u64,
     last_rcvd: u32,
     last_drop: u32,
     last_failures: u32,
+    last_ring_full: u64,
+    last_checkpoint: Instant,
+    // Set with --adaptive-rate, steers the shared rate limiter
+    adaptive_rate: Option<AdaptiveRate>,
 }
 
 impl Monitor {
     const UPDATE_INTERVAL: u64 = 1;
 
     pub fn new(ctx: Context) -> Self {
+        // create_context resolved the bounds and clamped the starting rate
+        let adaptive_rate = ctx.config.adaptive_rate.then(|| {
+            AdaptiveRate::new(
+                ctx.config.rate as f64,
+                ctx.config.min_rate.unwrap() as f64,
+                ctx.config.max_rate.unwrap() as f64,
+            )
+        });
         Self {
             ctx,
             last_now: Instant::now(),
@@ -24,6 +38,9 @@ impl Monitor {
             last_rcvd: 0,
             last_drop: 0,
             last_failures: 0,
+            last_ring_full: 0,
+            last_checkpoint: Instant::now(),
+            adaptive_rate,
         }
     }
 
@@ -36,18 +53,40 @@ impl Monitor {
             }
 
             self.update();
+            self.checkpoint();
             std::thread::sleep(std::time::Duration::from_secs(Monitor::UPDATE_INTERVAL));
         }
     }
 
+    // Sender threads only publish their progress, so checkpoints are written from here
+    fn checkpoint(&mut self) {
+        let Some(file) = self.ctx.checkpoint_file() else {
+            return;
+        };
+        if self.last_checkpoint.elapsed() < self.ctx.config.checkpoint_interval {
+            return;
+        }
+
+        let checkpoint = Checkpoint::capture(&self.ctx);
+        match checkpoint.write(file) {
+            Ok(()) => debug!("Saved checkpoint after {} addresses", checkpoint.sent()),
+            Err(e) => warn!("Could not write checkpoint {}: {}", file, e),
+        }
+        self.last_checkpoint = Instant::now();
+    }
+
     fn update(&mut self) {
+        let zsend_sendto_failures = self.ctx.sender_threads.sendto_failures();
+        let zsend_ring_full = self.ctx.sender_threads.ring_full();
         let zsend = self.ctx.sender_state.lock().unwrap();
+        // Read under the lock, so that a checkpoint being restored can't move resumed past it
+        let sent = self.ctx.sender_threads.sent();
         let zsend_complete = zsend.complete;
         let zsend_start = zsend.start;
         let zsend_finish = zsend.finish;
-        let zsend_sent = zsend.sent;
-        let zsend_sendto_failures = zsend.sendto_failures;
-        let zsend_targets = zsend.targets;
+        // Only count this run's progress when resuming a scan, so that rates and ETA stay correct
+        let zsend_sent = sent.saturating_sub(zsend.resumed);
+        let zsend_targets = zsend.targets.saturating_sub(zsend.resumed);
         drop(zsend);
 
         let zrecv = self.ctx.receiver_state.lock().unwrap();
@@ -70,7 +109,7 @@ impl Monitor {
         );
         let percent_complete = 100.0 * age_f64 / (age_f64 + remaining_secs.as_secs_f64());
 
-        let send_rate = (zsend_sent - self.last_sent) as f64 / delta_f64;
+        let send_rate = zsend_sent.saturating_sub(self.last_sent) as f64 / delta_f64;
         let send_avg = (zsend_sent as f64) / age_f64;
         let recv_rate = (zrecv_success_unique - self.last_rcvd) as f64 / delta_f64;
         let recv_avg = (zrecv_success_unique as f64) / age_f64;
@@ -78,7 +117,9 @@ impl Monitor {
             (zrecv_pcap_drop + zrecv_pcap_ifdrop - self.last_drop) as f64 / delta_f64;
         let pcap_drop_rate_avg = (zrecv_pcap_drop + zrecv_pcap_ifdrop) as f64 / age_f64;
 
-        if pcap_drop_rate > (((zrecv_success_unique - self.last_rcvd) as f64) / delta_f64) / 20f64 {
+        let dropping =
+            pcap_drop_rate > (((zrecv_success_unique - self.last_rcvd) as f64) / delta_f64) / 20f64;
+        if dropping {
             warn!(
                 "Dropped {:.0} in the last second, {} total dropped (pcap: {} + iface: {})",
                 pcap_drop_rate,
@@ -89,16 +130,34 @@ impl Monitor {
         }
 
         let fail_rate = ((zsend_sendto_failures - self.last_failures) as f64) / delta_f64;
-        if fail_rate > ((zsend_sent as f64) / age_f64) / 100.0 {
+        let failing = fail_rate > ((zsend_sent as f64) / age_f64) / 100.0;
+        if failing {
             warn!(
                 "Failed to send {:.0} packets/sec ({} total failures)",
                 fail_rate, zsend_sendto_failures
             );
         }
 
+        // Senders waiting on a full transmit ring are sending faster than the interface can
+        if zsend_ring_full > self.last_ring_full {
+            warn!(
+                "Transmit ring was full {} times in the last second ({} total)",
+                zsend_ring_full - self.last_ring_full,
+                zsend_ring_full
+            );
+        }
+
+        if !zsend_complete {
+            self.adapt_rate(dropping || failing);
+        }
+        let rate_status = match &self.adaptive_rate {
+            Some(adaptive_rate) => format!("; rate: {:.0} p/s", adaptive_rate.rate()),
+            None => String::new(),
+        };
+
         if !zsend_complete {
             info!(
-                "{:.0?} {:.2}% ({:.0?}); send: {} {:.0} p/s ({:.0} p/s avg); recv {} {:.0} p/s ({:.0} p/s avg); drops {:.0} p/s ({:.0} p/s avg); hits: {:.2}%",
+                "{:.0?} {:.2}% ({:.0?}); send: {} {:.0} p/s ({:.0} p/s avg); recv {} {:.0} p/s ({:.0} p/s avg); drops {:.0} p/s ({:.0} p/s avg); hits: {:.2}%{}",
                 age,
                 percent_complete,
                 remaining_secs,
@@ -111,11 +170,12 @@ impl Monitor {
                 pcap_drop_rate,
                 pcap_drop_rate_avg,
                 ((zrecv_success_unique as f64) * 100.0) / (zsend_sent as f64),
+                rate_status,
             );
         } else {
             let send_avg = zsend_sent as f64 / (zsend_finish - zsend_start).as_secs_f64();
             info!(
-                "{:.0?} {:.2}% ({:.0?}); send: {} done ({:.0} p/s avg); recv {} {:.0} p/s ({:.0} p/s avg); drops {:.0} p/s ({:.0} p/s avg); hits: {:.2}%",
+                "{:.0?} {:.2}% ({:.0?}); send: {} done ({:.0} p/s avg); recv {} {:.0} p/s ({:.0} p/s avg); drops {:.0} p/s ({:.0} p/s avg); hits: {:.2}%{}",
                 age,
                 percent_complete,
                 remaining_secs,
@@ -126,7 +186,8 @@ impl Monitor {
                 recv_avg,
                 pcap_drop_rate,
                 pcap_drop_rate_avg,
-                ((zrecv_success_unique as f64) * 100.0) / (zsend_sent as f64)
+                ((zrecv_success_unique as f64) * 100.0) / (zsend_sent as f64),
+                rate_status
             );
         }
 
@@ -135,14 +196,35 @@ impl Monitor {
         self.last_rcvd = zrecv_success_unique;
         self.last_drop = zrecv_pcap_drop + zrecv_pcap_ifdrop;
         self.last_failures = zsend_sendto_failures;
+        self.last_ring_full = zsend_ring_full;
+    }
+
+    // Backs off while the receiver drops packets or sends fail, and ramps back up once they clear
+    fn adapt_rate(&mut self, congested: bool) {
+        let (Some(adaptive_rate), Some(rate_limiter)) =
+            (&mut self.adaptive_rate, &self.ctx.rate_limiter)
+        else {
+            return;
+        };
+        let previous = adaptive_rate.rate();
+        let rate = adaptive_rate.update(congested);
+        if rate == previous {
+            return;
+        }
+        rate_limiter.set_rate(rate);
+        if congested {
+            info!("Lowering send rate from {:.0} to {:.0} p/s", previous, rate);
+        } else {
+            debug!("Raising send rate from {:.0} to {:.0} p/s", previous, rate);
+        }
     }
 
     fn compute_remaining_time(
         &self,
         zsend_complete: bool,
         zsend_finish: Instant,
-        zsend_targets: u32,
-        zsend_sent: u32,
+        zsend_targets: u64,
+        zsend_sent: u64,
         zrecv_success_unique: u32,
         age: Duration,
     ) -> Duration {
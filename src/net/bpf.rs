@@ -0,0 +1,462 @@
+// References:
+// https://www.kernel.org/doc/html/latest/networking/filter.html
+// https://www.tcpdump.org/papers/bpf-usenix93.pdf
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+
+use libc::{
+    sock_filter, BPF_ABS, BPF_B, BPF_H, BPF_IND, BPF_JEQ, BPF_JGE, BPF_JGT, BPF_JMP, BPF_JSET,
+    BPF_K, BPF_LD, BPF_LDX, BPF_MSH, BPF_RET, BPF_W,
+};
+
+const ETH_HDR_SIZE: u32 = 14;
+const IPV6_HDR_SIZE: u32 = 40;
+const ETHERTYPE_IPV4: u32 = 0x0800;
+const ETHERTYPE_IPV6: u32 = 0x86dd;
+
+// Bytes of an accepted packet to capture, as much as libpcap keeps by default
+pub const SNAPLEN: u32 = 262144;
+
+/// Replies to probes a probe module is interested in, see ProbeModule::replies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    /// TCP segments to one of our source ports, matching any of the flag conditions (or all
+    /// segments if there are none)
+    Tcp(&'static [TcpFlags]),
+    /// UDP datagrams to one of our source ports
+    Udp,
+    /// ICMP messages, except for those of the given type (e.g. our own echo requests)
+    Icmp { except: Option<u8> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpFlags {
+    /// At least one of these flags is set
+    Any(u8),
+    /// Exactly these flags are set
+    Exactly(u8),
+}
+
+impl TcpFlags {
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const ACK: u8 = 0x10;
+}
+
+/// Addresses our probes are sent from, so that replies are addressed to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Ipv4 { first: Ipv4Addr, last: Ipv4Addr },
+    Ipv6(Ipv6Addr),
+}
+
+/// Jump target in an Assembler, bound to an instruction with Assembler::bind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Size {
+    fn code(self) -> u16 {
+        (match self {
+            Size::Byte => BPF_B,
+            Size::Half => BPF_H,
+            Size::Word => BPF_W,
+        }) as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// A == k
+    Eq,
+    /// A > k
+    Gt,
+    /// A >= k
+    Ge,
+    /// A & k != 0
+    Set,
+}
+
+// Where a jump goes, resolved once every label is bound
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Next,
+    Label(Label),
+}
+
+impl From<Option<Label>> for Target {
+    fn from(label: Option<Label>) -> Self {
+        label.map_or(Target::Next, Target::Label)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    code: u16,
+    jt: Target,
+    jf: Target,
+    k: u32,
+}
+
+/// Assembler for classic BPF programs (SO_ATTACH_FILTER) with symbolic jump targets. Jumps
+/// only go forward and at most 255 instructions, which is checked when assembling.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    instructions: Vec<Instruction>,
+    labels: Vec<Option<usize>>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, code: u32, jt: Target, jf: Target, k: u32) {
+        self.instructions.push(Instruction {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        });
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Make `label` point to the next instruction
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "Label bound twice");
+        self.labels[label.0] = Some(self.instructions.len());
+    }
+
+    /// A = packet[offset]
+    pub fn load(&mut self, size: Size, offset: u32) {
+        let code = BPF_LD as u16 | size.code() | BPF_ABS as u16;
+        self.push(code as u32, Target::Next, Target::Next, offset);
+    }
+
+    /// A = packet[X + offset]
+    pub fn load_indirect(&mut self, size: Size, offset: u32) {
+        let code = BPF_LD as u16 | size.code() | BPF_IND as u16;
+        self.push(code as u32, Target::Next, Target::Next, offset);
+    }
+
+    /// X = 4 * (packet[offset] & 0xf), the length of the IPv4 header at `offset`
+    pub fn load_ipv4_header_len(&mut self, offset: u32) {
+        self.push(
+            BPF_LDX | BPF_B | BPF_MSH,
+            Target::Next,
+            Target::Next,
+            offset,
+        );
+    }
+
+    /// Jump to `if_true` or `if_false` depending on `condition`, falling through for None
+    pub fn jump(
+        &mut self,
+        condition: Condition,
+        k: u32,
+        if_true: Option<Label>,
+        if_false: Option<Label>,
+    ) {
+        let op = match condition {
+            Condition::Eq => BPF_JEQ,
+            Condition::Gt => BPF_JGT,
+            Condition::Ge => BPF_JGE,
+            Condition::Set => BPF_JSET,
+        };
+        self.push(BPF_JMP | op | BPF_K, if_true.into(), if_false.into(), k);
+    }
+
+    /// Accept the packet, capturing up to `len` bytes of it (0 drops it)
+    pub fn ret(&mut self, len: u32) {
+        self.push(BPF_RET | BPF_K, Target::Next, Target::Next, len);
+    }
+
+    pub fn assemble(self) -> Vec<sock_filter> {
+        let resolve = |index: usize, target: Target| -> u8 {
+            let offset = match target {
+                Target::Next => 0,
+                Target::Label(label) => {
+                    let target = self.labels[label.0].expect("Label never bound");
+                    assert!(target > index, "BPF only jumps forward");
+                    target - index - 1
+                }
+            };
+            offset.try_into().expect("BPF jump is too far")
+        };
+
+        self.instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| sock_filter {
+                code: instruction.code,
+                jt: resolve(index, instruction.jt),
+                jf: resolve(index, instruction.jf),
+                k: instruction.k,
+            })
+            .collect()
+    }
+}
+
+/// Compile a filter that accepts the given replies when they are addressed to `destination`
+/// and, for TCP and UDP, to one of our source `ports`. IPv6 replies are expected without
+/// extension headers.
+pub fn compile(
+    replies: &[Reply],
+    destination: &Destination,
+    ports: RangeInclusive<u16>,
+) -> Vec<sock_filter> {
+    let mut asm = Assembler::new();
+    let accept = asm.label();
+    let reject = asm.label();
+
+    // Network layer: the address family and our source addresses
+    let ipv6 = matches!(destination, Destination::Ipv6(_));
+    asm.load(Size::Half, 12);
+    match destination {
+        Destination::Ipv4 { first, last } => {
+            asm.jump(Condition::Eq, ETHERTYPE_IPV4, None, Some(reject));
+            asm.load(Size::Word, ETH_HDR_SIZE + 16);
+            asm.jump(Condition::Ge, u32::from(*first), None, Some(reject));
+            asm.jump(Condition::Gt, u32::from(*last), Some(reject), None);
+            asm.load(Size::Byte, ETH_HDR_SIZE + 9);
+        }
+        Destination::Ipv6(address) => {
+            asm.jump(Condition::Eq, ETHERTYPE_IPV6, None, Some(reject));
+            for (i, word) in address.octets().chunks(4).enumerate() {
+                let word = u32::from_be_bytes(word.try_into().unwrap());
+                asm.load(Size::Word, ETH_HDR_SIZE + 24 + 4 * i as u32);
+                asm.jump(Condition::Eq, word, None, Some(reject));
+            }
+            asm.load(Size::Byte, ETH_HDR_SIZE + 6);
+        }
+    }
+
+    // Dispatch on the transport protocol
+    let labels: Vec<Label> = replies.iter().map(|_| asm.label()).collect();
+    for (reply, label) in replies.iter().zip(&labels) {
+        let protocol = match reply {
+            Reply::Tcp(_) => 6,
+            Reply::Udp => 17,
+            Reply::Icmp { .. } if ipv6 => 58,
+            Reply::Icmp { .. } => 1,
+        };
+        asm.jump(Condition::Eq, protocol, Some(*label), None);
+    }
+    asm.ret(0);
+
+    // Transport layer, which follows a variable length header for IPv4
+    let load_transport = |asm: &mut Assembler, size: Size, offset: u32| {
+        if ipv6 {
+            asm.load(size, ETH_HDR_SIZE + IPV6_HDR_SIZE + offset);
+        } else {
+            asm.load_indirect(size, ETH_HDR_SIZE + offset);
+        }
+    };
+    for (reply, label) in replies.iter().zip(labels) {
+        asm.bind(label);
+        if !ipv6 {
+            // Only the first fragment has a transport header
+            asm.load(Size::Half, ETH_HDR_SIZE + 6);
+            asm.jump(Condition::Set, 0x1fff, Some(reject), None);
+            asm.load_ipv4_header_len(ETH_HDR_SIZE);
+        }
+
+        match reply {
+            Reply::Tcp(_) | Reply::Udp => {
+                load_transport(&mut asm, Size::Half, 2);
+                asm.jump(Condition::Ge, *ports.start() as u32, None, Some(reject));
+                asm.jump(Condition::Gt, *ports.end() as u32, Some(reject), None);
+            }
+            Reply::Icmp { .. } => {}
+        }
+
+        match reply {
+            Reply::Tcp(flags) if !flags.is_empty() => {
+                load_transport(&mut asm, Size::Byte, 13);
+                for flags in flags.iter() {
+                    match flags {
+                        TcpFlags::Any(mask) => {
+                            asm.jump(Condition::Set, *mask as u32, Some(accept), None)
+                        }
+                        TcpFlags::Exactly(value) => {
+                            asm.jump(Condition::Eq, *value as u32, Some(accept), None)
+                        }
+                    }
+                }
+                asm.ret(0);
+            }
+            Reply::Icmp {
+                except: Some(icmp_type),
+            } => {
+                load_transport(&mut asm, Size::Byte, 0);
+                asm.jump(Condition::Eq, *icmp_type as u32, Some(reject), Some(accept));
+            }
+            _ => asm.ret(SNAPLEN),
+        }
+    }
+
+    asm.bind(accept);
+    asm.ret(SNAPLEN);
+    asm.bind(reject);
+    asm.ret(0);
+    asm.assemble()
+}
+
+/// Run a program compiled by `compile` on a packet in user space, e.g. when replaying a capture.
+/// Returns how many bytes of the packet to capture, 0 if the packet is dropped.
+pub fn run(program: &[sock_filter], packet: &[u8]) -> u32 {
+    let load = |size: u32, offset: usize| -> Option<u32> {
+        let len = match size {
+            BPF_B => 1,
+            BPF_H => 2,
+            _ => 4,
+        };
+        let bytes = packet.get(offset..offset + len)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as u32),
+        )
+    };
+
+    let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+    loop {
+        let insn = program[pc];
+        let code = insn.code as u32;
+        pc += 1;
+        match code & 0x07 {
+            BPF_LD => {
+                let offset = match code & 0xe0 {
+                    BPF_ABS => insn.k as usize,
+                    BPF_IND => x as usize + insn.k as usize,
+                    _ => panic!("Unexpected load {:#x}", code),
+                };
+                // Out of bounds loads drop the packet
+                match load(code & 0x18, offset) {
+                    Some(value) => a = value,
+                    None => return 0,
+                }
+            }
+            BPF_LDX => match packet.get(insn.k as usize) {
+                Some(byte) => x = 4 * (*byte as u32 & 0xf),
+                None => return 0,
+            },
+            BPF_JMP => {
+                let taken = match code & 0xf0 {
+                    BPF_JEQ => a == insn.k,
+                    BPF_JGT => a > insn.k,
+                    BPF_JGE => a >= insn.k,
+                    BPF_JSET => a & insn.k != 0,
+                    _ => panic!("Unexpected jump {:#x}", code),
+                };
+                pc += if taken { insn.jt } else { insn.jf } as usize;
+            }
+            BPF_RET => return insn.k,
+            _ => panic!("Unexpected instruction {:#x}", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::PacketBuilder;
+
+    use crate::probe_modules::{module_icmp_echoscan, module_ipv6_tcp_synscan, module_tcp_synscan};
+
+    const OURS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DESTINATION: Destination = Destination::Ipv4 {
+        first: OURS,
+        last: Ipv4Addr::new(10, 0, 0, 3),
+    };
+    const PORTS: RangeInclusive<u16> = 32768..=61000;
+
+    fn tcp(destination: Ipv4Addr, port: u16, flags: u8) -> Vec<u8> {
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4([192, 0, 2, 1], destination.octets(), 64)
+            .tcp(80, port, 1, 1024);
+        let builder = match flags {
+            0x12 => builder.syn().ack(2),
+            0x14 => builder.rst().ack(2),
+            _ => builder.ack(2),
+        };
+        let mut packet = Vec::new();
+        builder.write(&mut packet, &[]).unwrap();
+        packet
+    }
+
+    #[test]
+    fn test_bpf_tcp() {
+        let program = compile(module_tcp_synscan::REPLIES, &DESTINATION, PORTS);
+        assert_eq!(run(&program, &tcp(OURS, 40000, 0x12)), SNAPLEN);
+        assert_eq!(run(&program, &tcp(OURS, 40000, 0x14)), SNAPLEN);
+        assert_eq!(
+            run(&program, &tcp(Ipv4Addr::new(10, 0, 0, 3), 61000, 0x12)),
+            SNAPLEN
+        );
+
+        // Bare ACKs, other addresses and ports outside our range
+        assert_eq!(run(&program, &tcp(OURS, 40000, 0x10)), 0);
+        assert_eq!(
+            run(&program, &tcp(Ipv4Addr::new(10, 0, 0, 4), 40000, 0x12)),
+            0
+        );
+        assert_eq!(run(&program, &tcp(OURS, 22, 0x12)), 0);
+
+        let mut udp = Vec::new();
+        PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4([192, 0, 2, 1], OURS.octets(), 64)
+            .udp(53, 40000)
+            .write(&mut udp, &[0; 8])
+            .unwrap();
+        assert_eq!(run(&program, &udp), 0);
+    }
+
+    #[test]
+    fn test_bpf_icmp() {
+        let program = compile(module_icmp_echoscan::REPLIES, &DESTINATION, PORTS);
+        for (icmp_type, expected) in [(0, SNAPLEN), (3, SNAPLEN), (8, 0)] {
+            let mut packet = Vec::new();
+            PacketBuilder::ethernet2([1; 6], [2; 6])
+                .ipv4([192, 0, 2, 1], OURS.octets(), 64)
+                .icmpv4_raw(icmp_type, 0, [0; 4])
+                .write(&mut packet, &[])
+                .unwrap();
+            assert_eq!(run(&program, &packet), expected, "ICMP type {}", icmp_type);
+        }
+    }
+
+    #[test]
+    fn test_bpf_ipv6() {
+        let ours: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let program = compile(
+            module_ipv6_tcp_synscan::REPLIES,
+            &Destination::Ipv6(ours),
+            PORTS,
+        );
+        for (destination, expected) in [(ours, SNAPLEN), ("2001:db8::3".parse().unwrap(), 0)] {
+            let mut packet = Vec::new();
+            PacketBuilder::ethernet2([1; 6], [2; 6])
+                .ipv6([0; 16], destination.octets(), 64)
+                .tcp(80, 40000, 1, 1024)
+                .syn()
+                .ack(2)
+                .write(&mut packet, &[])
+                .unwrap();
+            assert_eq!(run(&program, &packet), expected, "{}", destination);
+        }
+
+        // IPv4 replies don't match an IPv6 filter
+        assert_eq!(run(&program, &tcp(OURS, 40000, 0x12)), 0);
+    }
+}
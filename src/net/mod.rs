@@ -1,5 +1,10 @@
+pub mod bpf;
+#[cfg(feature = "pcap")]
 pub mod pcap;
+pub mod pcap_file;
+pub mod rx_ring;
 pub mod socket;
+pub mod tx_ring;
 
 use std::{
     error::Error,
@@ -11,6 +16,7 @@ use std::{
 };
 
 use eui48::{MacAddress, ParseError};
+use libc::c_void;
 
 pub fn get_default_interface() -> Result<String, ()> {
     let cmd = Command::new("sh")
@@ -63,3 +69,21 @@ pub fn get_default_gw_mac() -> Result<MacAddress, ParseError> {
     let gw_mac_str = std::str::from_utf8(&gw_mac_out.stdout).unwrap().trim();
     MacAddress::parse_str(gw_mac_str)
 }
+
+/// Set a socket option from a plain struct, for the options socket2 doesn't cover
+pub(crate) fn setsockopt<T>(fd: i32, level: i32, option: i32, value: &T) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            value as *const T as *const c_void,
+            std::mem::size_of::<T>() as u32,
+        )
+    };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
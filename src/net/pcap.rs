@@ -1,10 +1,9 @@
 // References:
-// https://www.tcpdump.org/manpages/pcap_compile.3pcap.html
 // https://www.tcpdump.org/manpages/pcap_setfilter.3pcap.html
 // https://man7.org/linux/man-pages/man3/pcap_loop.3pcap.html
 use core::slice;
 
-pub use libc::{c_char, c_int, c_uchar, c_uint, c_ushort, timeval};
+pub use libc::{c_char, c_int, c_uchar, c_uint, c_ushort, sock_filter, timeval};
 use log::debug;
 
 // Callback function to handle individual packets
@@ -29,15 +28,8 @@ pub struct pcap_pkthdr {
 #[repr(C)]
 struct bpf_program {
     bf_len: c_uint,
-    bf_insns: *mut bpf_insn,
-}
-
-#[repr(C)]
-struct bpf_insn {
-    code: c_ushort,
-    jt: c_uchar,
-    jf: c_uchar,
-    k: c_uint,
+    // Same layout as libpcap's bpf_insn
+    bf_insns: *mut sock_filter,
 }
 
 #[repr(C)]
@@ -64,16 +56,7 @@ extern "C" {
         errbuf: *mut c_char,
     ) -> *mut pcap_t;
 
-    // Compile a filter expression
-    fn pcap_compile(
-        p: *mut pcap_t,
-        fp: *mut bpf_program,
-        string: *const c_char,
-        optimize: c_int,
-        netmask: c_uint,
-    ) -> c_int;
-
-    // Set the filter with the bpf program created by pcap_compile
+    // Set the filter to a bpf program
     fn pcap_setfilter(p: *mut pcap_t, fp: *mut bpf_program) -> c_int;
 
     // Processes packets from a live capture until count packets are processed
@@ -109,7 +92,6 @@ impl PacketCapture {
     const PCAP_SNAPLEN: c_int = 8192;
     const PCAP_PROMISC: c_int = 1;
     const PCAP_TIMEOUT: c_int = -1;
-    const PCAP_OPTIMIZE: c_int = 1;
 
     pub fn new(interface: &str) -> Self {
         // Try to open the device
@@ -138,27 +120,12 @@ impl PacketCapture {
         Self { handle: p }
     }
 
-    pub fn with_filter(self, filter: &str) -> Self {
-        // Compile the filter
+    pub fn with_filter(self, filter: &[sock_filter]) -> Self {
+        // pcap copies the program, see net::bpf for how it's compiled
         let mut bpf = bpf_program {
-            bf_len: 0,
-            bf_insns: std::ptr::null_mut(),
+            bf_len: filter.len() as c_uint,
+            bf_insns: filter.as_ptr() as *mut sock_filter,
         };
-        let filter_cstr = std::ffi::CString::new(filter).unwrap();
-        let res = unsafe {
-            pcap_compile(
-                self.handle,
-                &mut bpf,
-                filter_cstr.as_ptr(),
-                Self::PCAP_OPTIMIZE,
-                0,
-            )
-        };
-        if res < 0 {
-            panic!("pcap_compile failed to compile filter");
-        }
-
-        // Install the filter
         let res = unsafe { pcap_setfilter(self.handle, &mut bpf) };
         if res < 0 {
             panic!("pcap_setfilter failed to install filter");
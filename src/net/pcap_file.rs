@@ -0,0 +1,407 @@
+// References:
+// https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-04.html
+// https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::time::Duration;
+
+use super::bpf::SNAPLEN;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+const LINKTYPE_ETHERNET: u32 = 1;
+// Replies are cut to the filter's snaplen when capturing, so longer packets aren't expected. The
+// limits keep a corrupt length from allocating gigabytes.
+const MAX_CAPLEN: usize = SNAPLEN as usize;
+// Room for a packet of the maximum length and its options
+const MAX_BLOCK_LEN: usize = 2 * MAX_CAPLEN;
+
+/// Packet read from a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    // Capture time since the Unix epoch
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Format {
+    Pcap {
+        big_endian: bool,
+        // Timestamp units per second
+        resolution: u64,
+    },
+    Pcapng {
+        big_endian: bool,
+        // Timestamp resolution of each interface of the current section
+        interfaces: Vec<u64>,
+    },
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reader for Ethernet captures in the pcap or pcapng format, e.g. written by tcpdump. In pcapng
+/// files, only enhanced packet blocks are read, as the other packet blocks have no timestamp.
+pub struct PcapFile<R: Read> {
+    reader: R,
+    format: Format,
+}
+
+impl PcapFile<BufReader<File>> {
+    pub fn open(file: &str) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(file)?))
+    }
+}
+
+impl<R: Read> PcapFile<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut file = Self {
+                reader,
+                format: Format::Pcapng {
+                    big_endian: false,
+                    interfaces: Vec::new(),
+                },
+            };
+            file.read_section_header()?;
+            return Ok(file);
+        }
+
+        let (magic, big_endian) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (magic @ (PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS), _) => (magic, false),
+            (_, magic @ (PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS)) => (magic, true),
+            _ => return Err(invalid("not a pcap or pcapng file".to_string())),
+        };
+        let resolution = if magic == PCAP_MAGIC_NANOS {
+            1_000_000_000
+        } else {
+            1_000_000
+        };
+        let format = Format::Pcap {
+            big_endian,
+            resolution,
+        };
+
+        // Version, reserved fields and snaplen, followed by the link type
+        let mut header = [0; 20];
+        reader.read_exact(&mut header)?;
+        let mut file = Self { reader, format };
+        let linktype = file.u32(&header[16..20]) & 0xffff;
+        if linktype != LINKTYPE_ETHERNET {
+            return Err(invalid(format!("unsupported link type {}", linktype)));
+        }
+        Ok(file)
+    }
+
+    fn big_endian(&self) -> bool {
+        match self.format {
+            Format::Pcap { big_endian, .. } | Format::Pcapng { big_endian, .. } => big_endian,
+        }
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = bytes[..2].try_into().unwrap();
+        if self.big_endian() {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        if self.big_endian() {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    // Reads exactly `len` bytes, or returns None at the end of the file
+    fn read(&mut self, len: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match self.reader.read(&mut buffer[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        Ok(Some(buffer))
+    }
+
+    fn read_exact(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        self.read(len)?
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+
+    // Skips `len` bytes without keeping them in memory
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        let skipped = io::copy(&mut self.reader.by_ref().take(len as u64), &mut io::sink())?;
+        if skipped < len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the next packet, or None at the end of the file
+    pub fn next_packet(&mut self) -> io::Result<Option<Record>> {
+        match self.format {
+            Format::Pcap { resolution, .. } => {
+                let Some(header) = self.read(16)? else {
+                    return Ok(None);
+                };
+                let seconds = self.u32(&header[0..4]) as u64;
+                let fraction = self.u32(&header[4..8]) as u64;
+                let caplen = self.u32(&header[8..12]) as usize;
+                if caplen > MAX_CAPLEN {
+                    return Err(invalid(format!(
+                        "packet length {} exceeds the maximum of {}",
+                        caplen, MAX_CAPLEN
+                    )));
+                }
+                Ok(Some(Record {
+                    timestamp: timestamp(seconds * resolution + fraction, resolution),
+                    data: self.read_exact(caplen)?,
+                }))
+            }
+            Format::Pcapng { .. } => self.next_pcapng_packet(),
+        }
+    }
+
+    fn next_pcapng_packet(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let Some(header) = self.read(8)? else {
+                return Ok(None);
+            };
+            let block_type = self.u32(&header[0..4]);
+            if block_type == PCAPNG_SECTION_HEADER {
+                self.read_section_header_body(header[4..8].try_into().unwrap())?;
+                continue;
+            }
+
+            // The body is followed by a copy of the total length
+            let total_len = self.u32(&header[4..8]) as usize;
+            if total_len < 12 || !total_len.is_multiple_of(4) {
+                return Err(invalid(format!(
+                    "invalid pcapng block length {}",
+                    total_len
+                )));
+            }
+            let min_len = match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => 8,
+                PCAPNG_ENHANCED_PACKET => 20,
+                _ => {
+                    self.skip(total_len - 8)?;
+                    continue;
+                }
+            };
+            if total_len > MAX_BLOCK_LEN {
+                return Err(invalid(format!(
+                    "pcapng block length {} exceeds the maximum of {}",
+                    total_len, MAX_BLOCK_LEN
+                )));
+            }
+            let body = self.read_exact(total_len - 8)?;
+            let body = &body[..body.len() - 4];
+            if body.len() < min_len {
+                return Err(invalid(format!("truncated pcapng block {}", block_type)));
+            }
+
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => {
+                    let linktype = self.u16(&body[0..2]) as u32;
+                    if linktype != LINKTYPE_ETHERNET {
+                        return Err(invalid(format!("unsupported link type {}", linktype)));
+                    }
+                    let resolution = self.interface_resolution(&body[8..]);
+                    if let Format::Pcapng { interfaces, .. } = &mut self.format {
+                        interfaces.push(resolution);
+                    }
+                }
+                PCAPNG_ENHANCED_PACKET => {
+                    let interface = self.u32(&body[0..4]) as usize;
+                    let Format::Pcapng { interfaces, .. } = &self.format else {
+                        unreachable!();
+                    };
+                    let resolution = *interfaces.get(interface).ok_or_else(|| {
+                        invalid(format!("packet from unknown interface {}", interface))
+                    })?;
+                    let time = (self.u32(&body[4..8]) as u64) << 32 | self.u32(&body[8..12]) as u64;
+                    let caplen = self.u32(&body[12..16]) as usize;
+                    let data = body
+                        .get(20..20 + caplen)
+                        .ok_or_else(|| invalid("truncated enhanced packet block".to_string()))?;
+                    return Ok(Some(Record {
+                        timestamp: timestamp(time, resolution),
+                        data: data.to_vec(),
+                    }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Called after the block type of a section header block was read
+    fn read_section_header(&mut self) -> io::Result<()> {
+        let total_len = self.read_exact(4)?;
+        self.read_section_header_body(total_len.try_into().unwrap())
+    }
+
+    // Every section has its own byte order and interfaces
+    fn read_section_header_body(&mut self, total_len: [u8; 4]) -> io::Result<()> {
+        let byte_order = self.read_exact(4)?;
+        let big_endian = match byte_order.try_into().unwrap() {
+            magic if u32::from_le_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC => false,
+            magic if u32::from_be_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(invalid("invalid pcapng byte order magic".to_string())),
+        };
+        self.format = Format::Pcapng {
+            big_endian,
+            interfaces: Vec::new(),
+        };
+
+        let total_len = self.u32(&total_len) as usize;
+        if total_len < 28 || !total_len.is_multiple_of(4) {
+            return Err(invalid(format!(
+                "invalid pcapng block length {}",
+                total_len
+            )));
+        }
+        self.skip(total_len - 12)
+    }
+
+    // Timestamps are in microseconds unless the if_tsresol option says otherwise
+    fn interface_resolution(&self, mut options: &[u8]) -> u64 {
+        while options.len() >= 4 {
+            let code = self.u16(&options[0..2]);
+            let len = self.u16(&options[2..4]) as usize;
+            if code == PCAPNG_OPTION_END {
+                break;
+            }
+            if code == PCAPNG_OPTION_TSRESOL && len == 1 && options.len() > 4 {
+                let exponent = (options[4] & 0x7f) as u32;
+                return if options[4] & 0x80 == 0 {
+                    10u64.pow(exponent.min(19))
+                } else {
+                    1u64 << exponent.min(63)
+                };
+            }
+            options = options
+                .get(4 + len.next_multiple_of(4)..)
+                .unwrap_or_default();
+        }
+        1_000_000
+    }
+}
+
+fn timestamp(time: u64, resolution: u64) -> Duration {
+    let nanos = time as u128 % resolution as u128 * 1_000_000_000 / resolution as u128;
+    Duration::new(time / resolution, nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_file() {
+        // Big endian with nanosecond timestamps
+        let mut file = Vec::new();
+        file.extend(PCAP_MAGIC_NANOS.to_be_bytes());
+        file.extend([0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        file.extend(LINKTYPE_ETHERNET.to_be_bytes());
+        for (seconds, nanos, data) in [(1, 500, &[1, 2, 3][..]), (2, 0, &[4][..])] {
+            file.extend(u32::to_be_bytes(seconds));
+            file.extend(u32::to_be_bytes(nanos));
+            file.extend((data.len() as u32).to_be_bytes());
+            file.extend((data.len() as u32).to_be_bytes());
+            file.extend(data);
+        }
+
+        let mut reader = PcapFile::new(&file[..]).unwrap();
+        let record = reader.next_packet().unwrap().unwrap();
+        assert_eq!(record.timestamp, Duration::new(1, 500));
+        assert_eq!(record.data, vec![1, 2, 3]);
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, vec![4]);
+        assert_eq!(reader.next_packet().unwrap(), None);
+
+        // Truncated records are errors rather than the end of the file
+        let mut reader = PcapFile::new(&file[..file.len() - 1]).unwrap();
+        reader.next_packet().unwrap();
+        assert!(reader.next_packet().is_err());
+
+        // So are lengths no capture would have, without reading that much first
+        let mut file = file[..24].to_vec();
+        file.extend([0; 8]);
+        file.extend([0xff; 8]);
+        let mut reader = PcapFile::new(&file[..]).unwrap();
+        let error = reader.next_packet().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let total_len = (12 + body.len().next_multiple_of(4)) as u32;
+        let mut block = Vec::new();
+        block.extend(block_type.to_le_bytes());
+        block.extend(total_len.to_le_bytes());
+        block.extend(body);
+        block.resize(total_len as usize - 4, 0);
+        block.extend(total_len.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn test_pcapng_file() {
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend([1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+        // An interface with millisecond timestamps
+        let mut interface = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        interface.extend([9, 0, 1, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut packet = vec![0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend(1_234u32.to_le_bytes());
+        packet.extend([5, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5]);
+
+        let mut file = block(PCAPNG_SECTION_HEADER, &section);
+        file.extend(block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+        file.extend(block(5, &[0; 8]));
+        file.extend(block(PCAPNG_ENHANCED_PACKET, &packet));
+
+        let mut reader = PcapFile::new(&file[..]).unwrap();
+        let record = reader.next_packet().unwrap().unwrap();
+        assert_eq!(record.timestamp, Duration::from_millis(1_234));
+        assert_eq!(record.data, vec![1, 2, 3, 4, 5]);
+        assert_eq!(reader.next_packet().unwrap(), None);
+
+        // Blocks that aren't read are skipped whatever their length, the others are limited
+        let mut file = block(PCAPNG_SECTION_HEADER, &section);
+        file.extend(block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+        file.extend(5u32.to_le_bytes());
+        file.extend(0xfffffffcu32.to_le_bytes());
+        let mut reader = PcapFile::new(&file[..]).unwrap();
+        let error = reader.next_packet().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut file = block(PCAPNG_SECTION_HEADER, &section);
+        file.extend(block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+        file.extend(PCAPNG_ENHANCED_PACKET.to_le_bytes());
+        file.extend(0xfffffffcu32.to_le_bytes());
+        let mut reader = PcapFile::new(&file[..]).unwrap();
+        let error = reader.next_packet().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
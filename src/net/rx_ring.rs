@@ -0,0 +1,398 @@
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use libc::{
+    c_void, packet_mreq, pollfd, sock_filter, sock_fprog, sockaddr, sockaddr_ll, socklen_t,
+    tpacket3_hdr, tpacket_block_desc, tpacket_req3, tpacket_stats_v3, tpacket_versions, AF_PACKET,
+    ETH_P_ALL, MAP_FAILED, MAP_SHARED, PACKET_ADD_MEMBERSHIP, PACKET_FANOUT,
+    PACKET_FANOUT_FLAG_DEFRAG, PACKET_FANOUT_FLAG_UNIQUEID, PACKET_FANOUT_HASH, PACKET_MR_PROMISC,
+    PACKET_OUTGOING, PACKET_RX_RING, PACKET_STATISTICS, PACKET_VERSION, POLLERR, POLLIN, PROT_READ,
+    PROT_WRITE, SOCK_RAW, SOL_PACKET, SOL_SOCKET, SO_ATTACH_FILTER, TPACKET_ALIGNMENT,
+    TP_STATUS_KERNEL, TP_STATUS_USER,
+};
+use socket2::Socket;
+
+use super::setsockopt;
+
+/// Capture counters equivalent to pcap_stat, counted since the ring was opened
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureStats {
+    // Packets that passed the filter, including the dropped ones
+    pub recv: u32,
+    // Packets dropped because the ring was full
+    pub drop: u32,
+    // Packets dropped by the interface
+    pub ifdrop: u32,
+}
+
+/// Receive ring shared with the kernel (PACKET_RX_RING, TPACKET_V3). The kernel fills whole
+/// blocks of packets that are read in place, so there is no copy or system call per packet.
+/// Blocks are handed over once full, or after a short timeout so replies aren't held back.
+pub struct RxRing {
+    socket: Socket,
+    ring: *mut u8,
+    // Next block to read
+    head: usize,
+    stats: CaptureStats,
+    // Interface drops are only exposed through sysfs, so remember where they started
+    rx_dropped_path: String,
+    rx_dropped_start: u64,
+}
+
+impl RxRing {
+    // In network byte order, as both socket() and bind() expect
+    const PROTO: u16 = (ETH_P_ALL as u16).to_be();
+    const FRAME_SIZE: usize = 2048;
+    const BLOCK_SIZE: usize = 1 << 20;
+    const BLOCK_NR: usize = 64;
+    const BLOCK_TIMEOUT_MS: u32 = 10;
+
+    pub fn new(interface: &str, filter: &[sock_filter]) -> std::io::Result<Self> {
+        let interface_index = super::get_interface_index(interface)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let socket = Socket::new(
+            AF_PACKET.into(),
+            SOCK_RAW.into(),
+            Some((Self::PROTO as i32).into()),
+        )?;
+        let fd = socket.as_raw_fd();
+
+        // Attach the filter before binding, so the ring never sees unfiltered traffic
+        let program = sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut sock_filter,
+        };
+        setsockopt(fd, SOL_SOCKET, SO_ATTACH_FILTER, &program)?;
+
+        let version = tpacket_versions::TPACKET_V3 as i32;
+        setsockopt(fd, SOL_PACKET, PACKET_VERSION, &version)?;
+        let req = tpacket_req3 {
+            tp_block_size: Self::BLOCK_SIZE as u32,
+            tp_block_nr: Self::BLOCK_NR as u32,
+            tp_frame_size: Self::FRAME_SIZE as u32,
+            tp_frame_nr: (Self::BLOCK_SIZE / Self::FRAME_SIZE * Self::BLOCK_NR) as u32,
+            tp_retire_blk_tov: Self::BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, SOL_PACKET, PACKET_RX_RING, &req)?;
+
+        let size = Self::BLOCK_SIZE * Self::BLOCK_NR;
+        let ring = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ring == MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let rx_dropped_path = format!("/sys/class/net/{}/statistics/rx_dropped", interface);
+        let rx_dropped_start = read_counter(&rx_dropped_path);
+        let ring = Self {
+            socket,
+            ring: ring as *mut u8,
+            head: 0,
+            stats: CaptureStats::default(),
+            rx_dropped_path,
+            rx_dropped_start,
+        };
+
+        let sockaddr = sockaddr_ll {
+            sll_family: AF_PACKET as u16,
+            sll_protocol: Self::PROTO,
+            sll_ifindex: interface_index,
+            sll_hatype: 0,
+            sll_pkttype: 0,
+            sll_halen: 0,
+            sll_addr: [0; 8],
+        };
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &sockaddr as *const sockaddr_ll as *const sockaddr,
+                std::mem::size_of::<sockaddr_ll>() as u32,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Replies are addressed to our source IP, which may not be the interface's own address
+        let membership = packet_mreq {
+            mr_ifindex: interface_index,
+            mr_type: PACKET_MR_PROMISC as u16,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        setsockopt(fd, SOL_PACKET, PACKET_ADD_MEMBERSHIP, &membership)?;
+
+        Ok(ring)
+    }
+
+    /// Split the interface's traffic with the other rings that joined `group`, or start a new
+    /// group with an ID picked by the kernel that no other process uses. Returns the group's ID.
+    /// Packets are spread by a hash of their flow, so replies from a target always end up in the
+    /// same ring.
+    pub fn join_fanout(&self, group: Option<u16>) -> std::io::Result<u16> {
+        // Fragments are reassembled first, as only the first one would have the ports to hash
+        let mut mode = PACKET_FANOUT_HASH | PACKET_FANOUT_FLAG_DEFRAG;
+        if group.is_none() {
+            mode |= PACKET_FANOUT_FLAG_UNIQUEID;
+        }
+        let fanout = group.unwrap_or(0) as u32 | mode << 16;
+        let fd = self.socket.as_raw_fd();
+        setsockopt(fd, SOL_PACKET, PACKET_FANOUT, &(fanout as i32))?;
+
+        // The group's ID is in the low bits, followed by its mode
+        let mut fanout: u32 = 0;
+        let mut len = std::mem::size_of::<u32>() as socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_PACKET,
+                PACKET_FANOUT,
+                &mut fanout as *mut u32 as *mut c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(fanout as u16)
+    }
+
+    fn block(&self, block: usize) -> *mut tpacket_block_desc {
+        unsafe { self.ring.add(block * Self::BLOCK_SIZE) as *mut tpacket_block_desc }
+    }
+
+    fn block_status(&self, block: usize) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(&raw mut (*self.block(block)).hdr.bh1.block_status) }
+    }
+
+    fn block_ready(&self) -> bool {
+        self.block_status(self.head).load(Ordering::Acquire) & TP_STATUS_USER != 0
+    }
+
+    /// Returns the next block of packets, waiting up to `timeout` for the kernel to hand one over
+    pub fn next_block(&mut self, timeout: Duration) -> std::io::Result<Option<Block<'_>>> {
+        if !self.block_ready() {
+            let mut fds = pollfd {
+                fd: self.socket.as_raw_fd(),
+                events: POLLIN | POLLERR,
+                revents: 0,
+            };
+            let result = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as i32) };
+            if result < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            if !self.block_ready() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Block { ring: self }))
+    }
+
+    /// Counters since the ring was opened, like pcap_stats
+    pub fn stats(&mut self) -> std::io::Result<CaptureStats> {
+        // The kernel resets its counters on every read
+        let mut stats = tpacket_stats_v3 {
+            tp_packets: 0,
+            tp_drops: 0,
+            tp_freeze_q_cnt: 0,
+        };
+        let mut len = std::mem::size_of::<tpacket_stats_v3>() as socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                SOL_PACKET,
+                PACKET_STATISTICS,
+                &mut stats as *mut tpacket_stats_v3 as *mut c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        self.stats.recv = self.stats.recv.wrapping_add(stats.tp_packets);
+        self.stats.drop = self.stats.drop.wrapping_add(stats.tp_drops);
+        self.stats.ifdrop =
+            read_counter(&self.rx_dropped_path).saturating_sub(self.rx_dropped_start) as u32;
+        Ok(self.stats)
+    }
+}
+
+impl Drop for RxRing {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ring as *mut c_void, Self::BLOCK_SIZE * Self::BLOCK_NR) };
+    }
+}
+
+// Interface counters may not exist for every interface, e.g. in containers
+fn read_counter(path: &str) -> u64 {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|counter| counter.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Block of packets owned by user space until dropped, when it's handed back to the kernel
+pub struct Block<'a> {
+    ring: &'a mut RxRing,
+}
+
+impl Block<'_> {
+    /// Packets in the block, from the Ethernet header on
+    pub fn packets(&self) -> Packets<'_> {
+        let block = self.ring.block(self.ring.head) as *const u8;
+        unsafe {
+            let header = &(*(block as *const tpacket_block_desc)).hdr.bh1;
+            Packets::new(
+                block.add(header.offset_to_first_pkt as usize),
+                header.num_pkts,
+            )
+        }
+    }
+}
+
+impl Drop for Block<'_> {
+    fn drop(&mut self) {
+        let ring = &mut *self.ring;
+        ring.block_status(ring.head)
+            .store(TP_STATUS_KERNEL, Ordering::Release);
+        ring.head = (ring.head + 1) % RxRing::BLOCK_NR;
+    }
+}
+
+/// Iterator over the packets of a block, skipping the ones we sent ourselves
+pub struct Packets<'a> {
+    next: *const u8,
+    remaining: u32,
+    _block: PhantomData<&'a [u8]>,
+}
+
+impl Packets<'_> {
+    // The packet's sockaddr_ll follows the aligned tpacket3_hdr
+    const SOCKADDR_OFFSET: usize =
+        std::mem::size_of::<tpacket3_hdr>().next_multiple_of(TPACKET_ALIGNMENT);
+
+    /// Safety: `first` must point to `count` packets chained by tp_next_offset
+    unsafe fn new(first: *const u8, count: u32) -> Self {
+        Self {
+            next: first,
+            remaining: count,
+            _block: PhantomData,
+        }
+    }
+}
+
+impl<'a> Iterator for Packets<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while self.remaining > 0 {
+            let (header, address) = unsafe {
+                (
+                    &*(self.next as *const tpacket3_hdr),
+                    &*(self.next.add(Self::SOCKADDR_OFFSET) as *const sockaddr_ll),
+                )
+            };
+            let data = unsafe {
+                std::slice::from_raw_parts(
+                    self.next.add(header.tp_mac as usize),
+                    header.tp_snaplen as usize,
+                )
+            };
+            self.remaining -= 1;
+            self.next = unsafe { self.next.add(header.tp_next_offset as usize) };
+
+            // Like pcap_setdirection(PCAP_D_IN), our own probes aren't replies
+            if address.sll_pkttype != PACKET_OUTGOING {
+                return Some(data);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lay out packets in a buffer the way the kernel does in a block
+    fn write_packet(block: &mut Vec<u8>, data: &[u8], pkttype: u8) {
+        let start = block.len();
+        let mac = Packets::SOCKADDR_OFFSET + std::mem::size_of::<sockaddr_ll>();
+        let next = (mac + data.len()).next_multiple_of(TPACKET_ALIGNMENT);
+        block.resize(start + next, 0);
+
+        let header = block[start..].as_mut_ptr() as *mut tpacket3_hdr;
+        let address = block[start + Packets::SOCKADDR_OFFSET..].as_mut_ptr() as *mut sockaddr_ll;
+        unsafe {
+            (*header).tp_next_offset = next as u32;
+            (*header).tp_snaplen = data.len() as u32;
+            (*header).tp_len = data.len() as u32;
+            (*header).tp_mac = mac as u16;
+            (*address).sll_pkttype = pkttype;
+        }
+        block[start + mac..start + mac + data.len()].copy_from_slice(data);
+    }
+
+    #[test]
+    fn test_rx_ring_packets() {
+        let mut block = Vec::new();
+        write_packet(&mut block, &[1, 2, 3], libc::PACKET_HOST);
+        write_packet(&mut block, &[4, 5], PACKET_OUTGOING);
+        write_packet(&mut block, &[6; 100], libc::PACKET_OTHERHOST);
+
+        let packets: Vec<&[u8]> = unsafe { Packets::new(block.as_ptr(), 3) }.collect();
+        assert_eq!(packets, vec![&[1, 2, 3][..], &[6; 100][..]]);
+    }
+
+    const ACCEPT: [sock_filter; 1] = [sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: u32::MAX,
+    }];
+
+    // Needs CAP_NET_RAW
+    #[ignore]
+    #[test]
+    fn test_rx_ring_capture() {
+        let mut ring = RxRing::new("lo", &ACCEPT).unwrap();
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let payload = b"zmap-rs rx ring test";
+        socket
+            .send_to(payload, socket.local_addr().unwrap())
+            .unwrap();
+
+        let block = ring
+            .next_block(Duration::from_secs(1))
+            .unwrap()
+            .expect("Nothing captured");
+        assert!(block.packets().any(|packet| packet.ends_with(payload)));
+    }
+
+    // Needs CAP_NET_RAW
+    #[ignore]
+    #[test]
+    fn test_rx_ring_fanout() {
+        let first = RxRing::new("lo", &ACCEPT).unwrap();
+        let second = RxRing::new("lo", &ACCEPT).unwrap();
+        let group = first.join_fanout(None).unwrap();
+        assert_eq!(second.join_fanout(Some(group)).unwrap(), group);
+    }
+}
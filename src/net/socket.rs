@@ -1,9 +1,74 @@
 use std::os::unix::io::AsRawFd;
 
 use eui48::MacAddress;
-use libc::{c_void, sendto, sockaddr, sockaddr_ll, AF_PACKET, ETH_P_ALL, SOCK_RAW};
+use libc::{
+    c_void, iovec, mmsghdr, sendmmsg, sendto, sockaddr, sockaddr_ll, AF_PACKET, ETH_P_ALL, SOCK_RAW,
+};
 use socket2::Socket;
 
+use crate::probe_modules::packet::MAX_PACKET_SIZE;
+
+/// Packets to send with a single call to RawEthSocket::send_batch. Buffers are kept between
+/// batches, so that filling a batch doesn't allocate once it has been used.
+pub struct PacketBatch {
+    // Buffers are allocated at MAX_PACKET_SIZE, with the length of the packet in each kept apart
+    buffers: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    capacity: usize,
+}
+
+impl PacketBatch {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Batches must hold at least one packet");
+        Self {
+            buffers: Vec::with_capacity(capacity),
+            lens: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Copy a packet into the next free buffer
+    pub fn push(&mut self, packet: &[u8]) {
+        self.push_with(|buffer| {
+            buffer[..packet.len()].copy_from_slice(packet);
+            packet.len()
+        });
+    }
+
+    /// Let `write` build a packet in the next free buffer, and return its length
+    pub fn push_with(&mut self, write: impl FnOnce(&mut [u8]) -> usize) {
+        assert!(!self.is_full(), "Packet batch is full");
+        if self.lens.len() == self.buffers.len() {
+            self.buffers.push(vec![0; MAX_PACKET_SIZE]);
+        }
+        let len = write(&mut self.buffers[self.lens.len()]);
+        self.lens.push(len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.lens.len() == self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.lens.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.buffers
+            .iter()
+            .zip(&self.lens)
+            .map(|(buffer, &len)| &buffer[..len])
+    }
+}
+
 pub struct RawEthSocket {
     inner: Socket,
 }
@@ -17,12 +82,7 @@ impl RawEthSocket {
         Self { inner: socket }
     }
 
-    pub fn sendto(
-        &self,
-        buf: &[u8],
-        interface_index: i32,
-        address: &MacAddress,
-    ) -> Result<(), std::io::Error> {
+    fn sockaddr(interface_index: i32, address: &MacAddress) -> sockaddr_ll {
         let mut sockaddr = sockaddr_ll {
             sll_family: AF_PACKET as u16,
             sll_protocol: (Self::PROTO as u16).to_be(),
@@ -33,7 +93,16 @@ impl RawEthSocket {
             sll_addr: [0; 8],
         };
         sockaddr.sll_addr[..6].copy_from_slice(address.as_bytes());
+        sockaddr
+    }
 
+    pub fn sendto(
+        &self,
+        buf: &[u8],
+        interface_index: i32,
+        address: &MacAddress,
+    ) -> Result<(), std::io::Error> {
+        let sockaddr = Self::sockaddr(interface_index, address);
         let result = unsafe {
             sendto(
                 self.inner.as_raw_fd(),
@@ -51,4 +120,95 @@ impl RawEthSocket {
             Ok(())
         }
     }
+
+    /// Send a batch of packets with as few sendmmsg calls as possible. A packet that can't be sent
+    /// is skipped, and returned with its index in the batch and the error.
+    pub fn send_batch(
+        &self,
+        batch: &PacketBatch,
+        interface_index: i32,
+        address: &MacAddress,
+    ) -> Vec<(usize, std::io::Error)> {
+        // There is nothing to gain from sendmmsg for a single packet
+        if batch.len() == 1 {
+            let packet = batch.iter().next().unwrap();
+            return match self.sendto(packet, interface_index, address) {
+                Ok(()) => vec![],
+                Err(e) => vec![(0, e)],
+            };
+        }
+
+        let mut sockaddr = Self::sockaddr(interface_index, address);
+        let mut iovecs: Vec<iovec> = batch
+            .iter()
+            .map(|packet| iovec {
+                iov_base: packet.as_ptr() as *mut c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        let mut messages: Vec<mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                // msghdr has private padding fields on some targets, so it can't be built directly
+                let mut message: mmsghdr = unsafe { std::mem::zeroed() };
+                message.msg_hdr.msg_name = &mut sockaddr as *mut sockaddr_ll as *mut c_void;
+                message.msg_hdr.msg_namelen = std::mem::size_of::<sockaddr_ll>() as u32;
+                message.msg_hdr.msg_iov = iovec;
+                message.msg_hdr.msg_iovlen = 1;
+                message
+            })
+            .collect();
+
+        let mut failures = vec![];
+        let mut sent = 0;
+        while sent < messages.len() {
+            let result = unsafe {
+                sendmmsg(
+                    self.inner.as_raw_fd(),
+                    messages[sent..].as_mut_ptr(),
+                    (messages.len() - sent) as u32,
+                    0,
+                )
+            };
+
+            // sendmmsg only fails if the first remaining packet couldn't be sent
+            if result < 0 {
+                failures.push((sent, std::io::Error::last_os_error()));
+                sent += 1;
+            } else {
+                sent += result as usize;
+            }
+        }
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_batch() {
+        let mut batch = PacketBatch::new(2);
+        batch.push(&[1, 2, 3]);
+        batch.push(&[4]);
+        assert!(batch.is_full());
+        assert_eq!(batch.iter().collect::<Vec<_>>(), vec![&[1, 2, 3][..], &[4]]);
+
+        // Buffers are reused after clearing
+        batch.clear();
+        assert!(batch.is_empty());
+        batch.push(&[5, 6]);
+        assert_eq!(batch.iter().collect::<Vec<_>>(), vec![&[5, 6][..]]);
+        assert_eq!(batch.buffers.len(), 2);
+
+        // Packets can also be built in place
+        batch.push_with(|buffer| {
+            assert_eq!(buffer.len(), MAX_PACKET_SIZE);
+            buffer[..2].copy_from_slice(&[7, 8]);
+            2
+        });
+        assert_eq!(batch.iter().collect::<Vec<_>>(), vec![&[5, 6][..], &[7, 8]]);
+        assert_eq!(batch.buffers.len(), 2);
+    }
 }
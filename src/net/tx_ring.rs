@@ -0,0 +1,176 @@
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc::{
+    c_void, sockaddr, sockaddr_ll, tpacket2_hdr, tpacket_req, tpacket_versions, AF_PACKET,
+    ETH_P_ALL, MAP_FAILED, MAP_SHARED, PACKET_TX_RING, PACKET_VERSION, PROT_READ, PROT_WRITE,
+    SOCK_RAW, SOL_PACKET, TPACKET_ALIGNMENT, TP_STATUS_AVAILABLE, TP_STATUS_SEND_REQUEST,
+    TP_STATUS_WRONG_FORMAT,
+};
+use socket2::Socket;
+
+use super::setsockopt;
+
+/// Outcome of waiting for a free frame, see TxRing::next_frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameWait {
+    // The ring was full, so we had to wait for the kernel to send queued frames
+    pub ring_full: bool,
+    // The kernel rejected the probe previously queued in this frame
+    pub failed: bool,
+}
+
+/// Transmit ring shared with the kernel (PACKET_TX_RING). Probes are written straight into the
+/// frames of the ring and handed to the kernel with a single send() for all queued frames, so
+/// the kernel doesn't copy them from user space and there is no system call per packet.
+pub struct TxRing {
+    socket: Socket,
+    ring: *mut u8,
+    frame_size: usize,
+    frame_nr: usize,
+    // Next frame to fill
+    head: usize,
+}
+
+impl TxRing {
+    const PROTO: i32 = ETH_P_ALL.to_be();
+    const FRAME_SIZE: usize = 2048;
+    const BLOCK_SIZE: usize = 1 << 16;
+    const BLOCK_NR: usize = 64;
+
+    // Frame data follows the aligned tpacket2_hdr
+    const DATA_OFFSET: usize =
+        std::mem::size_of::<tpacket2_hdr>().next_multiple_of(TPACKET_ALIGNMENT);
+
+    pub fn new(interface_index: i32) -> std::io::Result<Self> {
+        let socket = Socket::new(AF_PACKET.into(), SOCK_RAW.into(), Some(Self::PROTO.into()))?;
+        let fd = socket.as_raw_fd();
+
+        let version = tpacket_versions::TPACKET_V2 as i32;
+        setsockopt(fd, SOL_PACKET, PACKET_VERSION, &version)?;
+        let req = tpacket_req {
+            tp_block_size: Self::BLOCK_SIZE as u32,
+            tp_block_nr: Self::BLOCK_NR as u32,
+            tp_frame_size: Self::FRAME_SIZE as u32,
+            tp_frame_nr: (Self::BLOCK_SIZE / Self::FRAME_SIZE * Self::BLOCK_NR) as u32,
+        };
+        setsockopt(fd, SOL_PACKET, PACKET_TX_RING, &req)?;
+
+        let size = Self::BLOCK_SIZE * Self::BLOCK_NR;
+        let ring = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ring == MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Frames are sent on the interface the socket is bound to
+        let sockaddr = sockaddr_ll {
+            sll_family: AF_PACKET as u16,
+            sll_protocol: (Self::PROTO as u16).to_be(),
+            sll_ifindex: interface_index,
+            sll_hatype: 0,
+            sll_pkttype: 0,
+            sll_halen: 0,
+            sll_addr: [0; 8],
+        };
+        let ring = Self {
+            socket,
+            ring: ring as *mut u8,
+            frame_size: Self::FRAME_SIZE,
+            frame_nr: req.tp_frame_nr as usize,
+            head: 0,
+        };
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &sockaddr as *const sockaddr_ll as *const sockaddr,
+                std::mem::size_of::<sockaddr_ll>() as u32,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(ring)
+    }
+
+    /// Largest probe that fits in a frame
+    pub fn max_packet_size(&self) -> usize {
+        self.frame_size - Self::DATA_OFFSET
+    }
+
+    fn header(&self, frame: usize) -> *mut tpacket2_hdr {
+        unsafe { self.ring.add(frame * self.frame_size) as *mut tpacket2_hdr }
+    }
+
+    fn status(&self, frame: usize) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(&raw mut (*self.header(frame)).tp_status) }
+    }
+
+    /// Returns the next frame to write a probe into, waiting for the kernel to send queued frames
+    /// if the ring is full
+    pub fn next_frame(&mut self) -> (&mut [u8], FrameWait) {
+        let mut wait = FrameWait::default();
+        loop {
+            match self.status(self.head).load(Ordering::Acquire) {
+                TP_STATUS_AVAILABLE => break,
+                TP_STATUS_WRONG_FORMAT => {
+                    wait.failed = true;
+                    self.status(self.head)
+                        .store(TP_STATUS_AVAILABLE, Ordering::Release);
+                    break;
+                }
+                _ => {
+                    wait.ring_full = true;
+                    // The frames may only be queued so far, so make sure the kernel sends them
+                    let _ = self.flush();
+                    std::thread::yield_now();
+                }
+            }
+        }
+
+        let data = unsafe {
+            std::slice::from_raw_parts_mut(
+                self.ring
+                    .add(self.head * self.frame_size + Self::DATA_OFFSET),
+                self.max_packet_size(),
+            )
+        };
+        (data, wait)
+    }
+
+    /// Queue the probe of `len` bytes written into the frame returned by next_frame
+    pub fn commit(&mut self, len: usize) {
+        assert!(
+            len <= self.max_packet_size(),
+            "Probe doesn't fit in a frame"
+        );
+        unsafe { (*self.header(self.head)).tp_len = len as u32 };
+        self.status(self.head)
+            .store(TP_STATUS_SEND_REQUEST, Ordering::Release);
+        self.head = (self.head + 1) % self.frame_nr;
+    }
+
+    /// Ask the kernel to send every queued frame
+    pub fn flush(&self) -> std::io::Result<()> {
+        let result = unsafe { libc::send(self.socket.as_raw_fd(), std::ptr::null(), 0, 0) };
+        if result < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for TxRing {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ring as *mut c_void, Self::BLOCK_SIZE * Self::BLOCK_NR) };
+    }
+}
@@ -0,0 +1,4 @@
+pub mod module_csv;
+pub mod module_json;
+pub mod module_plain;
+pub mod registry;
@@ -0,0 +1,74 @@
+use std::io::{self, Write};
+
+use crate::output_modules::registry::OutputModule;
+use crate::probe_modules::probe_modules::Field;
+
+// Quote values that would otherwise break the row apart (RFC 4180)
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Comma separated values, with a header row naming the selected fields
+pub struct Csv;
+
+impl OutputModule for Csv {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn description(&self) -> &'static str {
+        "Comma separated values with a header row"
+    }
+
+    fn start(&mut self, out: &mut dyn Write, fields: &[&'static str]) -> io::Result<()> {
+        writeln!(out, "{}", fields.join(","))
+    }
+
+    fn process_result(&mut self, out: &mut dyn Write, fields: &[Field]) -> io::Result<()> {
+        let values: Vec<String> = fields
+            .iter()
+            .map(|field| csv_escape(&field.value.to_string()))
+            .collect();
+        writeln!(out, "{}", values.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe_modules::probe_modules::FieldValue;
+
+    #[test]
+    fn test_csv_output() {
+        let mut out = Vec::new();
+        let mut csv = Csv;
+        csv.start(
+            &mut out,
+            &["saddr", "sport", "success", "dns_answers", "window"],
+        )
+        .unwrap();
+        csv.process_result(
+            &mut out,
+            &[
+                Field::new("saddr", FieldValue::Str("192.168.68.3".to_string())),
+                Field::new("sport", FieldValue::Int(443)),
+                Field::new("success", FieldValue::Bool(true)),
+                Field::new(
+                    "dns_answers",
+                    FieldValue::Str("TXT \"a,b\";A 1.2.3.4".to_string()),
+                ),
+                Field::new("window", FieldValue::Null),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "saddr,sport,success,dns_answers,window\n192.168.68.3,443,1,\"TXT \"\"a,b\"\";A 1.2.3.4\",\n"
+        );
+    }
+}
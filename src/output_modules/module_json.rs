@@ -0,0 +1,83 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::output_modules::registry::OutputModule;
+use crate::probe_modules::probe_modules::{Field, FieldValue};
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_value(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Int(value) => value.to_string(),
+        FieldValue::Bool(value) => value.to_string(),
+        FieldValue::Str(value) => json_string(value),
+        // Bytes are written as a hex string
+        FieldValue::Bytes(_) => json_string(&value.to_string()),
+        FieldValue::Null => String::from("null"),
+    }
+}
+
+/// JSON Lines, one object per result keyed by field name
+pub struct Json;
+
+impl OutputModule for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn description(&self) -> &'static str {
+        "JSON Lines, one object per result"
+    }
+
+    fn process_result(&mut self, out: &mut dyn Write, fields: &[Field]) -> io::Result<()> {
+        let members: Vec<String> = fields
+            .iter()
+            .map(|field| format!("{}:{}", json_string(field.name), json_value(&field.value)))
+            .collect();
+        writeln!(out, "{{{}}}", members.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_output() {
+        let mut out = Vec::new();
+        let mut json = Json;
+        json.process_result(
+            &mut out,
+            &[
+                Field::new("saddr", FieldValue::Str("2001:db8::3".to_string())),
+                Field::new("sport", FieldValue::Int(443)),
+                Field::new("repeat", FieldValue::Bool(false)),
+                Field::new("data", FieldValue::Bytes(vec![0xde, 0xad])),
+                Field::new("dns_answers", FieldValue::Str("a\"b\\\u{1}".to_string())),
+                Field::new("window", FieldValue::Null),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"saddr\":\"2001:db8::3\",\"sport\":443,\"repeat\":false,\"data\":\"dead\",\"dns_answers\":\"a\\\"b\\\\\\u0001\",\"window\":null}\n"
+        );
+    }
+}
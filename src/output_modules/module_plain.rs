@@ -0,0 +1,23 @@
+use std::io::{self, Write};
+
+use crate::output_modules::registry::OutputModule;
+use crate::probe_modules::probe_modules::Field;
+
+/// The selected values separated by commas, without a header or any quoting. With the default
+/// fields this is a plain list of responsive addresses.
+pub struct Plain;
+
+impl OutputModule for Plain {
+    fn name(&self) -> &'static str {
+        "plain"
+    }
+
+    fn description(&self) -> &'static str {
+        "Comma separated values without a header or quoting"
+    }
+
+    fn process_result(&mut self, out: &mut dyn Write, fields: &[Field]) -> io::Result<()> {
+        let values: Vec<String> = fields.iter().map(|field| field.value.to_string()).collect();
+        writeln!(out, "{}", values.join(","))
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+
+use crate::output_modules::module_csv::Csv;
+use crate::output_modules::module_json::Json;
+use crate::output_modules::module_plain::Plain;
+use crate::probe_modules::probe_modules::{Field, FieldValue, ProbeModule};
+
+/// Fields taken from the IP and transport headers of a reply, see header_fields
+pub const HEADER_FIELDS: &[&str] = &[
+    "saddr", "daddr", "sport", "dport", "seqnum", "acknum", "window", "ttl",
+];
+
+/// Fields filled in by the receiver for every reply
+pub const SYSTEM_FIELDS: &[&str] = &[
+    "classification",
+    "success",
+    "repeat",
+    "cooldown",
+    "timestamp",
+];
+
+/// An output module writes the selected fields of each result in a particular format. Values are
+/// always given in the order of --output-fields, with FieldValue::Null for fields a reply doesn't
+/// have (e.g. the sequence number of an ICMP reply).
+pub trait OutputModule: Send {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// Called once before any results are written, e.g. to write a header
+    fn start(&mut self, _out: &mut dyn Write, _fields: &[&'static str]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn process_result(&mut self, out: &mut dyn Write, fields: &[Field]) -> io::Result<()>;
+}
+
+/// All output modules known to zmap-rs
+pub fn output_modules() -> Vec<Box<dyn OutputModule>> {
+    vec![Box::new(Csv), Box::new(Json), Box::new(Plain)]
+}
+
+pub fn get_output_module(name: &str) -> Option<Box<dyn OutputModule>> {
+    output_modules().into_iter().find(|m| m.name() == name)
+}
+
+pub fn print_output_modules() {
+    for module in output_modules() {
+        println!("{:<16} {}", module.name(), module.description());
+    }
+}
+
+/// Every field that can be selected with --output-fields when scanning with the given module
+pub fn available_fields(probe_module: &dyn ProbeModule) -> Vec<&'static str> {
+    HEADER_FIELDS
+        .iter()
+        .chain(SYSTEM_FIELDS)
+        .chain(probe_module.fields())
+        .copied()
+        .collect()
+}
+
+pub fn print_output_fields(probe_module: &dyn ProbeModule) {
+    for field in available_fields(probe_module) {
+        println!("{}", field);
+    }
+}
+
+/// Check the fields requested with --output-fields against those available, keeping their order
+pub fn select_fields(
+    names: &[String],
+    probe_module: &dyn ProbeModule,
+) -> Result<Vec<&'static str>, String> {
+    let available = available_fields(probe_module);
+    names
+        .iter()
+        .map(|name| {
+            available
+                .iter()
+                .find(|field| *field == name)
+                .copied()
+                .ok_or_else(|| {
+                    format!(
+                        "unknown output field '{}' for probe module {} (see --list-output-fields)",
+                        name,
+                        probe_module.name()
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Extract the header fields of a reply. The source address is passed in as probe modules may
+/// attribute a reply to a different host than the one that sent it (e.g. ICMP errors).
+pub fn header_fields(packet: &[u8], src_ip: &IpAddr) -> Vec<Field> {
+    let mut fields = vec![Field::new("saddr", FieldValue::Str(src_ip.to_string()))];
+
+    let sliced_packet = match SlicedPacket::from_ethernet(packet) {
+        Ok(p) => p,
+        Err(_) => return fields,
+    };
+
+    match &sliced_packet.net {
+        Some(NetSlice::Ipv4(slice)) => {
+            let header = slice.header();
+            fields.push(Field::new(
+                "daddr",
+                FieldValue::Str(header.destination_addr().to_string()),
+            ));
+            fields.push(Field::new("ttl", FieldValue::Int(header.ttl() as u64)));
+        }
+        Some(NetSlice::Ipv6(slice)) => {
+            let header = slice.header();
+            fields.push(Field::new(
+                "daddr",
+                FieldValue::Str(header.destination_addr().to_string()),
+            ));
+            fields.push(Field::new(
+                "ttl",
+                FieldValue::Int(header.hop_limit() as u64),
+            ));
+        }
+        None => {}
+    }
+
+    match &sliced_packet.transport {
+        Some(TransportSlice::Tcp(tcp)) => {
+            fields.push(Field::new(
+                "sport",
+                FieldValue::Int(tcp.source_port() as u64),
+            ));
+            fields.push(Field::new(
+                "dport",
+                FieldValue::Int(tcp.destination_port() as u64),
+            ));
+            fields.push(Field::new(
+                "seqnum",
+                FieldValue::Int(tcp.sequence_number() as u64),
+            ));
+            fields.push(Field::new(
+                "acknum",
+                FieldValue::Int(tcp.acknowledgment_number() as u64),
+            ));
+            fields.push(Field::new(
+                "window",
+                FieldValue::Int(tcp.window_size() as u64),
+            ));
+        }
+        Some(TransportSlice::Udp(udp)) => {
+            fields.push(Field::new(
+                "sport",
+                FieldValue::Int(udp.source_port() as u64),
+            ));
+            fields.push(Field::new(
+                "dport",
+                FieldValue::Int(udp.destination_port() as u64),
+            ));
+        }
+        _ => {}
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe_modules::probe_modules::get_probe_module;
+
+    #[test]
+    fn test_select_fields() {
+        let probe_module = get_probe_module("dns").unwrap();
+        let names: Vec<String> = ["saddr", "dns_rcode", "success"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(
+            select_fields(&names, probe_module.as_ref()).unwrap(),
+            vec!["saddr", "dns_rcode", "success"]
+        );
+
+        // Module specific fields are only available with that module
+        let probe_module = get_probe_module("tcp_synscan").unwrap();
+        assert!(select_fields(&names, probe_module.as_ref()).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+pub mod module_dns;
+pub mod module_icmp_echoscan;
+pub mod module_ipv6_tcp_synscan;
+pub mod module_tcp_ackscan;
 pub mod module_tcp_synscan;
+pub mod module_udp;
 pub mod packet;
 pub mod probe_modules;
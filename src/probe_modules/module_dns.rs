@@ -0,0 +1,608 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use etherparse::{IpNumber, NetSlice, SlicedPacket, TransportSlice};
+use eui48::MacAddress;
+use log::{debug, warn};
+
+use crate::config::{Config, Context};
+use crate::net::bpf::Reply;
+use crate::probe_modules::module_udp::udp_validate_packet;
+use crate::probe_modules::packet::{
+    ip_checksum, make_eth_header, make_ip_header, make_udp_header, udp_checksum, ETH_HDR_SIZE,
+    IP_HDR_SIZE, MAX_PACKET_SIZE, UDP_HDR_SIZE,
+};
+use crate::probe_modules::probe_modules::{
+    expect_ipv4, get_src_port, Classification, Field, FieldValue, ProbeGenerator, ProbeModule,
+};
+
+pub const REPLIES: &[Reply] = &[Reply::Udp, Reply::Icmp { except: None }];
+
+const HEADERS_LEN: usize = ETH_HDR_SIZE + IP_HDR_SIZE + UDP_HDR_SIZE;
+const DNS_HDR_SIZE: usize = 12;
+const DEFAULT_PROBE_ARGS: &str = "A,www.google.com";
+
+// Flags in the second 16-bit word of the DNS header
+const DNS_QR: u16 = 1 << 15;
+const DNS_AA: u16 = 1 << 10;
+const DNS_TC: u16 = 1 << 9;
+const DNS_RD: u16 = 1 << 8;
+const DNS_RA: u16 = 1 << 7;
+
+// Resource record types we know how to name and decode
+const QTYPES: [(&str, u16); 9] = [
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", 28),
+    ("ANY", 255),
+];
+
+fn qtype_name(qtype: u16) -> String {
+    match QTYPES.iter().find(|(_, t)| *t == qtype) {
+        Some((name, _)) => name.to_string(),
+        None => format!("TYPE{}", qtype),
+    }
+}
+
+// The transaction ID carries the low half of the first validation word, and the source port is
+// derived from the second word as for every other UDP module
+fn dns_txid(validation: &[u32]) -> u16 {
+    (validation[0] & 0xFFFF) as u16
+}
+
+/// Encode a DNS question for `qname` with the given type and the IN class
+fn encode_question(qname: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut question = vec![];
+    for label in qname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid label '{}' in query name {}", label, qname));
+        }
+        question.push(label.len() as u8);
+        question.extend_from_slice(label.as_bytes());
+    }
+    question.push(0);
+
+    if question.len() > 255 {
+        return Err(format!("query name {} is too long", qname));
+    }
+
+    question.extend_from_slice(&qtype.to_be_bytes());
+    question.extend_from_slice(&1u16.to_be_bytes()); // IN
+    Ok(question)
+}
+
+/// Parsed DNS response header and answer section
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DnsResponse {
+    id: u16,
+    flags: u16,
+    answer_count: u16,
+    answers: Vec<String>,
+}
+
+impl DnsResponse {
+    fn rcode(&self) -> u16 {
+        self.flags & 0xF
+    }
+
+    fn flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+/// Bounds checked reader over a DNS message, following compression pointers for names
+struct DnsReader<'a> {
+    message: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> DnsReader<'a> {
+    fn new(message: &'a [u8]) -> Self {
+        Self { message, offset: 0 }
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        let value = *self.message.get(self.offset)?;
+        self.offset += 1;
+        Some(value)
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        let bytes = self.read_bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let bytes = self.read_bytes(4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.message.get(self.offset..self.offset + len)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn read_name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = vec![];
+        let mut offset = self.offset;
+        let mut jumped = false;
+
+        // Bound the number of labels so that pointer loops can't keep us here forever
+        for _ in 0..128 {
+            let len = *self.message.get(offset)? as usize;
+            match len & 0xC0 {
+                0x00 if len == 0 => {
+                    if !jumped {
+                        self.offset = offset + 1;
+                    }
+                    if labels.is_empty() {
+                        return Some(String::from("."));
+                    }
+                    return Some(labels.join("."));
+                }
+                0x00 => {
+                    let label = self.message.get(offset + 1..offset + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    offset += 1 + len;
+                }
+                0xC0 => {
+                    let pointer =
+                        (u16::from_be_bytes([self.message[offset], *self.message.get(offset + 1)?])
+                            & 0x3FFF) as usize;
+                    if !jumped {
+                        self.offset = offset + 2;
+                        jumped = true;
+                    }
+                    offset = pointer;
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn skip_question(&mut self) -> Option<()> {
+        self.read_name()?;
+        self.read_bytes(4)?;
+        Some(())
+    }
+
+    // Records are rendered as "<name> <type> <data>"
+    fn read_record(&mut self) -> Option<String> {
+        let name = self.read_name()?;
+        let rtype = self.read_u16()?;
+        let _class = self.read_u16()?;
+        let _ttl = self.read_u32()?;
+        let rdlength = self.read_u16()? as usize;
+        let rdata_start = self.offset;
+        let rdata = self.read_bytes(rdlength)?;
+
+        let data = match rtype {
+            1 if rdlength == 4 => Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string(),
+            28 if rdlength == 16 => {
+                Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap()).to_string()
+            }
+            2 | 5 | 12 => {
+                let mut reader = DnsReader {
+                    message: self.message,
+                    offset: rdata_start,
+                };
+                reader.read_name()?
+            }
+            15 if rdlength > 2 => {
+                let mut reader = DnsReader {
+                    message: self.message,
+                    offset: rdata_start,
+                };
+                let preference = reader.read_u16()?;
+                format!("{} {}", preference, reader.read_name()?)
+            }
+            16 => {
+                let mut strings = vec![];
+                let mut reader = DnsReader::new(rdata);
+                while let Some(len) = reader.read_u8() {
+                    let string = reader.read_bytes(len as usize)?;
+                    strings.push(format!("\"{}\"", String::from_utf8_lossy(string)));
+                }
+                strings.join(" ")
+            }
+            _ => FieldValue::Bytes(rdata.to_vec()).to_string(),
+        };
+
+        Some(format!("{} {} {}", name, qtype_name(rtype), data))
+    }
+}
+
+fn parse_response(message: &[u8]) -> Option<DnsResponse> {
+    let mut reader = DnsReader::new(message);
+    let id = reader.read_u16()?;
+    let flags = reader.read_u16()?;
+    let question_count = reader.read_u16()?;
+    let answer_count = reader.read_u16()?;
+    let _authority_count = reader.read_u16()?;
+    let _additional_count = reader.read_u16()?;
+
+    for _ in 0..question_count {
+        reader.skip_question()?;
+    }
+
+    // Keep whatever answers we could decode if the message turns out to be truncated
+    let mut answers = vec![];
+    for _ in 0..answer_count {
+        match reader.read_record() {
+            Some(answer) => answers.push(answer),
+            None => break,
+        }
+    }
+
+    Some(DnsResponse {
+        id,
+        flags,
+        answer_count,
+        answers,
+    })
+}
+
+fn dns_payload(packet: &[u8]) -> Option<&[u8]> {
+    match SlicedPacket::from_ethernet(packet).ok()?.transport {
+        Some(TransportSlice::Udp(udp)) => Some(udp.payload()),
+        _ => None,
+    }
+}
+
+/// Precomputed DNS query generator. The query is identical for all targets, so only the IP
+/// destination address, UDP source port, DNS transaction ID and checksums change between probes.
+pub struct DnsProbeGenerator {
+    query: Vec<u8>,
+    source_ip: Ipv4Addr,
+    source_port_first: u16,
+    source_port_last: u16,
+    buffer: Vec<u8>,
+}
+
+impl DnsProbeGenerator {
+    fn new(query: Vec<u8>) -> Self {
+        DnsProbeGenerator {
+            query,
+            source_ip: Ipv4Addr::new(0, 0, 0, 0),
+            source_port_first: 0,
+            source_port_last: 0,
+            buffer: Vec::with_capacity(MAX_PACKET_SIZE),
+        }
+    }
+}
+
+impl ProbeGenerator for DnsProbeGenerator {
+    fn thread_initialize(
+        &mut self,
+        source_mac: &MacAddress,
+        gateway_mac: &MacAddress,
+        source_ip: &IpAddr,
+        source_port_first: u16,
+        source_port_last: u16,
+    ) {
+        let source_ip = expect_ipv4(source_ip);
+        self.source_ip = source_ip;
+        self.source_port_first = source_port_first;
+        self.source_port_last = source_port_last;
+
+        make_eth_header(source_mac, gateway_mac)
+            .write(&mut self.buffer)
+            .unwrap();
+
+        let mut ip_header = make_ip_header(IpNumber::UDP);
+        ip_header.source = source_ip.octets();
+        ip_header.total_len = (IP_HDR_SIZE + UDP_HDR_SIZE + self.query.len()) as u16;
+        ip_header.write_raw(&mut self.buffer).unwrap();
+
+        // The destination port is set for each probe
+        let mut udp_header = make_udp_header(0);
+        udp_header.length = (UDP_HDR_SIZE + self.query.len()) as u16;
+        udp_header.write(&mut self.buffer).unwrap();
+
+        self.buffer.extend_from_slice(&self.query);
+    }
+
+    fn make_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+    ) -> &[u8] {
+        let destination_ip = expect_ipv4(destination_ip);
+
+        // Set the destination IP address
+        self.buffer[30..34].copy_from_slice(&destination_ip.octets());
+
+        // Calculate and set source port, and set the destination port
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
+        self.buffer[34..36].copy_from_slice(&src_port.to_be_bytes());
+        self.buffer[36..38].copy_from_slice(&destination_port.to_be_bytes());
+
+        // Set the transaction ID
+        self.buffer[HEADERS_LEN..HEADERS_LEN + 2]
+            .copy_from_slice(&dns_txid(validation).to_be_bytes());
+
+        // Calculate and set IP header checksum
+        self.buffer[24..26].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let ip_header_checksum = ip_checksum(&self.buffer[14..34]);
+        self.buffer[24..26].copy_from_slice(&ip_header_checksum.to_be_bytes());
+
+        // Calculate and set UDP checksum
+        self.buffer[40..42].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let udp_checksum = udp_checksum(
+            &self.buffer[34..],
+            self.source_ip.into(),
+            destination_ip.into(),
+        );
+        self.buffer[40..42].copy_from_slice(&udp_checksum.to_be_bytes());
+        &self.buffer
+    }
+}
+
+/// Probe module that sends a DNS query (--probe-args=<qtype>,<name>[,norecurse]) and parses the
+/// responses. Any DNS response is a success, the rcode tells whether the query was answered.
+pub struct Dns {
+    query: Vec<u8>,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Dns {
+            query: Self::build_query(DEFAULT_PROBE_ARGS).unwrap(),
+        }
+    }
+}
+
+impl Dns {
+    /// Build the DNS message (with a zero transaction ID) described by the probe args
+    fn build_query(probe_args: &str) -> Result<Vec<u8>, String> {
+        let mut args = probe_args.split(',');
+        let qtype = args.next().unwrap_or_default().to_uppercase();
+        let qname = args
+            .next()
+            .ok_or("probe args must be of the form <qtype>,<name>[,norecurse]")?;
+        let recurse = match args.next() {
+            None => true,
+            Some("norecurse") => false,
+            Some(arg) => return Err(format!("unknown DNS option '{}'", arg)),
+        };
+
+        let qtype = match QTYPES.iter().find(|(name, _)| *name == qtype) {
+            Some((_, qtype)) => *qtype,
+            None => return Err(format!("unsupported query type '{}'", qtype)),
+        };
+
+        let flags = if recurse { DNS_RD } else { 0 };
+        let mut query = vec![0, 0];
+        query.extend_from_slice(&flags.to_be_bytes());
+        query.extend_from_slice(&1u16.to_be_bytes()); // One question
+        query.extend_from_slice(&[0; 6]); // No answer, authority or additional records
+        query.extend_from_slice(&encode_question(qname, qtype)?);
+        Ok(query)
+    }
+}
+
+impl ProbeModule for Dns {
+    fn name(&self) -> &'static str {
+        "dns"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send a DNS query (--probe-args=<qtype>,<name>[,norecurse]), DNS responses are successes"
+    }
+
+    fn global_initialize(&mut self, config: &Config) -> Result<(), String> {
+        if config.target_ports() != [53] {
+            warn!(
+                "The dns module is sending queries to ports {:?} rather than 53",
+                config.target_ports()
+            );
+        }
+
+        let probe_args = config.probe_args.as_deref().unwrap_or(DEFAULT_PROBE_ARGS);
+        self.query = Self::build_query(probe_args)?;
+        Ok(())
+    }
+
+    fn replies(&self) -> &'static [Reply] {
+        REPLIES
+    }
+
+    fn packet_length(&self) -> u64 {
+        (HEADERS_LEN + self.query.len()) as u64
+    }
+
+    fn make_generator(&self, _config: &Config) -> Box<dyn ProbeGenerator> {
+        Box::new(DnsProbeGenerator::new(self.query.clone()))
+    }
+
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool {
+        if !udp_validate_packet(packet, src_ip, validation, ctx) {
+            return false;
+        }
+
+        // ICMP errors have already been checked against the quoted probe
+        let payload = match dns_payload(packet) {
+            Some(payload) => payload,
+            None => return true,
+        };
+
+        if payload.len() < DNS_HDR_SIZE {
+            return false;
+        }
+
+        let id = u16::from_be_bytes([payload[0], payload[1]]);
+        let flags = u16::from_be_bytes([payload[2], payload[3]]);
+        id == dns_txid(validation) && flags & DNS_QR != 0
+    }
+
+    fn classify_packet(&self, packet: &[u8]) -> Classification {
+        match dns_payload(packet).map(parse_response) {
+            Some(Some(_)) => Classification::success("dns"),
+            Some(None) => Classification::failure("dns-malformed"),
+            None => Classification::failure("icmp-unreach"),
+        }
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        &[
+            "dns_rcode",
+            "dns_aa",
+            "dns_tc",
+            "dns_ra",
+            "dns_ancount",
+            "dns_answers",
+        ]
+    }
+
+    fn packet_fields(&self, packet: &[u8]) -> Vec<Field> {
+        let response = match dns_payload(packet).and_then(parse_response) {
+            Some(response) => response,
+            None => return vec![],
+        };
+
+        vec![
+            Field::new("dns_rcode", FieldValue::Int(response.rcode() as u64)),
+            Field::new("dns_aa", FieldValue::Bool(response.flag(DNS_AA))),
+            Field::new("dns_tc", FieldValue::Bool(response.flag(DNS_TC))),
+            Field::new("dns_ra", FieldValue::Bool(response.flag(DNS_RA))),
+            Field::new("dns_ancount", FieldValue::Int(response.answer_count as u64)),
+            Field::new("dns_answers", FieldValue::Str(response.answers.join(";"))),
+        ]
+    }
+
+    fn print_packet(&self, packet: &[u8]) {
+        let sliced_packet = match SlicedPacket::from_ethernet(packet) {
+            Ok(p) => p,
+            Err(_) => {
+                debug!("Could not parse Ethernet packet");
+                return;
+            }
+        };
+
+        let ip_header = match &sliced_packet.net {
+            Some(NetSlice::Ipv4(slice)) => slice.header(),
+            _ => {
+                debug!("Could not unpack network slice");
+                return;
+            }
+        };
+
+        let udp = match &sliced_packet.transport {
+            Some(TransportSlice::Udp(udp)) => udp,
+            _ => {
+                debug!("Could not unpack transport slice");
+                return;
+            }
+        };
+
+        println!(
+            "ip {{ saddr: {} | daddr: {} | checksum: {} }}",
+            ip_header.source_addr(),
+            ip_header.destination_addr(),
+            ip_header.header_checksum()
+        );
+
+        println!(
+            "udp {{ sport: {} | dport: {} | length: {} | checksum: {} }}",
+            udp.source_port(),
+            udp.destination_port(),
+            udp.length(),
+            udp.checksum(),
+        );
+
+        let mut reader = DnsReader::new(udp.payload());
+        let id = reader.read_u16().unwrap_or_default();
+        reader.read_bytes(DNS_HDR_SIZE - 2);
+        let qname = reader.read_name().unwrap_or_default();
+        let qtype = reader.read_u16().unwrap_or_default();
+        println!(
+            "dns {{ id: {} | qname: {} | qtype: {} }}",
+            id,
+            qname,
+            qtype_name(qtype)
+        );
+
+        println!("------------------------------------------------------");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_query() {
+        let query = Dns::build_query("a,example.com").unwrap();
+        assert_eq!(
+            &query[..DNS_HDR_SIZE],
+            &[0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            &query[DNS_HDR_SIZE..],
+            b"\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+
+        let query = Dns::build_query("MX,example.com.,norecurse").unwrap();
+        assert_eq!(&query[2..4], &[0, 0]);
+        assert_eq!(&query[query.len() - 4..], &[0, 15, 0, 1]);
+
+        assert!(Dns::build_query("BOGUS,example.com").is_err());
+        assert!(Dns::build_query("A").is_err());
+        assert!(Dns::build_query("A,example..com").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut response = Dns::build_query("A,example.com").unwrap();
+        response[0..2].copy_from_slice(&0x1234u16.to_be_bytes());
+        response[2..4].copy_from_slice(&(DNS_QR | DNS_RD | DNS_RA | 3).to_be_bytes());
+        response[6..8].copy_from_slice(&2u16.to_be_bytes());
+
+        // A record, with the name compressed as a pointer to the question
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+
+        // CNAME record pointing to www.example.com, again using compression
+        response.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        response.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, 12]);
+
+        let parsed = parse_response(&response).unwrap();
+        assert_eq!(parsed.id, 0x1234);
+        assert_eq!(parsed.rcode(), 3);
+        assert!(parsed.flag(DNS_RA));
+        assert!(!parsed.flag(DNS_AA));
+        assert!(!parsed.flag(DNS_TC));
+        assert_eq!(parsed.answer_count, 2);
+        assert_eq!(
+            parsed.answers,
+            vec![
+                "example.com A 93.184.216.34",
+                "example.com CNAME www.example.com",
+            ]
+        );
+
+        // Pointer loops must not hang the parser
+        let mut looped = response[..DNS_HDR_SIZE].to_vec();
+        looped[4..6].copy_from_slice(&1u16.to_be_bytes());
+        looped.extend_from_slice(&[0xC0, 12]);
+        assert!(parse_response(&looped).is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use etherparse::{
+    IcmpEchoHeader, Icmpv4Header, Icmpv4Slice, Icmpv4Type, IpNumber, Ipv4HeaderSlice, NetSlice,
+    SlicedPacket, TransportSlice,
+};
+use eui48::MacAddress;
+use log::debug;
+
+use crate::config::{Config, Context};
+use crate::lib::validate;
+use crate::net::bpf::Reply;
+use crate::probe_modules::packet::{
+    ip_checksum, make_eth_header, make_ip_header, ICMP_HDR_SIZE, IP_HDR_SIZE, MAX_PACKET_SIZE,
+};
+use crate::probe_modules::probe_modules::{
+    expect_ipv4, Classification, ProbeGenerator, ProbeModule,
+};
+
+// Ethernet + IP + ICMP echo header + 8 bytes of validation in the payload
+pub const PACKET_LENGTH: u64 = 50;
+// Everything but echo requests, which would be our own probes
+pub const REPLIES: &[Reply] = &[Reply::Icmp { except: Some(8) }];
+
+const ICMP_PAYLOAD_SIZE: usize = 8;
+
+// ICMP types that quote the header of the probe that triggered them
+const ICMP_UNREACH: u8 = 3;
+const ICMP_SOURCEQUENCH: u8 = 4;
+const ICMP_REDIRECT: u8 = 5;
+const ICMP_TIMXCEED: u8 = 11;
+const ICMP_PARAMPROB: u8 = 12;
+
+// The echo identifier and sequence number together carry the first validation word, and the
+// payload carries both words in case the identifier/sequence are rewritten along the way.
+fn echo_fields(validation: &[u32]) -> (u16, u16) {
+    (
+        (validation[0] >> 16) as u16,
+        (validation[0] & 0xFFFF) as u16,
+    )
+}
+
+fn echo_payload(validation: &[u32]) -> [u8; ICMP_PAYLOAD_SIZE] {
+    let mut payload = [0u8; ICMP_PAYLOAD_SIZE];
+    payload[0..4].copy_from_slice(&validation[0].to_be_bytes());
+    payload[4..8].copy_from_slice(&validation[1].to_be_bytes());
+    payload
+}
+
+/// Precomputed ICMP echo request generator. Only the IP destination address and checksum, the ICMP
+/// identifier, sequence number, payload and checksum change between probes.
+pub struct IcmpEchoProbeGenerator {
+    buffer: Vec<u8>,
+}
+
+impl Default for IcmpEchoProbeGenerator {
+    fn default() -> Self {
+        IcmpEchoProbeGenerator {
+            buffer: Vec::with_capacity(MAX_PACKET_SIZE),
+        }
+    }
+}
+
+impl ProbeGenerator for IcmpEchoProbeGenerator {
+    fn thread_initialize(
+        &mut self,
+        source_mac: &MacAddress,
+        gateway_mac: &MacAddress,
+        source_ip: &IpAddr,
+        _source_port_first: u16,
+        _source_port_last: u16,
+    ) {
+        let source_ip = expect_ipv4(source_ip);
+        make_eth_header(source_mac, gateway_mac)
+            .write(&mut self.buffer)
+            .unwrap();
+
+        let mut ip_header = make_ip_header(IpNumber::ICMP);
+        ip_header.source = source_ip.octets();
+        ip_header.total_len = (IP_HDR_SIZE + ICMP_HDR_SIZE + ICMP_PAYLOAD_SIZE) as u16;
+        ip_header.write_raw(&mut self.buffer).unwrap();
+
+        let icmp_header =
+            Icmpv4Header::new(Icmpv4Type::EchoRequest(IcmpEchoHeader { id: 0, seq: 0 }));
+        icmp_header.write(&mut self.buffer).unwrap();
+        self.buffer.extend_from_slice(&[0u8; ICMP_PAYLOAD_SIZE]);
+    }
+
+    fn make_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        _destination_port: u16,
+        validation: &[u32],
+        _probe_num: u32,
+    ) -> &[u8] {
+        let destination_ip = expect_ipv4(destination_ip);
+
+        // Set the destination IP address
+        self.buffer[30..34].copy_from_slice(&destination_ip.octets());
+
+        // Set the identifier, sequence number and payload
+        let (id, seq) = echo_fields(validation);
+        self.buffer[38..40].copy_from_slice(&id.to_be_bytes());
+        self.buffer[40..42].copy_from_slice(&seq.to_be_bytes());
+        self.buffer[42..50].copy_from_slice(&echo_payload(validation));
+
+        // Calculate and set IP header checksum
+        self.buffer[24..26].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let ip_header_checksum = ip_checksum(&self.buffer[14..34]);
+        self.buffer[24..26].copy_from_slice(&ip_header_checksum.to_be_bytes());
+
+        // The ICMP checksum uses the same one's complement sum, but without a pseudo header
+        self.buffer[36..38].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let icmp_checksum = ip_checksum(&self.buffer[34..]);
+        self.buffer[36..38].copy_from_slice(&icmp_checksum.to_be_bytes());
+        &self.buffer
+    }
+}
+
+fn icmp_slice<'a>(packet: &SlicedPacket<'a>) -> Option<Icmpv4Slice<'a>> {
+    match &packet.transport {
+        Some(TransportSlice::Icmpv4(slice)) => Some(slice.clone()),
+        _ => {
+            debug!("Could not unpack transport slice");
+            None
+        }
+    }
+}
+
+/// Probe module that sends ICMP echo requests, echo replies are successes
+pub struct IcmpEchoscan;
+
+impl IcmpEchoscan {
+    fn validate_echo_reply(icmp: &Icmpv4Slice, validation: &[u32]) -> bool {
+        let (id, seq) = echo_fields(validation);
+        let echo = match icmp.icmp_type() {
+            Icmpv4Type::EchoReply(echo) => echo,
+            _ => return false,
+        };
+
+        if echo.id != id || echo.seq != seq {
+            return false;
+        }
+
+        // Hosts are supposed to echo our payload back, but don't penalize those that truncate it
+        let payload = icmp.payload();
+        if payload.len() >= ICMP_PAYLOAD_SIZE
+            && payload[..ICMP_PAYLOAD_SIZE] != echo_payload(validation)
+        {
+            return false;
+        }
+
+        true
+    }
+
+    // ICMP errors are sent by whichever host dropped the probe, so the outer source address is not
+    // the one we validated against. Recover the original target from the quoted IP header instead.
+    fn validate_icmp_error(
+        icmp: &Icmpv4Slice,
+        local_ip: Ipv4Addr,
+        src_ip: &mut IpAddr,
+        ctx: &Context,
+    ) -> bool {
+        let inner_ip = match Ipv4HeaderSlice::from_slice(icmp.payload()) {
+            Ok(header) => header,
+            Err(_) => return false,
+        };
+
+        if inner_ip.protocol() != IpNumber::ICMP || inner_ip.source_addr() != local_ip {
+            return false;
+        }
+
+        let inner_icmp = &icmp.payload()[inner_ip.slice().len()..];
+        if inner_icmp.len() < ICMP_HDR_SIZE || inner_icmp[0] != 8 {
+            return false;
+        }
+
+        let target = inner_ip.destination_addr();
+        let Some(validation) =
+            validate::gen_words(&ctx.validate_ctx, &local_ip.into(), &target.into())
+        else {
+            return false;
+        };
+
+        let (id, seq) = echo_fields(&validation);
+        if u16::from_be_bytes([inner_icmp[4], inner_icmp[5]]) != id
+            || u16::from_be_bytes([inner_icmp[6], inner_icmp[7]]) != seq
+        {
+            return false;
+        }
+
+        *src_ip = target.into();
+        true
+    }
+}
+
+impl ProbeModule for IcmpEchoscan {
+    fn name(&self) -> &'static str {
+        "icmp_echoscan"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send ICMP echo requests, echo replies are successes"
+    }
+
+    fn replies(&self) -> &'static [Reply] {
+        REPLIES
+    }
+
+    fn packet_length(&self) -> u64 {
+        PACKET_LENGTH
+    }
+
+    fn make_generator(&self, _config: &Config) -> Box<dyn ProbeGenerator> {
+        Box::new(IcmpEchoProbeGenerator::default())
+    }
+
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool {
+        let packet_slice = match SlicedPacket::from_ethernet(packet) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        let ip_header = match &packet_slice.net {
+            Some(NetSlice::Ipv4(slice)) => slice.header(),
+            _ => {
+                debug!("Could not unpack network slice");
+                return false;
+            }
+        };
+
+        let icmp = match icmp_slice(&packet_slice) {
+            Some(icmp) => icmp,
+            None => return false,
+        };
+
+        match icmp.type_u8() {
+            ICMP_UNREACH | ICMP_SOURCEQUENCH | ICMP_REDIRECT | ICMP_TIMXCEED | ICMP_PARAMPROB => {
+                Self::validate_icmp_error(&icmp, ip_header.destination_addr(), src_ip, ctx)
+            }
+            _ => Self::validate_echo_reply(&icmp, validation),
+        }
+    }
+
+    fn classify_packet(&self, packet: &[u8]) -> Classification {
+        let icmp = match SlicedPacket::from_ethernet(packet)
+            .ok()
+            .as_ref()
+            .and_then(icmp_slice)
+        {
+            Some(icmp) => icmp,
+            None => return Classification::failure("other"),
+        };
+
+        match icmp.type_u8() {
+            0 => Classification::success("echoreply"),
+            ICMP_UNREACH => Classification::failure("unreach"),
+            ICMP_SOURCEQUENCH => Classification::failure("sourcequench"),
+            ICMP_REDIRECT => Classification::failure("redirect"),
+            ICMP_TIMXCEED => Classification::failure("timxceed"),
+            ICMP_PARAMPROB => Classification::failure("paramprob"),
+            _ => Classification::failure("other"),
+        }
+    }
+
+    fn print_packet(&self, packet: &[u8]) {
+        let sliced_packet = match SlicedPacket::from_ethernet(packet) {
+            Ok(p) => p,
+            Err(_) => {
+                debug!("Could not parse Ethernet packet");
+                return;
+            }
+        };
+
+        let ip_header = match &sliced_packet.net {
+            Some(NetSlice::Ipv4(slice)) => slice.header(),
+            _ => {
+                debug!("Could not unpack network slice");
+                return;
+            }
+        };
+
+        let icmp = match icmp_slice(&sliced_packet) {
+            Some(icmp) => icmp,
+            None => return,
+        };
+
+        println!(
+            "ip {{ saddr: {} | daddr: {} | checksum: {} }}",
+            ip_header.source_addr(),
+            ip_header.destination_addr(),
+            ip_header.header_checksum()
+        );
+
+        match icmp.icmp_type() {
+            Icmpv4Type::EchoRequest(echo) => println!(
+                "icmp {{ type: 8 | id: {} | seq: {} | checksum: {} }}",
+                echo.id,
+                echo.seq,
+                icmp.checksum()
+            ),
+            _ => println!(
+                "icmp {{ type: {} | code: {} | checksum: {} }}",
+                icmp.type_u8(),
+                icmp.code_u8(),
+                icmp.checksum()
+            ),
+        }
+
+        println!("------------------------------------------------------");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icmp_echo_probe() {
+        let source_ip = Ipv4Addr::new(192, 168, 68, 3);
+        let destination_ip = Ipv4Addr::new(46, 216, 152, 50);
+        let validation = [0xdeadbeef, 0x01234567];
+
+        let mut generator = IcmpEchoProbeGenerator::default();
+        generator.thread_initialize(
+            &MacAddress::default(),
+            &MacAddress::default(),
+            &source_ip.into(),
+            0,
+            0,
+        );
+        let packet = generator.make_packet(&destination_ip.into(), 0, &validation, 0);
+        assert_eq!(packet.len() as u64, PACKET_LENGTH);
+
+        let sliced_packet = SlicedPacket::from_ethernet(packet).unwrap();
+        let icmp = icmp_slice(&sliced_packet).unwrap();
+        assert_eq!(
+            icmp.icmp_type(),
+            Icmpv4Type::EchoRequest(IcmpEchoHeader {
+                id: 0xdead,
+                seq: 0xbeef
+            })
+        );
+        assert_eq!(icmp.payload(), &echo_payload(&validation));
+
+        let expected = icmp.icmp_type().calc_checksum(icmp.payload());
+        assert_eq!(icmp.checksum(), expected);
+    }
+}
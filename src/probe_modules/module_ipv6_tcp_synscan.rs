@@ -0,0 +1,241 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use etherparse::{EtherType, IpNumber};
+use eui48::MacAddress;
+use log::debug;
+
+use crate::config::{Config, Context};
+use crate::net::bpf::Reply;
+use crate::probe_modules::module_tcp_synscan::{
+    self, synscan_classify_packet, synscan_print_packet, synscan_validate_packet,
+};
+use crate::probe_modules::packet::{
+    make_eth_header, make_ipv6_header, make_syn_header, tcp6_checksum, TcpOptionsLayout,
+    ETH_HDR_SIZE, IPV6_HDR_SIZE, MAX_PACKET_SIZE,
+};
+use crate::probe_modules::probe_modules::{
+    get_src_port, Classification, ProbeGenerator, ProbeModule,
+};
+
+// Length of a SYN without any TCP options
+pub const PACKET_LENGTH: u64 = 74;
+
+// Same replies as module_tcp_synscan
+pub const REPLIES: &[Reply] = module_tcp_synscan::REPLIES;
+
+const TCP_OFFSET: usize = ETH_HDR_SIZE + IPV6_HDR_SIZE;
+
+fn expect_ipv6(ip: &IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V6(ip) => *ip,
+        IpAddr::V4(ip) => panic!("IPv6 probe module given IPv4 address {}", ip),
+    }
+}
+
+/// Precomputed probe generator for SYNs over IPv6. There is no IPv6 header checksum, so only the
+/// destination address, and the TCP ports, sequence number, timestamp (if any) and checksum change
+/// between probes.
+pub struct Ipv6SynProbeGenerator {
+    source_ip: Ipv6Addr,
+    source_port_first: u16,
+    source_port_last: u16,
+    options: TcpOptionsLayout,
+    buffer: Vec<u8>,
+}
+
+impl Default for Ipv6SynProbeGenerator {
+    fn default() -> Self {
+        Ipv6SynProbeGenerator {
+            source_ip: Ipv6Addr::UNSPECIFIED,
+            source_port_first: 0,
+            source_port_last: 0,
+            options: TcpOptionsLayout::default(),
+            buffer: Vec::with_capacity(MAX_PACKET_SIZE),
+        }
+    }
+}
+
+impl Ipv6SynProbeGenerator {
+    pub fn with_options(mut self, options: TcpOptionsLayout) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl ProbeGenerator for Ipv6SynProbeGenerator {
+    fn thread_initialize(
+        &mut self,
+        source_mac: &MacAddress,
+        gateway_mac: &MacAddress,
+        source_ip: &IpAddr,
+        source_port_first: u16,
+        source_port_last: u16,
+    ) {
+        self.source_ip = expect_ipv6(source_ip);
+        self.source_port_first = source_port_first;
+        self.source_port_last = source_port_last;
+
+        let mut eth_header = make_eth_header(source_mac, gateway_mac);
+        eth_header.ether_type = EtherType::IPV6;
+        eth_header.write(&mut self.buffer).unwrap();
+
+        // The destination port is set for each probe
+        let tcp_header = make_syn_header(0, self.options, 0);
+
+        let mut ip_header = make_ipv6_header(IpNumber::TCP);
+        ip_header.source = self.source_ip.octets();
+        ip_header.payload_length = tcp_header.header_len_u16();
+        ip_header.write(&mut self.buffer).unwrap();
+
+        tcp_header.write(&mut self.buffer).unwrap();
+    }
+
+    fn make_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+    ) -> &[u8] {
+        let destination_ip = expect_ipv6(destination_ip);
+
+        // Set the destination IP address
+        self.buffer[38..54].copy_from_slice(&destination_ip.octets());
+
+        // Calculate and set source port, and set the destination port
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
+        self.buffer[54..56].copy_from_slice(&src_port.to_be_bytes());
+        self.buffer[56..58].copy_from_slice(&destination_port.to_be_bytes());
+
+        // Set the sequence number
+        self.buffer[58..62].copy_from_slice(&validation[0].to_be_bytes());
+
+        // Set the timestamp value, which follows the fixed 20 byte TCP header
+        if let Some(offset) = self.options.timestamp_offset() {
+            let offset = 74 + offset;
+            self.buffer[offset..offset + 4].copy_from_slice(&validation[1].to_be_bytes());
+        }
+
+        // Calculate and set TCP checksum over the header including options
+        self.buffer[70..72].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let tcp_checksum =
+            tcp6_checksum(&self.buffer[TCP_OFFSET..], &self.source_ip, &destination_ip);
+        self.buffer[70..72].copy_from_slice(&tcp_checksum.to_be_bytes());
+        &self.buffer
+    }
+}
+
+/// Probe module that sends TCP SYN packets to IPv6 targets read from --ipv6-target-file. Replies
+/// are validated and classified exactly like tcp_synscan, including the --probe-args options
+/// layouts.
+#[derive(Default)]
+pub struct Ipv6TcpSynscan {
+    options: TcpOptionsLayout,
+}
+
+impl ProbeModule for Ipv6TcpSynscan {
+    fn name(&self) -> &'static str {
+        "ipv6_tcp_synscan"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send TCP SYN packets to a single port on IPv6 targets, SYN-ACK replies are successes"
+    }
+
+    fn ipv6(&self) -> bool {
+        true
+    }
+
+    fn global_initialize(&mut self, config: &Config) -> Result<(), String> {
+        if let Some(probe_args) = &config.probe_args {
+            self.options = TcpOptionsLayout::parse(probe_args)?;
+        }
+        debug!("Using TCP options layout {:?}", self.options);
+        Ok(())
+    }
+
+    fn replies(&self) -> &'static [Reply] {
+        REPLIES
+    }
+
+    fn packet_length(&self) -> u64 {
+        PACKET_LENGTH + self.options.options_len() as u64
+    }
+
+    fn make_generator(&self, _config: &Config) -> Box<dyn ProbeGenerator> {
+        Box::new(Ipv6SynProbeGenerator::default().with_options(self.options))
+    }
+
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        _src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool {
+        synscan_validate_packet(packet, validation, &ctx.config)
+    }
+
+    fn classify_packet(&self, packet: &[u8]) -> Classification {
+        if synscan_classify_packet(packet) {
+            Classification::success("synack")
+        } else {
+            Classification::failure("rst")
+        }
+    }
+
+    fn print_packet(&self, packet: &[u8]) {
+        synscan_print_packet(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::{NetSlice, PacketBuilder, SlicedPacket, TransportSlice};
+    use libc::MAXTTL;
+
+    use super::*;
+
+    // The precomputed packet must match one serialized from scratch by etherparse
+    #[test]
+    fn test_ipv6_syn_probe() {
+        let source_mac = MacAddress::new([0xaa, 0x41, 0x72, 0x51, 0x54, 0x42]);
+        let gateway_mac = MacAddress::new([0xf6, 0xd4, 0x88, 0x07, 0x37, 0x64]);
+        let source_ip: Ipv6Addr = "2001:db8::3".parse().unwrap();
+        let destination_ip: Ipv6Addr = "2001:db8:1::32".parse().unwrap();
+        let validation = [2324566490, 0x01234567];
+
+        for layout in ["bare", "linux", "bsd"] {
+            let layout = TcpOptionsLayout::parse(layout).unwrap();
+            let mut generator = Ipv6SynProbeGenerator::default().with_options(layout);
+            generator.thread_initialize(&source_mac, &gateway_mac, &source_ip.into(), 32768, 61000);
+            let packet = generator.make_packet(&destination_ip.into(), 443, &validation, 0);
+            assert_eq!(
+                packet.len() as u64,
+                PACKET_LENGTH + layout.options_len() as u64
+            );
+
+            let mut tcp_header = make_syn_header(443, layout, validation[1]);
+            tcp_header.source_port = get_src_port(32768, 61000, &validation, 0);
+            tcp_header.sequence_number = validation[0];
+            let builder = PacketBuilder::ethernet2(source_mac.to_array(), gateway_mac.to_array())
+                .ipv6(source_ip.octets(), destination_ip.octets(), MAXTTL)
+                .tcp_header(tcp_header);
+            let mut expected = Vec::with_capacity(builder.size(0));
+            builder.write(&mut expected, &[]).unwrap();
+            assert_eq!(packet, expected.as_slice(), "{:?}", layout);
+
+            let sliced_packet = SlicedPacket::from_ethernet(packet).unwrap();
+            assert!(matches!(sliced_packet.net, Some(NetSlice::Ipv6(_))));
+            assert!(matches!(
+                sliced_packet.transport,
+                Some(TransportSlice::Tcp(tcp)) if tcp.syn()
+            ));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use etherparse::{IpNumber, NetSlice, SlicedPacket, TcpHeaderSlice, TransportSlice};
+use eui48::MacAddress;
+use log::debug;
+
+use crate::config::{Config, Context};
+use crate::net::bpf::{Reply, TcpFlags};
+use crate::probe_modules::module_tcp_synscan::synscan_print_packet;
+use crate::probe_modules::packet::{
+    ip_checksum, make_eth_header, make_ip_header, make_tcp_header, tcp_checksum, IP_HDR_SIZE,
+    MAX_PACKET_SIZE, TCP_HDR_SIZE,
+};
+use crate::probe_modules::probe_modules::{
+    check_dst_port, expect_ipv4, get_src_port, Classification, ProbeGenerator, ProbeModule,
+};
+
+pub const PACKET_LENGTH: u64 = 54;
+pub const REPLIES: &[Reply] = &[Reply::Tcp(&[TcpFlags::Any(TcpFlags::RST)])];
+
+/// Precomputed generator for bare ACK probes.
+///
+/// A host answers an ACK that doesn't belong to any connection with a RST whose sequence number is
+/// the acknowledgment number of the ACK (RFC 793), so the validation goes in the acknowledgment
+/// number rather than the sequence number.
+pub struct AckProbeGenerator {
+    source_ip: Ipv4Addr,
+    source_port_first: u16,
+    source_port_last: u16,
+    buffer: Vec<u8>,
+}
+
+impl Default for AckProbeGenerator {
+    fn default() -> Self {
+        AckProbeGenerator {
+            source_ip: Ipv4Addr::new(0, 0, 0, 0),
+            source_port_first: 0,
+            source_port_last: 0,
+            buffer: Vec::with_capacity(MAX_PACKET_SIZE),
+        }
+    }
+}
+
+impl ProbeGenerator for AckProbeGenerator {
+    fn thread_initialize(
+        &mut self,
+        source_mac: &MacAddress,
+        gateway_mac: &MacAddress,
+        source_ip: &IpAddr,
+        source_port_first: u16,
+        source_port_last: u16,
+    ) {
+        let source_ip = expect_ipv4(source_ip);
+        self.source_ip = source_ip;
+        self.source_port_first = source_port_first;
+        self.source_port_last = source_port_last;
+
+        make_eth_header(source_mac, gateway_mac)
+            .write(&mut self.buffer)
+            .unwrap();
+
+        let mut ip_header = make_ip_header(IpNumber::TCP);
+        ip_header.source = source_ip.octets();
+        ip_header.total_len = IP_HDR_SIZE as u16 + TCP_HDR_SIZE as u16;
+        ip_header.write_raw(&mut self.buffer).unwrap();
+
+        // The destination port is set for each probe
+        let mut tcp_header = make_tcp_header(0);
+        tcp_header.syn = false;
+        tcp_header.ack = true;
+        tcp_header.write(&mut self.buffer).unwrap();
+    }
+
+    // We need to set the IP header checksum and destination address, and the TCP ports, sequence
+    // and acknowledgment numbers, and checksum
+    fn make_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+    ) -> &[u8] {
+        let destination_ip = expect_ipv4(destination_ip);
+
+        // Set the destination IP address and port
+        self.buffer[30..34].copy_from_slice(&destination_ip.octets());
+        self.buffer[36..38].copy_from_slice(&destination_port.to_be_bytes());
+
+        // Calculate and set source port
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
+        self.buffer[34..36].copy_from_slice(&src_port.to_be_bytes());
+
+        // Set the sequence and acknowledgment numbers
+        self.buffer[38..42].copy_from_slice(&validation[1].to_be_bytes());
+        self.buffer[42..46].copy_from_slice(&validation[0].to_be_bytes());
+
+        // Calculate and set IP header checksum
+        self.buffer[24..26].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let ip_checksum = ip_checksum(&self.buffer[14..34]);
+        self.buffer[24..26].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        // Calculate and set TCP checksum
+        self.buffer[50..52].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let tcp_checksum = tcp_checksum(
+            &self.buffer[34..],
+            TCP_HDR_SIZE as u16,
+            self.source_ip.into(),
+            destination_ip.into(),
+        );
+        self.buffer[50..52].copy_from_slice(&tcp_checksum.to_be_bytes());
+        &self.buffer
+    }
+}
+
+/// Probe module that sends bare TCP ACK packets. A validated RST shows that the host is up and that
+/// the port is not filtered by a stateful firewall, whether or not it is open.
+pub struct TcpAckscan;
+
+impl ProbeModule for TcpAckscan {
+    fn name(&self) -> &'static str {
+        "tcp_ackscan"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send TCP ACK packets to a single port, RST replies (unfiltered) are successes"
+    }
+
+    fn replies(&self) -> &'static [Reply] {
+        REPLIES
+    }
+
+    fn packet_length(&self) -> u64 {
+        PACKET_LENGTH
+    }
+
+    fn make_generator(&self, _config: &Config) -> Box<dyn ProbeGenerator> {
+        Box::new(AckProbeGenerator::default())
+    }
+
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        _src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool {
+        let packet_slice = match SlicedPacket::from_ethernet(packet) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        match &packet_slice.net {
+            Some(NetSlice::Ipv4(slice)) if slice.header().protocol() == IpNumber::TCP => {}
+            _ => {
+                debug!("Could not unpack network slice");
+                return false;
+            }
+        }
+
+        let tcp_header = match &packet_slice.transport {
+            Some(TransportSlice::Tcp(slice)) => {
+                TcpHeaderSlice::from_slice(slice.slice()).expect("Could not create TcpHeaderSlice")
+            }
+            _ => {
+                debug!("Could not unpack transport slice");
+                return false;
+            }
+        };
+
+        if !tcp_header.rst() || !ctx.config.is_target_port(tcp_header.source_port()) {
+            return false;
+        }
+
+        if !check_dst_port(tcp_header.destination_port(), validation, &ctx.config) {
+            return false;
+        }
+
+        // The RST's sequence number is the acknowledgment number we sent
+        tcp_header.sequence_number() == validation[0]
+    }
+
+    fn classify_packet(&self, _packet: &[u8]) -> Classification {
+        // Only RSTs pass validation
+        Classification::success("rst")
+    }
+
+    fn print_packet(&self, packet: &[u8]) {
+        synscan_print_packet(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_probe() {
+        let source_ip = Ipv4Addr::new(192, 168, 68, 3);
+        let destination_ip = Ipv4Addr::new(46, 216, 152, 50);
+        let validation = [0xdeadbeef, 0x01234567];
+
+        let mut generator = AckProbeGenerator::default();
+        generator.thread_initialize(
+            &MacAddress::default(),
+            &MacAddress::default(),
+            &source_ip.into(),
+            32768,
+            61000,
+        );
+        let packet = generator.make_packet(&destination_ip.into(), 443, &validation, 0);
+        assert_eq!(packet.len() as u64, PACKET_LENGTH);
+
+        let sliced_packet = SlicedPacket::from_ethernet(packet).unwrap();
+        let tcp = match sliced_packet.transport {
+            Some(TransportSlice::Tcp(tcp)) => tcp,
+            _ => panic!("Expected a TCP packet"),
+        };
+        assert!(tcp.ack() && !tcp.syn() && !tcp.rst());
+        assert_eq!(tcp.acknowledgment_number(), validation[0]);
+        assert_eq!(tcp.destination_port(), 443);
+
+        let expected = tcp
+            .to_header()
+            .calc_checksum_ipv4_raw(source_ip.octets(), destination_ip.octets(), &[])
+            .unwrap();
+        assert_eq!(tcp.checksum(), expected);
+    }
+}
@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 use etherparse::{
     IpHeaders, IpNumber, LinkSlice, NetSlice, PacketBuilder, SlicedPacket, TcpHeaderSlice,
@@ -8,15 +8,24 @@ use eui48::MacAddress;
 use libc::{c_uchar, c_uint, c_ushort, ETH_ALEN, ETH_P_IP, IPPROTO_TCP, MAXTTL};
 use log::debug;
 
-use crate::config::Config;
+use crate::config::{Config, Context};
+use crate::net::bpf::{Reply, TcpFlags};
 use crate::probe_modules::packet::{
-    ethhdr, ip_checksum, iphdr, make_eth_header, make_ip_header, make_tcp_header, tcp_checksum,
-    tcphdr, ETH_HDR_SIZE, IP_HDR_SIZE, MAX_PACKET_SIZE, TCP_HDR_SIZE,
+    ethhdr, ip_checksum, iphdr, make_eth_header, make_ip_header, make_syn_header, tcp_checksum,
+    tcphdr, TcpOptionsLayout, ETH_HDR_SIZE, IP_HDR_SIZE, MAX_PACKET_SIZE, TCP_HDR_SIZE,
+};
+use crate::probe_modules::probe_modules::{
+    check_dst_port, expect_ipv4, get_src_port, Classification, ProbeGenerator, ProbeModule,
 };
-use crate::probe_modules::probe_modules::ProbeGenerator;
 
+// Length of a SYN without any TCP options
 pub const PACKET_LENGTH: u64 = 54;
-pub const PCAP_FILTER: &str = "tcp && tcp[13] & 4 != 0 || tcp[13] == 18";
+
+// RSTs and SYN-ACKs
+pub const REPLIES: &[Reply] = &[Reply::Tcp(&[
+    TcpFlags::Any(TcpFlags::RST),
+    TcpFlags::Exactly(TcpFlags::SYN | TcpFlags::ACK),
+])];
 
 pub struct NaiveProbeGenerator {
     source_mac: MacAddress,
@@ -24,7 +33,7 @@ pub struct NaiveProbeGenerator {
     source_ip: Ipv4Addr,
     source_port_first: u16,
     source_port_last: u16,
-    target_port: u16,
+    options: TcpOptionsLayout,
     buffer: Vec<u8>,
 }
 
@@ -36,12 +45,19 @@ impl Default for NaiveProbeGenerator {
             source_ip: Ipv4Addr::new(0, 0, 0, 0),
             source_port_first: 0,
             source_port_last: 0,
-            target_port: 0,
+            options: TcpOptionsLayout::default(),
             buffer: Vec::with_capacity(MAX_PACKET_SIZE),
         }
     }
 }
 
+impl NaiveProbeGenerator {
+    pub fn with_options(mut self, options: TcpOptionsLayout) -> Self {
+        self.options = options;
+        self
+    }
+}
+
 /// This is a simple probe generator that serializes the packet each time make_packet is called
 impl ProbeGenerator for NaiveProbeGenerator {
     // This is a no-op as we build the packet from scratch each time
@@ -49,34 +65,41 @@ impl ProbeGenerator for NaiveProbeGenerator {
         &mut self,
         source_mac: &MacAddress,
         gateway_mac: &MacAddress,
-        source_ip: &Ipv4Addr,
+        source_ip: &IpAddr,
         source_port_first: u16,
         source_port_last: u16,
-        target_port: u16,
     ) {
+        let source_ip = expect_ipv4(source_ip);
         self.source_mac = *source_mac;
         self.gateway_mac = *gateway_mac;
-        self.source_ip = *source_ip;
+        self.source_ip = source_ip;
         self.source_port_first = source_port_first;
         self.source_port_last = source_port_last;
-        self.target_port = target_port;
     }
 
     fn make_packet(
         &mut self,
-        destination_ip: &Ipv4Addr,
+        destination_ip: &IpAddr,
+        destination_port: u16,
         validation: &[u32],
         probe_num: u32,
     ) -> &[u8] {
+        let destination_ip = expect_ipv4(destination_ip);
+
         // Calculate source port
-        let num_ports = (self.source_port_last - self.source_port_first + 1) as u32;
-        let src_port = self.source_port_first + ((validation[1] + probe_num) % num_ports) as u16;
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
 
         let mut ip_header = make_ip_header(IpNumber::TCP);
         ip_header.source = self.source_ip.octets();
         ip_header.destination = destination_ip.octets();
 
-        let mut tcp_header = make_tcp_header(self.target_port);
+        // The second validation word doubles as the timestamp value, if the layout has one
+        let mut tcp_header = make_syn_header(destination_port, self.options, validation[1]);
         tcp_header.source_port = src_port;
         tcp_header.sequence_number = validation[0];
 
@@ -97,13 +120,14 @@ impl ProbeGenerator for NaiveProbeGenerator {
 /// This is a precomputed probe generator that sets up most of the packet in advance as there are fields
 /// that do not change between probes.
 ///
-/// The only fields that need to be set in make_packet are the IPv4 header checksum and destination
-/// address, and the TCP source port, sequence number, and checksum.
+/// The only fields that need to be set for each probe are the IPv4 header checksum and destination
+/// address, and the TCP ports, sequence number, timestamp (if any) and checksum. write_packet
+/// copies the template straight into the batch buffer or ring frame and sets them there.
 pub struct PrecomputedProbeGenerator {
     source_ip: Ipv4Addr,
     source_port_first: u16,
     source_port_last: u16,
-    target_port: u16,
+    options: TcpOptionsLayout,
     buffer: Vec<u8>,
 }
 
@@ -113,74 +137,137 @@ impl Default for PrecomputedProbeGenerator {
             source_ip: Ipv4Addr::new(0, 0, 0, 0),
             source_port_first: 0,
             source_port_last: 0,
-            target_port: 0,
+            options: TcpOptionsLayout::default(),
             buffer: Vec::with_capacity(MAX_PACKET_SIZE),
         }
     }
 }
 
+impl PrecomputedProbeGenerator {
+    pub fn with_options(mut self, options: TcpOptionsLayout) -> Self {
+        self.options = options;
+        self
+    }
+
+    // Set the fields that differ between probes in a copy of the template
+    fn fill(
+        &self,
+        packet: &mut [u8],
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+    ) {
+        let destination_ip = expect_ipv4(destination_ip);
+
+        // Set the destination IP address and port
+        packet[30..34].copy_from_slice(&destination_ip.octets());
+        packet[36..38].copy_from_slice(&destination_port.to_be_bytes());
+
+        // Calculate and set source port
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
+        packet[34..36].copy_from_slice(&src_port.to_be_bytes());
+
+        // Set the sequence number
+        packet[38..42].copy_from_slice(&validation[0].to_be_bytes());
+
+        // Set the timestamp value, which follows the fixed 20 byte TCP header
+        if let Some(offset) = self.options.timestamp_offset() {
+            let offset = 54 + offset;
+            packet[offset..offset + 4].copy_from_slice(&validation[1].to_be_bytes());
+        }
+
+        // Calculate and set IP header checksum
+        packet[24..26].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let ip_checksum = ip_checksum(&packet[14..34]);
+        packet[24..26].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        // Calculate and set TCP checksum over the header including options
+        packet[50..52].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let tcp_len = (packet.len() - 34) as u16;
+        let tcp_checksum = tcp_checksum(
+            &packet[34..],
+            tcp_len,
+            self.source_ip.into(),
+            destination_ip.into(),
+        );
+        packet[50..52].copy_from_slice(&tcp_checksum.to_be_bytes());
+    }
+}
+
 impl ProbeGenerator for PrecomputedProbeGenerator {
     fn thread_initialize(
         &mut self,
         source_mac: &MacAddress,
         gateway_mac: &MacAddress,
-        source_ip: &Ipv4Addr,
+        source_ip: &IpAddr,
         source_port_first: u16,
         source_port_last: u16,
-        target_port: u16,
     ) {
-        self.source_ip = *source_ip;
+        let source_ip = expect_ipv4(source_ip);
+        self.source_ip = source_ip;
         self.source_port_first = source_port_first;
         self.source_port_last = source_port_last;
-        self.target_port = target_port;
 
         make_eth_header(source_mac, gateway_mac)
             .write(&mut self.buffer)
             .unwrap();
 
+        // The destination port is set for each probe
+        let tcp_header = make_syn_header(0, self.options, 0);
+
         let mut ip_header = make_ip_header(IpNumber::TCP);
         ip_header.source = source_ip.octets();
-        ip_header.total_len = IP_HDR_SIZE as u16 + TCP_HDR_SIZE as u16;
+        ip_header.total_len = IP_HDR_SIZE as u16 + tcp_header.header_len_u16();
         ip_header.write_raw(&mut self.buffer).unwrap();
 
-        let tcp_header = make_tcp_header(target_port);
         tcp_header.write(&mut self.buffer).unwrap();
     }
 
-    // We just need to the IP header checksum, destination address, and TCP source port, sequence number, and checksum
     fn make_packet(
         &mut self,
-        destination_ip: &Ipv4Addr,
+        destination_ip: &IpAddr,
+        destination_port: u16,
         validation: &[u32],
         probe_num: u32,
     ) -> &[u8] {
-        // Set the destination IP address
-        self.buffer[30..34].copy_from_slice(&destination_ip.octets());
-
-        // Calculate and set source port
-        let num_ports = (self.source_port_last - self.source_port_first + 1) as u32;
-        let src_port = self.source_port_first + ((validation[1] + probe_num) % num_ports) as u16;
-        self.buffer[34..36].copy_from_slice(&src_port.to_be_bytes());
-
-        // Set the sequence number
-        self.buffer[38..42].copy_from_slice(&validation[0].to_be_bytes());
-
-        // Calculate and set IP header checksum
-        self.buffer[24..26].copy_from_slice(&0u16.to_be_bytes()); // Zero out
-        let ip_checksum = ip_checksum(&self.buffer[14..34]);
-        self.buffer[24..26].copy_from_slice(&ip_checksum.to_be_bytes());
-
-        // Calculate and set TCP checksum
-        self.buffer[50..52].copy_from_slice(&0u16.to_be_bytes()); // Zero out
-        let tcp_checksum = tcp_checksum(
-            &self.buffer[34..],
-            20,
-            self.source_ip.into(),
-            (*destination_ip).into(),
+        // The template in the buffer is patched in place
+        let mut buffer = std::mem::take(&mut self.buffer);
+        self.fill(
+            &mut buffer,
+            destination_ip,
+            destination_port,
+            validation,
+            probe_num,
         );
-        self.buffer[50..52].copy_from_slice(&tcp_checksum.to_be_bytes());
+        self.buffer = buffer;
         &self.buffer
     }
+
+    fn write_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+        buffer: &mut [u8],
+    ) -> usize {
+        let packet = &mut buffer[..self.buffer.len()];
+        packet.copy_from_slice(&self.buffer);
+        self.fill(
+            packet,
+            destination_ip,
+            destination_port,
+            validation,
+            probe_num,
+        );
+        packet.len()
+    }
 }
 
 /// Unsafe probe generator similar to above, but with no bounds checking, similar to ZMap's
@@ -189,7 +276,6 @@ pub struct PrecomputedUnsafeProbeGenerator {
     source_ip: Ipv4Addr,
     source_port_first: u16,
     source_port_last: u16,
-    target_port: u16,
     buffer: Vec<u8>,
 }
 
@@ -202,7 +288,6 @@ impl Default for PrecomputedUnsafeProbeGenerator {
             source_ip: Ipv4Addr::new(0, 0, 0, 0),
             source_port_first: 0,
             source_port_last: 0,
-            target_port: 0,
             buffer,
         }
     }
@@ -213,15 +298,14 @@ impl ProbeGenerator for PrecomputedUnsafeProbeGenerator {
         &mut self,
         source_mac: &MacAddress,
         gateway_mac: &MacAddress,
-        source_ip: &Ipv4Addr,
+        source_ip: &IpAddr,
         source_port_first: u16,
         source_port_last: u16,
-        target_port: u16,
     ) {
-        self.source_ip = *source_ip;
+        let source_ip = expect_ipv4(source_ip);
+        self.source_ip = source_ip;
         self.source_port_first = source_port_first;
         self.source_port_last = source_port_last;
-        self.target_port = target_port;
 
         // Set the Ethernet header
         unsafe {
@@ -247,7 +331,7 @@ impl ProbeGenerator for PrecomputedUnsafeProbeGenerator {
             ip_header.id = 54321u16.to_be();
             ip_header.ttl = MAXTTL;
             ip_header.protocol = IPPROTO_TCP as u8;
-            ip_header.saddr = u32::from(*source_ip).to_be();
+            ip_header.saddr = u32::from(source_ip).to_be();
             ip_header.frag_off = 0x4000u16.to_be(); // Don't fragment
         };
 
@@ -255,36 +339,42 @@ impl ProbeGenerator for PrecomputedUnsafeProbeGenerator {
         unsafe {
             let tcp_header =
                 &mut *(self.buffer.as_mut_ptr().add(ETH_HDR_SIZE + IP_HDR_SIZE) as *mut tcphdr);
-            tcp_header.dest = target_port.to_be();
             tcp_header.flags = 0x02; // SYN
             tcp_header.window = u16::MAX.to_be();
             tcp_header.data_off = 0x50;
         };
     }
 
-    // We just need to the IP header checksum, destination address, and TCP source port, sequence number, and checksum
+    // We just need to the IP header checksum, destination address, and TCP ports, sequence number, and checksum
     fn make_packet(
         &mut self,
-        destination_ip: &Ipv4Addr,
+        destination_ip: &IpAddr,
+        destination_port: u16,
         validation: &[u32],
         probe_num: u32,
     ) -> &[u8] {
+        let destination_ip = expect_ipv4(destination_ip);
         unsafe {
             let ip_header = &mut *(self.buffer.as_mut_ptr().add(ETH_HDR_SIZE) as *mut iphdr);
-            ip_header.daddr = u32::from(*destination_ip).to_be();
+            ip_header.daddr = u32::from(destination_ip).to_be();
             let ip_checksum = ip_checksum(&self.buffer[ETH_HDR_SIZE..ETH_HDR_SIZE + IP_HDR_SIZE]);
             ip_header.checksum = ip_checksum.to_be();
         };
 
-        let num_ports = (self.source_port_last - self.source_port_first + 1) as u32;
-        let src_port = self.source_port_first + ((validation[1] + probe_num) % num_ports) as u16;
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
 
         unsafe {
             let tcp_header =
                 &mut *(self.buffer.as_mut_ptr().add(ETH_HDR_SIZE + IP_HDR_SIZE) as *mut tcphdr);
 
-            // Calculate and set source port
+            // Calculate and set source and destination ports
             tcp_header.source = src_port.to_be();
+            tcp_header.dest = destination_port.to_be();
 
             // Set the sequence number
             tcp_header.seq = validation[0].to_be();
@@ -294,7 +384,7 @@ impl ProbeGenerator for PrecomputedUnsafeProbeGenerator {
                 &self.buffer[ETH_HDR_SIZE + IP_HDR_SIZE..],
                 TCP_HDR_SIZE as u16,
                 self.source_ip.into(),
-                (*destination_ip).into(),
+                destination_ip.into(),
             );
 
             tcp_header.checksum = tcp_checksum.to_be();
@@ -304,33 +394,81 @@ impl ProbeGenerator for PrecomputedUnsafeProbeGenerator {
     }
 }
 
-// Return false if dst port is outside the expected valid range
-fn check_dst_port(port: u16, validation: &[u32], config: &Config) -> bool {
-    if port > config.source_port_last || port < config.source_port_first {
-        return false;
+/// Probe module that sends TCP SYN packets and treats a SYN-ACK as a success. The TCP options
+/// layout can be selected with --probe-args (bare, smallest, linux, bsd or windows).
+#[derive(Default)]
+pub struct TcpSynscan {
+    options: TcpOptionsLayout,
+}
+
+impl ProbeModule for TcpSynscan {
+    fn name(&self) -> &'static str {
+        "tcp_synscan"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send TCP SYN packets to a single port, SYN-ACK replies are successes"
+    }
+
+    fn global_initialize(&mut self, config: &Config) -> Result<(), String> {
+        if let Some(probe_args) = &config.probe_args {
+            self.options = TcpOptionsLayout::parse(probe_args)?;
+        }
+        debug!("Using TCP options layout {:?}", self.options);
+        Ok(())
+    }
+
+    fn replies(&self) -> &'static [Reply] {
+        REPLIES
+    }
+
+    fn packet_length(&self) -> u64 {
+        PACKET_LENGTH + self.options.options_len() as u64
+    }
+
+    fn make_generator(&self, config: &Config) -> Box<dyn ProbeGenerator> {
+        if config.naive_probes {
+            debug!("Using naive probe generator");
+            Box::new(NaiveProbeGenerator::default().with_options(self.options))
+        } else {
+            debug!("Using optimized probe generator");
+            Box::new(PrecomputedProbeGenerator::default().with_options(self.options))
+        }
     }
 
-    let num_ports = (config.source_port_last - config.source_port_first + 1) as u32;
-    let to_validate = (port - config.source_port_first) as u32;
-    let min = validation[1] % num_ports;
-    let max = (validation[1] + config.probes - 1) % num_ports;
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        _src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool {
+        synscan_validate_packet(packet, validation, &ctx.config)
+    }
+
+    fn classify_packet(&self, packet: &[u8]) -> Classification {
+        if synscan_classify_packet(packet) {
+            Classification::success("synack")
+        } else {
+            Classification::failure("rst")
+        }
+    }
 
-    return ((max - min) % num_ports) >= ((to_validate - min) % num_ports);
+    fn print_packet(&self, packet: &[u8]) {
+        synscan_print_packet(packet)
+    }
 }
 
 pub fn synscan_validate_packet(packet: &[u8], validation: &[u32], config: &Config) -> bool {
     let packet_slice =
         SlicedPacket::from_ethernet(packet).expect("Could not parse Ethernet packet");
-    let ip_header = match &packet_slice.net {
-        Some(NetSlice::Ipv4(slice)) => slice.header(),
+    match &packet_slice.net {
+        Some(NetSlice::Ipv4(slice)) if slice.header().protocol() == IpNumber::TCP => {}
+        Some(NetSlice::Ipv6(_)) => {}
         _ => {
             debug!("Could not unpack network slice");
             return false;
         }
-    };
-
-    if ip_header.protocol() != IpNumber::TCP {
-        return false;
     }
 
     let tcp_header = match &packet_slice.transport {
@@ -343,7 +481,7 @@ pub fn synscan_validate_packet(packet: &[u8], validation: &[u32], config: &Confi
         }
     };
 
-    if config.target_port != tcp_header.source_port() {
+    if !config.is_target_port(tcp_header.source_port()) {
         return false;
     }
 
@@ -351,7 +489,7 @@ pub fn synscan_validate_packet(packet: &[u8], validation: &[u32], config: &Confi
         return false;
     }
 
-    if tcp_header.acknowledgment_number() != validation[0] + 1 {
+    if tcp_header.acknowledgment_number() != validation[0].wrapping_add(1) {
         return false;
     }
 
@@ -388,14 +526,6 @@ pub fn synscan_print_packet(packet: &[u8]) {
         }
     };
 
-    let ip_header = match &sliced_packet.net {
-        Some(NetSlice::Ipv4(slice)) => slice.header(),
-        _ => {
-            debug!("Could not unpack network slice");
-            return;
-        }
-    };
-
     let tcp_header = match &sliced_packet.transport {
         Some(TransportSlice::Tcp(slice)) => {
             TcpHeaderSlice::from_slice(slice.slice()).expect("Could not create TcpHeaderSlice")
@@ -424,12 +554,20 @@ pub fn synscan_print_packet(packet: &[u8]) {
         eth_destination[5],
     );
 
-    println!(
-        "ip {{ saddr: {} | daddr: {} | checksum: {} }}",
-        ip_header.source_addr(),
-        ip_header.destination_addr(),
-        ip_header.header_checksum()
-    );
+    match &sliced_packet.net {
+        Some(NetSlice::Ipv4(slice)) => println!(
+            "ip {{ saddr: {} | daddr: {} | checksum: {} }}",
+            slice.header().source_addr(),
+            slice.header().destination_addr(),
+            slice.header().header_checksum()
+        ),
+        Some(NetSlice::Ipv6(slice)) => println!(
+            "ip6 {{ saddr: {} | daddr: {} }}",
+            slice.header().source_addr(),
+            slice.header().destination_addr()
+        ),
+        _ => debug!("Could not unpack network slice"),
+    }
 
     println!(
         "tcp {{ sport: {} | dport: {} | seq: {} | ack: {} ({}) | syn: {} | rst: {} | checksum: {} }}",
@@ -445,3 +583,107 @@ pub fn synscan_print_packet(packet: &[u8]) {
 
     println!("------------------------------------------------------");
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    // The precomputed generator patches fields in place, so it must produce exactly the packet the
+    // naive generator serializes from scratch for every options layout
+    #[test]
+    fn test_precomputed_matches_naive() {
+        let source_mac = MacAddress::new([0xaa, 0x41, 0x72, 0x51, 0x54, 0x42]);
+        let gateway_mac = MacAddress::new([0xf6, 0xd4, 0x88, 0x07, 0x37, 0x64]);
+        let source_ip = Ipv4Addr::new(192, 168, 68, 3);
+        let destination_ip = Ipv4Addr::new(46, 216, 152, 50);
+        let validation = [2324566490, 0x01234567];
+
+        for layout in ["bare", "smallest", "linux", "bsd", "windows"] {
+            let layout = TcpOptionsLayout::parse(layout).unwrap();
+            let mut naive = NaiveProbeGenerator::default().with_options(layout);
+            let mut precomputed = PrecomputedProbeGenerator::default().with_options(layout);
+            for generator in [
+                &mut naive as &mut dyn ProbeGenerator,
+                &mut precomputed as &mut dyn ProbeGenerator,
+            ] {
+                generator.thread_initialize(
+                    &source_mac,
+                    &gateway_mac,
+                    &source_ip.into(),
+                    32768,
+                    61000,
+                );
+            }
+
+            // The destination port changes between probes when scanning several ports
+            for destination_port in [443, 8080] {
+                let expected = naive
+                    .make_packet(&destination_ip.into(), destination_port, &validation, 1)
+                    .to_vec();
+                let actual = precomputed.make_packet(
+                    &destination_ip.into(),
+                    destination_port,
+                    &validation,
+                    1,
+                );
+                assert_eq!(actual, expected.as_slice(), "{:?}", layout);
+                assert_eq!(
+                    actual.len() as u64,
+                    PACKET_LENGTH + layout.options_len() as u64
+                );
+
+                // Probes written straight into a frame match as well
+                let mut frame = [0xff; 2048];
+                let len = precomputed.write_packet(
+                    &destination_ip.into(),
+                    destination_port,
+                    &validation,
+                    1,
+                    &mut frame,
+                );
+                assert_eq!(&frame[..len], expected.as_slice(), "{:?}", layout);
+            }
+        }
+    }
+
+    // Source ports wrap around with the validation, like the ports check_dst_port accepts
+    #[test]
+    fn test_src_port_wraps() {
+        let config = Config::parse_from([
+            "zmap-rs",
+            "--probes",
+            "3",
+            "--source-port-first",
+            "1000",
+            "--source-port-last",
+            "1009",
+        ]);
+        let source_ip = Ipv4Addr::new(192, 168, 68, 3);
+        let destination_ip = Ipv4Addr::new(46, 216, 152, 50);
+        let validation = [2324566490, u32::MAX];
+
+        let mut naive = NaiveProbeGenerator::default();
+        let mut precomputed = PrecomputedProbeGenerator::default();
+        for generator in [
+            &mut naive as &mut dyn ProbeGenerator,
+            &mut precomputed as &mut dyn ProbeGenerator,
+        ] {
+            generator.thread_initialize(
+                &MacAddress::default(),
+                &MacAddress::default(),
+                &source_ip.into(),
+                1000,
+                1009,
+            );
+            for probe_num in 0..3 {
+                let packet =
+                    generator.make_packet(&destination_ip.into(), 443, &validation, probe_num);
+                let src_port = u16::from_be_bytes([packet[34], packet[35]]);
+                assert_eq!(src_port, get_src_port(1000, 1009, &validation, probe_num));
+                assert!(check_dst_port(src_port, &validation, &config));
+            }
+        }
+    }
+}
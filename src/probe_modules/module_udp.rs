@@ -0,0 +1,680 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use etherparse::{IpNumber, Ipv4HeaderSlice, NetSlice, SlicedPacket, TransportSlice};
+use eui48::MacAddress;
+use log::debug;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+use crate::config::{Config, Context};
+use crate::lib::validate;
+use crate::net::bpf::Reply;
+use crate::probe_modules::packet::{
+    ip_checksum, make_eth_header, make_ip_header, make_udp_header, udp_checksum, ETH_HDR_SIZE,
+    IP_HDR_SIZE, MAX_PACKET_SIZE, UDP_HDR_SIZE,
+};
+use crate::probe_modules::probe_modules::{
+    check_dst_port, expect_ipv4, get_src_port, Classification, Field, FieldValue, ProbeGenerator,
+    ProbeModule,
+};
+
+pub const REPLIES: &[Reply] = &[Reply::Udp, Reply::Icmp { except: None }];
+
+// Largest payload that fits in a single unfragmented packet with a 1500 byte MTU
+const MAX_UDP_PAYLOAD_LEN: usize = 1472;
+
+const HEADERS_LEN: usize = ETH_HDR_SIZE + IP_HDR_SIZE + UDP_HDR_SIZE;
+const ICMP_UNREACH: u8 = 3;
+
+/// A field in a payload template, written out as `${NAME}` or `${NAME=n}` in the template file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplateField {
+    Data(Vec<u8>),
+    RandByte(usize),
+    RandDigit(usize),
+    RandAlpha(usize),
+    RandAlphanum(usize),
+    Saddr,
+    SaddrN,
+    Daddr,
+    DaddrN,
+    Sport,
+    SportN,
+    Dport,
+    DportN,
+    Validation,
+}
+
+impl TemplateField {
+    // Upper bound on the number of bytes this field expands to
+    fn max_len(&self) -> usize {
+        match self {
+            TemplateField::Data(data) => data.len(),
+            TemplateField::RandByte(n)
+            | TemplateField::RandDigit(n)
+            | TemplateField::RandAlpha(n)
+            | TemplateField::RandAlphanum(n) => *n,
+            TemplateField::Saddr | TemplateField::Daddr => 15,
+            TemplateField::SaddrN | TemplateField::DaddrN => 4,
+            TemplateField::Sport | TemplateField::Dport => 5,
+            TemplateField::SportN | TemplateField::DportN => 2,
+            TemplateField::Validation => 8,
+        }
+    }
+}
+
+fn parse_template_field(spec: &str) -> Result<TemplateField, String> {
+    let (name, len) = match spec.split_once('=') {
+        Some((name, len)) => {
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| format!("invalid length in template field ${{{}}}", spec))?;
+            (name, Some(len))
+        }
+        None => (spec, None),
+    };
+
+    let field = match (name, len) {
+        ("RAND_BYTE", Some(n)) => TemplateField::RandByte(n),
+        ("RAND_DIGIT", Some(n)) => TemplateField::RandDigit(n),
+        ("RAND_ALPHA", Some(n)) => TemplateField::RandAlpha(n),
+        ("RAND_ALPHANUM", Some(n)) => TemplateField::RandAlphanum(n),
+        ("SADDR", None) => TemplateField::Saddr,
+        ("SADDR_N", None) => TemplateField::SaddrN,
+        ("DADDR", None) => TemplateField::Daddr,
+        ("DADDR_N", None) => TemplateField::DaddrN,
+        ("SPORT", None) => TemplateField::Sport,
+        ("SPORT_N", None) => TemplateField::SportN,
+        ("DPORT", None) => TemplateField::Dport,
+        ("DPORT_N", None) => TemplateField::DportN,
+        ("VALIDATION", None) => TemplateField::Validation,
+        _ => return Err(format!("unknown template field ${{{}}}", spec)),
+    };
+    Ok(field)
+}
+
+fn parse_template(template: &[u8]) -> Result<Vec<TemplateField>, String> {
+    let mut fields = vec![];
+    let mut data = vec![];
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        if rest.starts_with(b"${") {
+            let end = rest
+                .iter()
+                .position(|&b| b == b'}')
+                .ok_or("unterminated template field")?;
+            let spec = std::str::from_utf8(&rest[2..end])
+                .map_err(|_| String::from("template field is not valid UTF-8"))?;
+
+            if !data.is_empty() {
+                fields.push(TemplateField::Data(std::mem::take(&mut data)));
+            }
+            fields.push(parse_template_field(spec)?);
+            rest = &rest[end + 1..];
+        } else {
+            data.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+
+    if !data.is_empty() {
+        fields.push(TemplateField::Data(data));
+    }
+    Ok(fields)
+}
+
+fn parse_hex(arg: &str) -> Result<Vec<u8>, String> {
+    arg.as_bytes()
+        .chunks(2)
+        .map(|digits| match digits {
+            [_, _] => std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex digits '{}'", String::from_utf8_lossy(digits))),
+            _ => Err(String::from(
+                "hex payload must have an even number of digits",
+            )),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum UdpPayload {
+    Fixed(Vec<u8>),
+    Template(Vec<TemplateField>),
+}
+
+impl UdpPayload {
+    fn parse(probe_args: &str) -> Result<Self, String> {
+        let (kind, arg) = probe_args
+            .split_once(':')
+            .ok_or("probe args must be of the form <type>:<value>")?;
+
+        let payload = match kind {
+            "text" => UdpPayload::Fixed(arg.as_bytes().to_vec()),
+            "hex" => UdpPayload::Fixed(parse_hex(arg)?),
+            "file" => UdpPayload::Fixed(
+                std::fs::read(arg).map_err(|e| format!("could not read {}: {}", arg, e))?,
+            ),
+            "template" => UdpPayload::Template(parse_template(
+                &std::fs::read(arg).map_err(|e| format!("could not read {}: {}", arg, e))?,
+            )?),
+            _ => {
+                return Err(format!(
+                    "unknown payload type '{}' (supported types are text, hex, file and template)",
+                    kind
+                ))
+            }
+        };
+
+        if payload.max_len() > MAX_UDP_PAYLOAD_LEN {
+            return Err(format!(
+                "payload may be up to {} bytes, exceeding the maximum of {}",
+                payload.max_len(),
+                MAX_UDP_PAYLOAD_LEN
+            ));
+        }
+        Ok(payload)
+    }
+
+    fn max_len(&self) -> usize {
+        match self {
+            UdpPayload::Fixed(data) => data.len(),
+            UdpPayload::Template(fields) => fields.iter().map(|f| f.max_len()).sum(),
+        }
+    }
+}
+
+/// Values of the template fields that depend on the probe
+struct TemplateValues<'a> {
+    source_ip: Ipv4Addr,
+    destination_ip: Ipv4Addr,
+    src_port: u16,
+    destination_port: u16,
+    validation: &'a [u32],
+}
+
+// Write the template's payload into `payload` and return its length
+fn fill_template(
+    fields: &[TemplateField],
+    values: &TemplateValues,
+    rng: &mut ThreadRng,
+    payload: &mut [u8],
+) -> usize {
+    const DIGITS: &[u8] = b"0123456789";
+    const ALPHA: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const ALPHANUM: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+    let mut len = 0;
+    let mut put = |bytes: &[u8]| {
+        payload[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    for field in fields {
+        match field {
+            TemplateField::Data(data) => put(data),
+            TemplateField::RandByte(n) => {
+                for _ in 0..*n {
+                    put(&[rng.gen()]);
+                }
+            }
+            TemplateField::RandDigit(n) => {
+                for _ in 0..*n {
+                    put(&[DIGITS[rng.gen_range(0..DIGITS.len())]]);
+                }
+            }
+            TemplateField::RandAlpha(n) => {
+                for _ in 0..*n {
+                    put(&[ALPHA[rng.gen_range(0..ALPHA.len())]]);
+                }
+            }
+            TemplateField::RandAlphanum(n) => {
+                for _ in 0..*n {
+                    put(&[ALPHANUM[rng.gen_range(0..ALPHANUM.len())]]);
+                }
+            }
+            TemplateField::Saddr => put(values.source_ip.to_string().as_bytes()),
+            TemplateField::SaddrN => put(&values.source_ip.octets()),
+            TemplateField::Daddr => put(values.destination_ip.to_string().as_bytes()),
+            TemplateField::DaddrN => put(&values.destination_ip.octets()),
+            TemplateField::Sport => put(values.src_port.to_string().as_bytes()),
+            TemplateField::SportN => put(&values.src_port.to_be_bytes()),
+            TemplateField::Dport => put(values.destination_port.to_string().as_bytes()),
+            TemplateField::DportN => put(&values.destination_port.to_be_bytes()),
+            TemplateField::Validation => {
+                put(&values.validation[0].to_be_bytes());
+                put(&values.validation[1].to_be_bytes());
+            }
+        }
+    }
+    len
+}
+
+/// Generator for UDP probes. The Ethernet, IP and UDP headers are set up in advance, and the
+/// payload (which may differ per target when using a template) is written after a copy of them
+/// for each probe.
+pub struct UdpProbeGenerator {
+    payload: UdpPayload,
+    source_ip: Ipv4Addr,
+    source_port_first: u16,
+    source_port_last: u16,
+    rng: ThreadRng,
+    headers: Vec<u8>,
+    // Holds the probe returned by make_packet
+    buffer: Vec<u8>,
+}
+
+impl UdpProbeGenerator {
+    fn new(payload: UdpPayload) -> Self {
+        UdpProbeGenerator {
+            payload,
+            source_ip: Ipv4Addr::new(0, 0, 0, 0),
+            source_port_first: 0,
+            source_port_last: 0,
+            rng: rand::thread_rng(),
+            headers: Vec::with_capacity(HEADERS_LEN),
+            buffer: vec![0; MAX_PACKET_SIZE],
+        }
+    }
+}
+
+impl ProbeGenerator for UdpProbeGenerator {
+    fn thread_initialize(
+        &mut self,
+        source_mac: &MacAddress,
+        gateway_mac: &MacAddress,
+        source_ip: &IpAddr,
+        source_port_first: u16,
+        source_port_last: u16,
+    ) {
+        let source_ip = expect_ipv4(source_ip);
+        self.source_ip = source_ip;
+        self.source_port_first = source_port_first;
+        self.source_port_last = source_port_last;
+
+        make_eth_header(source_mac, gateway_mac)
+            .write(&mut self.headers)
+            .unwrap();
+
+        let mut ip_header = make_ip_header(IpNumber::UDP);
+        ip_header.source = source_ip.octets();
+        ip_header.write_raw(&mut self.headers).unwrap();
+
+        // The destination port is set for each probe
+        make_udp_header(0).write(&mut self.headers).unwrap();
+    }
+
+    fn make_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+    ) -> &[u8] {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let len = self.write_packet(
+            destination_ip,
+            destination_port,
+            validation,
+            probe_num,
+            &mut buffer,
+        );
+        self.buffer = buffer;
+        &self.buffer[..len]
+    }
+
+    fn write_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+        buffer: &mut [u8],
+    ) -> usize {
+        let destination_ip = expect_ipv4(destination_ip);
+        let src_port = get_src_port(
+            self.source_port_first,
+            self.source_port_last,
+            validation,
+            probe_num,
+        );
+
+        // Write the payload after a copy of the precomputed headers
+        buffer[..HEADERS_LEN].copy_from_slice(&self.headers);
+        let payload_len = match &self.payload {
+            UdpPayload::Fixed(data) => {
+                buffer[HEADERS_LEN..HEADERS_LEN + data.len()].copy_from_slice(data);
+                data.len()
+            }
+            UdpPayload::Template(fields) => {
+                let values = TemplateValues {
+                    source_ip: self.source_ip,
+                    destination_ip,
+                    src_port,
+                    destination_port,
+                    validation,
+                };
+                fill_template(fields, &values, &mut self.rng, &mut buffer[HEADERS_LEN..])
+            }
+        };
+        let packet = &mut buffer[..HEADERS_LEN + payload_len];
+
+        let ip_len = (packet.len() - ETH_HDR_SIZE) as u16;
+        let udp_len = (packet.len() - ETH_HDR_SIZE - IP_HDR_SIZE) as u16;
+
+        // Set the IP total length and destination address
+        packet[16..18].copy_from_slice(&ip_len.to_be_bytes());
+        packet[30..34].copy_from_slice(&destination_ip.octets());
+
+        // Set the UDP ports and length
+        packet[34..36].copy_from_slice(&src_port.to_be_bytes());
+        packet[36..38].copy_from_slice(&destination_port.to_be_bytes());
+        packet[38..40].copy_from_slice(&udp_len.to_be_bytes());
+
+        // Calculate and set IP header checksum
+        packet[24..26].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let ip_header_checksum = ip_checksum(&packet[14..34]);
+        packet[24..26].copy_from_slice(&ip_header_checksum.to_be_bytes());
+
+        // Calculate and set UDP checksum
+        packet[40..42].copy_from_slice(&0u16.to_be_bytes()); // Zero out
+        let udp_checksum =
+            udp_checksum(&packet[34..], self.source_ip.into(), destination_ip.into());
+        packet[40..42].copy_from_slice(&udp_checksum.to_be_bytes());
+        packet.len()
+    }
+}
+
+// An ICMP port unreachable quotes our probe, whose destination is the original target
+fn validate_icmp_unreach(
+    payload: &[u8],
+    local_ip: Ipv4Addr,
+    src_ip: &mut IpAddr,
+    ctx: &Context,
+) -> bool {
+    let inner_ip = match Ipv4HeaderSlice::from_slice(payload) {
+        Ok(header) => header,
+        Err(_) => return false,
+    };
+
+    if inner_ip.protocol() != IpNumber::UDP || inner_ip.source_addr() != local_ip {
+        return false;
+    }
+
+    let inner_udp = &payload[inner_ip.slice().len()..];
+    if inner_udp.len() < UDP_HDR_SIZE {
+        return false;
+    }
+
+    let inner_sport = u16::from_be_bytes([inner_udp[0], inner_udp[1]]);
+    let inner_dport = u16::from_be_bytes([inner_udp[2], inner_udp[3]]);
+    if !ctx.config.is_target_port(inner_dport) {
+        return false;
+    }
+
+    let target = inner_ip.destination_addr();
+    let Some(validation) = validate::gen_words(&ctx.validate_ctx, &local_ip.into(), &target.into())
+    else {
+        return false;
+    };
+    if !check_dst_port(inner_sport, &validation, &ctx.config) {
+        return false;
+    }
+
+    *src_ip = target.into();
+    true
+}
+
+/// Validate a UDP reply from the target port, or an ICMP port unreachable quoting one of our probes.
+/// Shared by all modules that send their probes over UDP.
+pub fn udp_validate_packet(
+    packet: &[u8],
+    src_ip: &mut IpAddr,
+    validation: &[u32],
+    ctx: &Context,
+) -> bool {
+    let packet_slice = match SlicedPacket::from_ethernet(packet) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let ip_header = match &packet_slice.net {
+        Some(NetSlice::Ipv4(slice)) => slice.header(),
+        _ => {
+            debug!("Could not unpack network slice");
+            return false;
+        }
+    };
+
+    match &packet_slice.transport {
+        Some(TransportSlice::Udp(udp)) => {
+            ctx.config.is_target_port(udp.source_port())
+                && check_dst_port(udp.destination_port(), validation, &ctx.config)
+        }
+        Some(TransportSlice::Icmpv4(icmp)) if icmp.type_u8() == ICMP_UNREACH => {
+            validate_icmp_unreach(icmp.payload(), ip_header.destination_addr(), src_ip, ctx)
+        }
+        _ => false,
+    }
+}
+
+/// Probe module that sends a UDP payload to the target port, UDP replies are successes
+pub struct Udp {
+    payload: UdpPayload,
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Udp {
+            payload: UdpPayload::Fixed(vec![]),
+        }
+    }
+}
+
+impl ProbeModule for Udp {
+    fn name(&self) -> &'static str {
+        "udp"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send a UDP payload (--probe-args=text:, hex:, file: or template:), UDP replies are successes"
+    }
+
+    fn global_initialize(&mut self, config: &Config) -> Result<(), String> {
+        let probe_args = config
+            .probe_args
+            .as_ref()
+            .ok_or("the udp module requires a payload in --probe-args")?;
+        self.payload = UdpPayload::parse(probe_args)?;
+        Ok(())
+    }
+
+    fn replies(&self) -> &'static [Reply] {
+        REPLIES
+    }
+
+    fn packet_length(&self) -> u64 {
+        (HEADERS_LEN + self.payload.max_len()) as u64
+    }
+
+    fn make_generator(&self, _config: &Config) -> Box<dyn ProbeGenerator> {
+        Box::new(UdpProbeGenerator::new(self.payload.clone()))
+    }
+
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool {
+        udp_validate_packet(packet, src_ip, validation, ctx)
+    }
+
+    fn classify_packet(&self, packet: &[u8]) -> Classification {
+        match SlicedPacket::from_ethernet(packet).map(|p| p.transport) {
+            Ok(Some(TransportSlice::Udp(_))) => Classification::success("udp"),
+            _ => Classification::failure("icmp-unreach"),
+        }
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        &["data"]
+    }
+
+    fn packet_fields(&self, packet: &[u8]) -> Vec<Field> {
+        let data = match SlicedPacket::from_ethernet(packet).map(|p| p.transport) {
+            Ok(Some(TransportSlice::Udp(udp))) => udp.payload().to_vec(),
+            _ => vec![],
+        };
+        vec![Field::new("data", FieldValue::Bytes(data))]
+    }
+
+    fn print_packet(&self, packet: &[u8]) {
+        let sliced_packet = match SlicedPacket::from_ethernet(packet) {
+            Ok(p) => p,
+            Err(_) => {
+                debug!("Could not parse Ethernet packet");
+                return;
+            }
+        };
+
+        let ip_header = match &sliced_packet.net {
+            Some(NetSlice::Ipv4(slice)) => slice.header(),
+            _ => {
+                debug!("Could not unpack network slice");
+                return;
+            }
+        };
+
+        let udp = match &sliced_packet.transport {
+            Some(TransportSlice::Udp(udp)) => udp,
+            _ => {
+                debug!("Could not unpack transport slice");
+                return;
+            }
+        };
+
+        println!(
+            "ip {{ saddr: {} | daddr: {} | checksum: {} }}",
+            ip_header.source_addr(),
+            ip_header.destination_addr(),
+            ip_header.header_checksum()
+        );
+
+        println!(
+            "udp {{ sport: {} | dport: {} | length: {} | checksum: {} }}",
+            udp.source_port(),
+            udp.destination_port(),
+            udp.length(),
+            udp.checksum(),
+        );
+
+        println!("data {{ {} }}", FieldValue::Bytes(udp.payload().to_vec()));
+
+        println!("------------------------------------------------------");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_payload() {
+        assert_eq!(
+            UdpPayload::parse("text:hello").unwrap(),
+            UdpPayload::Fixed(b"hello".to_vec())
+        );
+        assert_eq!(
+            UdpPayload::parse("hex:00ff10").unwrap(),
+            UdpPayload::Fixed(vec![0x00, 0xff, 0x10])
+        );
+        assert!(UdpPayload::parse("hex:0").is_err());
+        assert!(UdpPayload::parse("bogus:abc").is_err());
+
+        assert_eq!(
+            parse_template(b"GET ${DADDR}:${DPORT_N} ${RAND_DIGIT=3}").unwrap(),
+            vec![
+                TemplateField::Data(b"GET ".to_vec()),
+                TemplateField::Daddr,
+                TemplateField::Data(b":".to_vec()),
+                TemplateField::DportN,
+                TemplateField::Data(b" ".to_vec()),
+                TemplateField::RandDigit(3),
+            ]
+        );
+        assert!(parse_template(b"${NOPE}").is_err());
+        assert!(parse_template(b"${DADDR").is_err());
+    }
+
+    #[test]
+    fn test_udp_template_probe() {
+        let source_ip = Ipv4Addr::new(192, 168, 68, 3);
+        let destination_ip = Ipv4Addr::new(46, 216, 152, 50);
+        let validation = [0xdeadbeef, 0x01234567];
+
+        let fields = parse_template(b"${DADDR}|${VALIDATION}|${RAND_DIGIT=4}").unwrap();
+        let mut generator = UdpProbeGenerator::new(UdpPayload::Template(fields));
+        generator.thread_initialize(
+            &MacAddress::default(),
+            &MacAddress::default(),
+            &source_ip.into(),
+            32768,
+            61000,
+        );
+        let packet = generator.make_packet(&destination_ip.into(), 53, &validation, 0);
+
+        let sliced_packet = SlicedPacket::from_ethernet(packet).unwrap();
+        let udp = match sliced_packet.transport {
+            Some(TransportSlice::Udp(udp)) => udp,
+            _ => panic!("Expected a UDP packet"),
+        };
+        assert_eq!(udp.destination_port(), 53);
+        assert_eq!(
+            udp.source_port(),
+            get_src_port(32768, 61000, &validation, 0)
+        );
+
+        let payload = udp.payload();
+        assert_eq!(&payload[..14], b"46.216.152.50|");
+        assert_eq!(
+            &payload[14..22],
+            &[0xde, 0xad, 0xbe, 0xef, 0x01, 0x23, 0x45, 0x67]
+        );
+        assert_eq!(payload[22], b'|');
+        assert!(payload[23..].iter().all(|b| b.is_ascii_digit()));
+        assert_eq!(payload.len(), 27);
+    }
+
+    #[test]
+    fn test_udp_write_packet() {
+        let source_ip = Ipv4Addr::new(192, 168, 68, 3);
+        let destination_ip = Ipv4Addr::new(46, 216, 152, 50);
+        let validation = [0xdeadbeef, 0x01234567];
+
+        for payload in [
+            UdpPayload::Fixed(b"hello".to_vec()),
+            UdpPayload::Template(parse_template(b"${SADDR}:${SPORT}|${VALIDATION}").unwrap()),
+        ] {
+            let mut generator = UdpProbeGenerator::new(payload);
+            generator.thread_initialize(
+                &MacAddress::default(),
+                &MacAddress::default(),
+                &source_ip.into(),
+                32768,
+                61000,
+            );
+            let expected = generator
+                .make_packet(&destination_ip.into(), 53, &validation, 0)
+                .to_vec();
+
+            // A probe written into a used frame is the same as one from make_packet, even after a
+            // longer probe
+            let mut frame = [0xff; 2048];
+            let len =
+                generator.write_packet(&destination_ip.into(), 53, &validation, 0, &mut frame);
+            assert_eq!(&frame[..len], expected.as_slice());
+        }
+    }
+}
@@ -1,5 +1,8 @@
+use std::net::Ipv6Addr;
+
 use etherparse::{
-    EtherType, Ethernet2Header, IpFragOffset, IpNumber, Ipv4Dscp, Ipv4Header, TcpHeader,
+    EtherType, Ethernet2Header, IpFragOffset, IpNumber, Ipv4Dscp, Ipv4Header, Ipv6Header,
+    TcpHeader, TcpOptionElement, UdpHeader,
 };
 use eui48::MacAddress;
 use libc::{c_uchar, c_uint, c_ushort, ETH_ALEN, ETH_P_IP, IPPROTO_TCP, MAXTTL};
@@ -7,6 +10,9 @@ use libc::{c_uchar, c_uint, c_ushort, ETH_ALEN, ETH_P_IP, IPPROTO_TCP, MAXTTL};
 pub const ETH_HDR_SIZE: usize = std::mem::size_of::<ethhdr>();
 pub const IP_HDR_SIZE: usize = std::mem::size_of::<iphdr>();
 pub const TCP_HDR_SIZE: usize = std::mem::size_of::<tcphdr>();
+pub const IPV6_HDR_SIZE: usize = 40;
+pub const ICMP_HDR_SIZE: usize = 8;
+pub const UDP_HDR_SIZE: usize = 8;
 
 pub const MAX_PACKET_SIZE: usize = 4096;
 
@@ -64,6 +70,14 @@ pub fn make_ip_header(protocol: IpNumber) -> Ipv4Header {
     return header;
 }
 
+pub fn make_ipv6_header(next_header: IpNumber) -> Ipv6Header {
+    Ipv6Header {
+        next_header,
+        hop_limit: MAXTTL,
+        ..Default::default()
+    }
+}
+
 pub fn make_tcp_header(port: u16) -> TcpHeader {
     let mut header: TcpHeader = Default::default();
     header.sequence_number = 0;
@@ -111,6 +125,156 @@ pub fn tcp_checksum(tcp_header: &[u8], tcp_len: u16, src_ip: u32, dst_ip: u32) -
     return !sum as u16;
 }
 
+pub fn tcp6_checksum(tcp_segment: &[u8], src_ip: &Ipv6Addr, dst_ip: &Ipv6Addr) -> u16 {
+    let mut sum = 0u64;
+
+    // Pseudo header
+    for segment in src_ip.segments().iter().chain(dst_ip.segments().iter()) {
+        sum += *segment as u64;
+    }
+    sum += tcp_segment.len() as u64;
+    sum += 6u16 as u64;
+
+    for i in (0..tcp_segment.len()).step_by(2) {
+        sum += u16::from_be_bytes([tcp_segment[i], tcp_segment[i + 1]]) as u64;
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xFFFF);
+    }
+
+    !sum as u16
+}
+
+/// Layouts of TCP options mimicking the SYNs sent by common network stacks. Bare SYNs without any
+/// options are cheap to send but are treated as suspicious by some middleboxes and hosts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TcpOptionsLayout {
+    #[default]
+    Bare,
+    Smallest,
+    Linux,
+    Bsd,
+    Windows,
+}
+
+impl TcpOptionsLayout {
+    const MSS: u16 = 1460;
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "bare" => Ok(TcpOptionsLayout::Bare),
+            "smallest" => Ok(TcpOptionsLayout::Smallest),
+            "linux" => Ok(TcpOptionsLayout::Linux),
+            "bsd" => Ok(TcpOptionsLayout::Bsd),
+            "windows" => Ok(TcpOptionsLayout::Windows),
+            _ => Err(format!(
+                "unknown TCP options layout '{}' (supported layouts are bare, smallest, linux, bsd and windows)",
+                name
+            )),
+        }
+    }
+
+    pub fn options(&self, timestamp: u32) -> Vec<TcpOptionElement> {
+        use TcpOptionElement::*;
+        match self {
+            TcpOptionsLayout::Bare => vec![],
+            TcpOptionsLayout::Smallest => vec![MaximumSegmentSize(Self::MSS)],
+            TcpOptionsLayout::Linux => vec![
+                MaximumSegmentSize(Self::MSS),
+                SelectiveAcknowledgementPermitted,
+                Timestamp(timestamp, 0),
+                Noop,
+                WindowScale(7),
+            ],
+            TcpOptionsLayout::Bsd => vec![
+                MaximumSegmentSize(Self::MSS),
+                Noop,
+                WindowScale(6),
+                SelectiveAcknowledgementPermitted,
+                Timestamp(timestamp, 0),
+            ],
+            TcpOptionsLayout::Windows => vec![
+                MaximumSegmentSize(Self::MSS),
+                Noop,
+                WindowScale(8),
+                Noop,
+                Noop,
+                SelectiveAcknowledgementPermitted,
+            ],
+        }
+    }
+
+    pub fn window_size(&self) -> u16 {
+        match self {
+            TcpOptionsLayout::Linux | TcpOptionsLayout::Windows => 64240,
+            _ => u16::MAX,
+        }
+    }
+
+    /// Offset of the timestamp value from the start of the TCP options, if timestamps are sent
+    pub fn timestamp_offset(&self) -> Option<usize> {
+        match self {
+            // MSS (4) + SACK permitted (2) + timestamp kind and length (2)
+            TcpOptionsLayout::Linux => Some(8),
+            // MSS (4) + NOP (1) + window scale (3) + SACK permitted (2) + kind and length (2)
+            TcpOptionsLayout::Bsd => Some(12),
+            _ => None,
+        }
+    }
+
+    pub fn options_len(&self) -> usize {
+        make_syn_header(0, *self, 0).header_len() - TCP_HDR_SIZE
+    }
+}
+
+/// A SYN header carrying the options of the given layout
+pub fn make_syn_header(port: u16, layout: TcpOptionsLayout, timestamp: u32) -> TcpHeader {
+    let mut header = make_tcp_header(port);
+    header.window_size = layout.window_size();
+    header
+        .set_options(&layout.options(timestamp))
+        .expect("TCP options layouts fit in the header");
+    header
+}
+
+pub fn make_udp_header(port: u16) -> UdpHeader {
+    UdpHeader {
+        destination_port: port,
+        ..Default::default()
+    }
+}
+
+pub fn udp_checksum(udp_packet: &[u8], src_ip: u32, dst_ip: u32) -> u16 {
+    let mut sum = 0u64;
+
+    // Pseudo header
+    sum += (src_ip >> 16) as u64 + (src_ip & 0xFFFF) as u64;
+    sum += (dst_ip >> 16) as u64 + (dst_ip & 0xFFFF) as u64;
+    sum += udp_packet.len() as u64;
+    sum += 17u16 as u64;
+
+    // Payloads can have an odd length, in which case the last byte is padded with zero
+    for chunk in udp_packet.chunks(2) {
+        let word = match chunk {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+            [hi] => u16::from_be_bytes([*hi, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u64;
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xFFFF);
+    }
+
+    // A checksum of zero means no checksum was computed, so send all ones instead
+    match !sum as u16 {
+        0 => 0xFFFF,
+        checksum => checksum,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use etherparse::{IpHeaders, PacketBuilder, TcpOptions};
@@ -175,4 +339,77 @@ mod tests {
 
         assert_eq!(expected_checksum, actual_checksum);
     }
+
+    #[test]
+    fn test_tcp_options_layouts() {
+        for (name, len) in [
+            ("bare", 0),
+            ("smallest", 4),
+            ("linux", 20),
+            ("bsd", 20),
+            ("windows", 12),
+        ] {
+            let layout = TcpOptionsLayout::parse(name).unwrap();
+            assert_eq!(layout.options_len(), len, "{}", name);
+
+            // The timestamp value must be where we expect to overwrite it
+            let header = make_syn_header(443, layout, 0xdeadbeef);
+            if let Some(offset) = layout.timestamp_offset() {
+                assert_eq!(
+                    &header.options.as_slice()[offset..offset + 4],
+                    &[0xde, 0xad, 0xbe, 0xef]
+                );
+            }
+        }
+        assert!(TcpOptionsLayout::parse("solaris").is_err());
+    }
+
+    #[test]
+    fn test_udp_checksum() {
+        let mut ip_header = make_ip_header(IpNumber::UDP);
+        ip_header.source = IP_SRC;
+        ip_header.destination = IP_DEST;
+
+        // Odd payload length to exercise padding
+        let payload = b"zmap-rs";
+        let builder = PacketBuilder::ethernet2(MAC_SRC, MAC_DEST)
+            .ip(IpHeaders::Ipv4(ip_header, Default::default()))
+            .udp(47782, 53);
+
+        let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
+        builder.write(&mut result, payload).unwrap();
+
+        let expected_checksum = u16::from_be_bytes([result[40], result[41]]);
+        result[40..42].copy_from_slice(&[0, 0]);
+        let actual_checksum = udp_checksum(
+            &result[34..],
+            u32::from_be_bytes(IP_SRC),
+            u32::from_be_bytes(IP_DEST),
+        );
+
+        assert_eq!(expected_checksum, actual_checksum);
+    }
+
+    #[test]
+    fn test_tcp6_checksum() {
+        let source_ip: Ipv6Addr = "2001:db8::3".parse().unwrap();
+        let destination_ip: Ipv6Addr = "2001:db8:1::32".parse().unwrap();
+
+        let mut tcp_header = make_syn_header(443, TcpOptionsLayout::Linux, 0xdeadbeef);
+        tcp_header.source_port = 47782;
+        tcp_header.sequence_number = 2324566490;
+
+        let builder = PacketBuilder::ethernet2(MAC_SRC, MAC_DEST)
+            .ipv6(source_ip.octets(), destination_ip.octets(), MAXTTL)
+            .tcp_header(tcp_header);
+
+        let mut result = Vec::<u8>::with_capacity(builder.size(0));
+        builder.write(&mut result, &[]).unwrap();
+
+        let expected_checksum = u16::from_be_bytes([result[70], result[71]]);
+        result[70..72].copy_from_slice(&[0, 0]);
+        let actual_checksum = tcp6_checksum(&result[54..], &source_ip, &destination_ip);
+
+        assert_eq!(expected_checksum, actual_checksum);
+    }
 }
@@ -1,22 +1,264 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 use eui48::MacAddress;
 
+use crate::config::{Config, Context};
+use crate::net::bpf::Reply;
+use crate::probe_modules::module_dns::Dns;
+use crate::probe_modules::module_icmp_echoscan::IcmpEchoscan;
+use crate::probe_modules::module_ipv6_tcp_synscan::Ipv6TcpSynscan;
+use crate::probe_modules::module_tcp_ackscan::TcpAckscan;
+use crate::probe_modules::module_tcp_synscan::TcpSynscan;
+use crate::probe_modules::module_udp::Udp;
+
 pub trait ProbeGenerator {
     fn thread_initialize(
         &mut self,
         source_mac: &MacAddress,
         gateway_mac: &MacAddress,
-        source_ip: &Ipv4Addr,
+        source_ip: &IpAddr,
         source_port_first: u16,
         source_port_last: u16,
-        target_port: u16,
     );
 
     fn make_packet(
         &mut self,
-        destination_ip: &Ipv4Addr,
+        destination_ip: &IpAddr,
+        destination_port: u16,
         validation: &[u32],
         probe_num: u32,
     ) -> &[u8];
+
+    /// Build a probe into `buffer`, a buffer of the sender's batch or a frame of the transmit ring,
+    /// and return its length. By default the probe from make_packet is copied, generators that
+    /// can build their probes in place override this.
+    fn write_packet(
+        &mut self,
+        destination_ip: &IpAddr,
+        destination_port: u16,
+        validation: &[u32],
+        probe_num: u32,
+        buffer: &mut [u8],
+    ) -> usize {
+        let packet = self.make_packet(destination_ip, destination_port, validation, probe_num);
+        buffer[..packet.len()].copy_from_slice(packet);
+        packet.len()
+    }
+}
+
+/// Result of classifying a validated reply, e.g. a SYN-ACK ("synack") is a success but a RST is not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Classification {
+    pub success: bool,
+    pub name: &'static str,
+}
+
+impl Classification {
+    pub fn success(name: &'static str) -> Self {
+        Self {
+            success: true,
+            name,
+        }
+    }
+
+    pub fn failure(name: &'static str) -> Self {
+        Self {
+            success: false,
+            name,
+        }
+    }
+}
+
+/// Module specific data extracted from a validated reply, recorded alongside the result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    Int(u64),
+    Bool(bool),
+    Str(String),
+    Bytes(Vec<u8>),
+    Null,
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Int(value) => write!(f, "{}", value),
+            FieldValue::Bool(value) => write!(f, "{}", *value as u8),
+            FieldValue::Str(value) => write!(f, "{}", value),
+            FieldValue::Bytes(value) => {
+                for byte in value {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+            FieldValue::Null => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub value: FieldValue,
+}
+
+impl Field {
+    pub fn new(name: &'static str, value: FieldValue) -> Self {
+        Self { name, value }
+    }
+}
+
+/// A probe module describes a single scan type: what we send, which replies we capture, and how
+/// those replies are validated and classified. The sender and receiver only ever talk to the
+/// selected module through this trait.
+pub trait ProbeModule: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// Whether the module probes IPv6 targets read from --ipv6-target-file rather than the IPv4
+    /// address space
+    fn ipv6(&self) -> bool {
+        false
+    }
+
+    /// Called once before scanning starts, e.g. to parse `--probe-args`
+    fn global_initialize(&mut self, _config: &Config) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Replies to our probes, compiled into the kernel filter that captures them
+    fn replies(&self) -> &'static [Reply];
+
+    /// Length of a probe including the Ethernet header, used for bandwidth calculations
+    fn packet_length(&self) -> u64;
+
+    /// Create a per-thread generator that builds the probes for this module
+    fn make_generator(&self, config: &Config) -> Box<dyn ProbeGenerator>;
+
+    /// Check that a captured packet is a reply to one of our probes. `validation` is generated from
+    /// the packet's addresses; modules whose replies may come from a host other than the target
+    /// (e.g. ICMP errors) can revalidate and rewrite `src_ip` to the original target.
+    fn validate_packet(
+        &self,
+        packet: &[u8],
+        src_ip: &mut IpAddr,
+        validation: &[u32],
+        ctx: &Context,
+    ) -> bool;
+
+    /// Classify a validated reply as a success or failure
+    fn classify_packet(&self, packet: &[u8]) -> Classification;
+
+    /// Names of the fields returned by packet_fields, which can be selected with --output-fields
+    fn fields(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Extract module specific fields from a validated reply
+    fn packet_fields(&self, _packet: &[u8]) -> Vec<Field> {
+        vec![]
+    }
+
+    /// Print a probe in human readable form (used in dryrun mode)
+    fn print_packet(&self, packet: &[u8]);
+}
+
+impl std::fmt::Debug for dyn ProbeModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ProbeModule {{ name: {} }}", self.name())
+    }
+}
+
+// IPv4 probe modules are only ever handed IPv4 addresses, see ProbeModule::ipv6
+pub fn expect_ipv4(ip: &IpAddr) -> Ipv4Addr {
+    match ip {
+        IpAddr::V4(ip) => *ip,
+        IpAddr::V6(ip) => panic!("IPv4 probe module given IPv6 address {}", ip),
+    }
+}
+
+// Source ports are derived from the validation so that replies can be checked statelessly
+pub fn get_src_port(
+    source_port_first: u16,
+    source_port_last: u16,
+    validation: &[u32],
+    probe_num: u32,
+) -> u16 {
+    let num_ports = (source_port_last - source_port_first + 1) as u32;
+    source_port_first + (validation[1].wrapping_add(probe_num) % num_ports) as u16
+}
+
+// Return false if dst port is outside the expected valid range
+pub fn check_dst_port(port: u16, validation: &[u32], config: &Config) -> bool {
+    if port > config.source_port_last || port < config.source_port_first {
+        return false;
+    }
+
+    let num_ports = (config.source_port_last - config.source_port_first + 1) as u32;
+    let to_validate = (port - config.source_port_first) as u32;
+    let min = validation[1] % num_ports;
+    let max = validation[1].wrapping_add(config.probes - 1) % num_ports;
+
+    // Compare offsets from min modulo num_ports, as the range of valid ports may wrap around
+    (max + num_ports - min) % num_ports >= (to_validate + num_ports - min) % num_ports
+}
+
+/// All probe modules known to zmap-rs. New scan types only need to be added here.
+pub fn probe_modules() -> Vec<Box<dyn ProbeModule>> {
+    vec![
+        Box::new(TcpSynscan::default()),
+        Box::new(TcpAckscan),
+        Box::new(IcmpEchoscan),
+        Box::new(Udp::default()),
+        Box::new(Dns::default()),
+        Box::new(Ipv6TcpSynscan::default()),
+    ]
+}
+
+pub fn get_probe_module(name: &str) -> Option<Box<dyn ProbeModule>> {
+    probe_modules().into_iter().find(|m| m.name() == name)
+}
+
+pub fn print_probe_modules() {
+    for module in probe_modules() {
+        println!("{:<16} {}", module.name(), module.description());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_get_probe_module() {
+        let module = get_probe_module("tcp_synscan").unwrap();
+        assert_eq!(module.name(), "tcp_synscan");
+        assert!(get_probe_module("no_such_module").is_none());
+    }
+
+    #[test]
+    fn test_check_dst_port() {
+        let config = Config::parse_from([
+            "zmap-rs",
+            "--probes",
+            "3",
+            "--source-port-first",
+            "1000",
+            "--source-port-last",
+            "1009",
+        ]);
+
+        // The valid range of source ports wraps around the end of the port range
+        let validation = [0, 8];
+        for probe_num in 0..3 {
+            let port = get_src_port(1000, 1009, &validation, probe_num);
+            assert!(check_dst_port(port, &validation, &config));
+        }
+        assert!(!check_dst_port(1001, &validation, &config));
+        assert!(!check_dst_port(1007, &validation, &config));
+        assert!(!check_dst_port(999, &validation, &config));
+    }
 }
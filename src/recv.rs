@@ -1,55 +1,256 @@
-use std::{cell::RefCell, fs::File, io::Write, net::Ipv4Addr, time::Instant};
+use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 
-use etherparse::{NetSlice, SlicedPacket};
+use chrono::{DateTime, SecondsFormat, Utc};
+use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+use libc::sock_filter;
 use log::debug;
 
-use crate::config::Context;
+use crate::config::{Context, RecvBackend};
+use crate::lib::seen_set::SeenSet;
 use crate::lib::validate;
+use crate::net::bpf::{self, Destination};
+#[cfg(feature = "pcap")]
 use crate::net::pcap::*;
-use crate::probe_modules::module_tcp_synscan::{synscan_classify_packet, synscan_validate_packet};
+use crate::net::pcap_file::PcapFile;
+use crate::net::rx_ring::{CaptureStats, RxRing};
+use crate::output_modules::registry::{
+    get_output_module, header_fields, select_fields, OutputModule,
+};
+use crate::probe_modules::probe_modules::{Classification, Field, FieldValue};
 
+/// Captures replies with the selected --recv-backend, or reads them back with --replay-pcap
+enum Capture {
+    #[cfg(feature = "pcap")]
+    Pcap(PacketCapture),
+    RxRing(RxRing),
+    Replay {
+        file: PcapFile<BufReader<File>>,
+        // Applied in user space, as the kernel would have when capturing
+        filter: Vec<sock_filter>,
+        stats: CaptureStats,
+        finished: bool,
+    },
+}
+
+impl Capture {
+    // Longest wait for a block, so the receiver still notices when the scan is over
+    const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn new(ctx: &Context) -> Self {
+        let filter = reply_filter(ctx);
+        if let Some(file) = &ctx.config.replay_pcap {
+            return Self::Replay {
+                file: PcapFile::open(file).expect("Could not open --replay-pcap file"),
+                filter,
+                stats: CaptureStats::default(),
+                finished: false,
+            };
+        }
+
+        let interface = &ctx.config.interface;
+        match ctx.config.recv_backend {
+            #[cfg(feature = "pcap")]
+            RecvBackend::Pcap => Self::Pcap(PacketCapture::new(interface).with_filter(&filter)),
+            RecvBackend::RxRing => {
+                let ring = RxRing::new(interface, &filter)
+                    .expect("Failed to set up receive ring, are you running as root?");
+                // The first receiver thread starts the fanout group that the others join
+                if ctx.config.receiver_threads > 1 {
+                    let mut zrecv = ctx.receiver_state.lock().unwrap();
+                    let group = ring
+                        .join_fanout(zrecv.fanout_group)
+                        .expect("Could not join the receive fanout group");
+                    zrecv.fanout_group = Some(group);
+                }
+                Self::RxRing(ring)
+            }
+        }
+    }
+
+    /// Hand the next captured packets to `process` along with when they were received, returns
+    /// whether there were any
+    fn next_packets(&mut self, mut process: impl FnMut(&[u8], DateTime<Utc>)) -> bool {
+        match self {
+            #[cfg(feature = "pcap")]
+            Self::Pcap(pcap) => match pcap.next_packet() {
+                Some(packet) => {
+                    process(packet.data, Utc::now());
+                    true
+                }
+                None => false,
+            },
+            Self::RxRing(ring) => match ring
+                .next_block(Self::POLL_TIMEOUT)
+                .expect("Could not read from receive ring")
+            {
+                Some(block) => {
+                    block
+                        .packets()
+                        .for_each(|packet| process(packet, Utc::now()));
+                    true
+                }
+                None => false,
+            },
+            Self::Replay {
+                file,
+                filter,
+                stats,
+                finished,
+            } => match file
+                .next_packet()
+                .expect("Could not read --replay-pcap file")
+            {
+                Some(record) => {
+                    stats.recv += 1;
+                    let len = bpf::run(filter, &record.data) as usize;
+                    if len > 0 {
+                        let packet = &record.data[..len.min(record.data.len())];
+                        process(packet, (UNIX_EPOCH + record.timestamp).into());
+                    }
+                    true
+                }
+                None => {
+                    *finished = true;
+                    false
+                }
+            },
+        }
+    }
+
+    /// Whether there is nothing left to capture, i.e. the end of a replayed file was reached
+    fn finished(&self) -> bool {
+        matches!(self, Self::Replay { finished: true, .. })
+    }
+
+    fn stats(&mut self) -> CaptureStats {
+        match self {
+            #[cfg(feature = "pcap")]
+            Self::Pcap(pcap) => {
+                let pcap_stats = pcap.stats();
+                CaptureStats {
+                    recv: pcap_stats.ps_recv,
+                    drop: pcap_stats.ps_drop,
+                    ifdrop: pcap_stats.ps_ifdrop,
+                }
+            }
+            Self::RxRing(ring) => ring
+                .stats()
+                .expect("Could not read receive ring statistics"),
+            Self::Replay { stats, .. } => *stats,
+        }
+    }
+}
+
+// Kernel filter for replies to our probes, addressed to our source addresses and ports
+fn reply_filter(ctx: &Context) -> Vec<sock_filter> {
+    let config = &ctx.config;
+    let destination = match ctx.source_ip() {
+        IpAddr::V6(ip) => Destination::Ipv6(ip),
+        IpAddr::V4(_) => Destination::Ipv4 {
+            first: config.source_ip_first,
+            last: config.source_ip_last,
+        },
+    };
+    bpf::compile(
+        ctx.probe_module.replies(),
+        &destination,
+        config.source_port_first..=config.source_port_last,
+    )
+}
+
+struct Output {
+    file: File,
+    module: Box<dyn OutputModule>,
+}
+
+/// Targets seen and results written so far, shared by all receiver threads
+pub struct Results {
+    seen: SeenSet,
+    output: Mutex<Output>,
+    output_fields: Vec<&'static str>,
+}
+
+impl Results {
+    pub fn new(ctx: &Context) -> Self {
+        let seen = SeenSet::new(ctx.config.target_ports().len() == 1);
+        let mut output_file = File::create(&ctx.config.output_file).unwrap();
+
+        // The output fields were already checked when creating the context
+        let output_fields = select_fields(&ctx.config.output_fields, ctx.probe_module.as_ref())
+            .expect("Invalid output fields");
+        let mut output_module = get_output_module(&ctx.config.output_module).unwrap();
+        output_module
+            .start(&mut output_file, &output_fields)
+            .expect("Could not write to output file");
+
+        Self {
+            seen,
+            output: Mutex::new(Output {
+                file: output_file,
+                module: output_module,
+            }),
+            output_fields,
+        }
+    }
+}
+
+/// Receives replies on one of the --receiver-threads
 pub struct Receiver {
     ctx: Context,
-    pcap: PacketCapture,
-    seen_ips: RefCell<Vec<u64>>, // TODO: writeup
-    output_file: RefCell<File>,
+    capture: RefCell<Capture>,
+    // Capture counters already added to the receiver state
+    capture_stats: Cell<CaptureStats>,
+    results: Arc<Results>,
 }
 
 impl Receiver {
-    const SEEN_IPS_SIZE: usize = 0x4000000;
-
-    pub fn new(filter: &str, ctx: Context) -> Self {
-        let pcap = PacketCapture::new(&ctx.config.interface).with_filter(filter);
-        let seen_ips = RefCell::new(vec![0; Self::SEEN_IPS_SIZE]);
-        let output_file = RefCell::new(File::create(&ctx.config.output_file).unwrap());
+    pub fn new(ctx: Context, results: Arc<Results>) -> Self {
+        let capture = Capture::new(&ctx);
         Self {
             ctx,
-            pcap,
-            seen_ips,
-            output_file,
+            capture: RefCell::new(capture),
+            capture_stats: Cell::new(CaptureStats::default()),
+            results,
         }
     }
 
     pub fn run(&self) {
         debug!("Receiver thread started");
 
-        // Signal to main thread that receiver thread is ready to go
+        // Signal to main thread once every receiver thread is ready to go
         let mut zrecv = self.ctx.receiver_state.lock().unwrap();
-        zrecv.ready = true;
-        zrecv.start = Instant::now();
+        zrecv.ready_threads += 1;
+        if zrecv.ready_threads == self.ctx.config.receiver_threads {
+            zrecv.ready = true;
+            zrecv.start = Instant::now();
+        }
         drop(zrecv);
 
         loop {
-            if let Some(packet) = self.pcap.next_packet() {
-                self.process_packet(&packet);
+            let mut capture = self.capture.borrow_mut();
+            let received =
+                capture.next_packets(|packet, timestamp| self.process_packet(packet, timestamp));
+            let finished = capture.finished();
+            drop(capture);
+            if received {
                 self.update_pcap_stats();
             }
+            if finished {
+                break;
+            }
 
             let zrecv = self.ctx.receiver_state.lock().unwrap();
             if self.ctx.config.max_results > 0
                 && zrecv.success_unique >= self.ctx.config.max_results
             {
-                self.ctx.sender_state.lock().unwrap().complete = true;
+                self.ctx.sender_threads.stop.store(true, Ordering::Relaxed);
                 break;
             }
             drop(zrecv);
@@ -61,29 +262,40 @@ impl Receiver {
             drop(zsend);
         }
 
+        self.update_pcap_stats();
+
         let mut zrecv = self.ctx.receiver_state.lock().unwrap();
-        zrecv.finish = Instant::now();
-        zrecv.complete = true;
+        zrecv.finished_threads += 1;
+        if zrecv.finished_threads == self.ctx.config.receiver_threads {
+            zrecv.finish = Instant::now();
+            zrecv.complete = true;
+        }
         drop(zrecv);
 
-        self.update_pcap_stats();
         debug!("Receiver finished");
     }
 
+    // Every thread captures its own share of the packets, so their counters add up
     fn update_pcap_stats(&self) {
-        let pcap_stats = self.pcap.stats();
+        let stats = self.capture.borrow_mut().stats();
+        let last = self.capture_stats.replace(stats);
         let mut zrecv = self.ctx.receiver_state.lock().unwrap();
-        zrecv.pcap_recv = pcap_stats.ps_recv;
-        zrecv.pcap_drop = pcap_stats.ps_drop;
-        zrecv.pcap_ifdrop = pcap_stats.ps_ifdrop;
+        zrecv.pcap_recv = zrecv
+            .pcap_recv
+            .wrapping_add(stats.recv.wrapping_sub(last.recv));
+        zrecv.pcap_drop = zrecv
+            .pcap_drop
+            .wrapping_add(stats.drop.wrapping_sub(last.drop));
+        // Except for the interface's drops, which every thread sees
+        zrecv.pcap_ifdrop = zrecv.pcap_ifdrop.max(stats.ifdrop);
     }
 
-    fn process_packet(&self, packet: &Packet) {
+    fn process_packet(&self, packet: &[u8], timestamp: DateTime<Utc>) {
         if self.ctx.receiver_state.lock().unwrap().success_unique >= self.ctx.config.max_results {
             return;
         }
 
-        let sliced_packet = match SlicedPacket::from_ethernet(packet.data) {
+        let sliced_packet = match SlicedPacket::from_ethernet(packet) {
             Ok(p) => p,
             Err(_) => {
                 debug!("Could not parse Ethernet packet");
@@ -91,41 +303,60 @@ impl Receiver {
             }
         };
         
-        let ip_header = match &sliced_packet.net {
-            Some(NetSlice::Ipv4(slice)) => slice.header(),
+        let (mut src_ip, dst_ip): (IpAddr, IpAddr) = match &sliced_packet.net {
+            Some(NetSlice::Ipv4(slice)) => (
+                slice.header().source_addr().into(),
+                slice.header().destination_addr().into(),
+            ),
+            Some(NetSlice::Ipv6(slice)) => (
+                slice.header().source_addr().into(),
+                slice.header().destination_addr().into(),
+            ),
             _ => {
                 debug!("Could not unpack network slice");
                 return;
             }
         };
 
-        let src_ip = ip_header.source_addr();
-        let dst_ip = ip_header.destination_addr();
-        let validation = validate::gen(&self.ctx.validate_ctx, &dst_ip, &src_ip);
-        let validation = [
-            u32::from_be_bytes(validation[0..4].try_into().unwrap()),
-            u32::from_be_bytes(validation[4..8].try_into().unwrap()),
-        ];
+        // Replies come from the probed port, except for ICMP which has no ports
+        let src_port = match &sliced_packet.transport {
+            Some(TransportSlice::Tcp(tcp)) => tcp.source_port(),
+            Some(TransportSlice::Udp(udp)) => udp.source_port(),
+            _ => 0,
+        };
 
-        if !synscan_validate_packet(packet.data, &validation, &self.ctx.config) {
+        // E.g. an IPv4 reply that reached an IPv6 scan
+        let Some(validation) = validate::gen_words(&self.ctx.validate_ctx, &dst_ip, &src_ip) else {
+            debug!("Reply between different address families");
+            return;
+        };
+
+        if !self
+            .ctx
+            .probe_module
+            .validate_packet(packet, &mut src_ip, &validation, &self.ctx)
+        {
             debug!("Validation for probe reply failed");
             return;
         }
 
+        let classification = self.ctx.probe_module.classify_packet(packet);
+        // Only successes mark a target as seen, checked and marked at once so that two threads
+        // can't both report the same target
+        let is_repeat = if classification.success {
+            !self.results.seen.insert(src_ip, src_port)
+        } else {
+            self.results.seen.contains(src_ip, src_port)
+        };
+        let cooldown = self.ctx.sender_state.lock().unwrap().complete;
         let mut zrecv = self.ctx.receiver_state.lock().unwrap();
-        if synscan_classify_packet(packet.data) {
+        if classification.success {
             zrecv.success_total += 1;
-
-            let is_repeat = self.check_ip(src_ip);
             if !is_repeat {
                 zrecv.success_unique += 1;
-                self.set_ip(src_ip);
-
-                // Simple file output - add to writeup about cleaner implementation
-                writeln!(self.output_file.borrow_mut(), "{}", src_ip.to_string()).unwrap();
             }
 
-            if self.ctx.sender_state.lock().unwrap().complete {
+            if cooldown {
                 zrecv.cooldown_total += 1;
                 if !is_repeat {
                     zrecv.cooldown_unique += 1;
@@ -134,15 +365,192 @@ impl Receiver {
         } else {
             zrecv.failure_total += 1;
         }
+        drop(zrecv);
+
+        // Failures can be informative too (e.g. which hosts are unreachable), but are only written
+        // when asked for
+        let output = if classification.success {
+            !is_repeat
+        } else {
+            self.ctx.config.output_unsuccessful
+        };
+        if output {
+            self.write_result(
+                src_ip,
+                classification,
+                is_repeat,
+                cooldown,
+                packet,
+                timestamp,
+            );
+        }
     }
 
-    fn check_ip(&self, ip: Ipv4Addr) -> bool {
-        let ip: u32 = ip.into();
-        return ((self.seen_ips.borrow()[(ip >> 6) as usize] >> (ip & 0x3F)) & 1) != 0;
+    fn write_result(
+        &self,
+        src_ip: IpAddr,
+        classification: Classification,
+        repeat: bool,
+        cooldown: bool,
+        packet: &[u8],
+        timestamp: DateTime<Utc>,
+    ) {
+        let mut fields = header_fields(packet, &src_ip);
+        fields.extend([
+            Field::new(
+                "classification",
+                FieldValue::Str(classification.name.to_string()),
+            ),
+            Field::new("success", FieldValue::Bool(classification.success)),
+            Field::new("repeat", FieldValue::Bool(repeat)),
+            Field::new("cooldown", FieldValue::Bool(cooldown)),
+            Field::new(
+                "timestamp",
+                FieldValue::Str(timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            ),
+        ]);
+        fields.extend(self.ctx.probe_module.packet_fields(packet));
+
+        // Fields this reply doesn't have (e.g. the sequence number of an ICMP reply) are left empty
+        let selected: Vec<Field> = self
+            .results
+            .output_fields
+            .iter()
+            .map(|name| {
+                fields
+                    .iter()
+                    .find(|field| field.name == *name)
+                    .cloned()
+                    .unwrap_or_else(|| Field::new(name, FieldValue::Null))
+            })
+            .collect();
+
+        let mut output = self.results.output.lock().unwrap();
+        let Output { file, module } = &mut *output;
+        module.process_result(file, &selected).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+
+    use clap::Parser;
+    use etherparse::PacketBuilder;
+
+    use crate::config::Config;
+    use crate::probe_modules::probe_modules::{get_probe_module, get_src_port};
+
+    const OURS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    // Reply from `target` to the probe we sent it, or with a wrong acknowledgement number
+    fn reply(
+        ctx: &Context,
+        target: Ipv4Addr,
+        destination: Ipv4Addr,
+        rst: bool,
+        valid: bool,
+    ) -> Vec<u8> {
+        let validation =
+            validate::gen_words(&ctx.validate_ctx, &OURS.into(), &target.into()).unwrap();
+        let port = get_src_port(32768, 61000, &validation, 0);
+        let ack = validation[0].wrapping_add(if valid { 1 } else { 2 });
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(target.octets(), destination.octets(), 64)
+            .tcp(80, port, 1, 1024);
+        let builder = if rst { builder.rst() } else { builder.syn() };
+        let mut packet = Vec::new();
+        builder.ack(ack).write(&mut packet, &[]).unwrap();
+        packet
+    }
+
+    // Replay of `pcap` for a port 80 scan from OURS
+    fn replay_config(pcap: &Path, output: &Path, options: &[&str]) -> Config {
+        let mut args = vec![
+            "zmap-rs",
+            "-p",
+            "80",
+            "--seed",
+            "7",
+            "--seed-validation",
+            "--source-ip-first",
+            "10.0.0.2",
+            "--source-ip-last",
+            "10.0.0.2",
+            "--replay-pcap",
+            pcap.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "-O",
+            "csv",
+            "-f",
+            "saddr,classification,timestamp",
+        ];
+        args.extend(options);
+        Config::parse_from(args)
     }
 
-    fn set_ip(&self, ip: Ipv4Addr) {
-        let ip: u32 = ip.into();
-        self.seen_ips.borrow_mut()[(ip >> 6) as usize] |= 1u64 << (ip & 0x3F);
+    #[test]
+    fn test_receiver_replay() {
+        let dir = std::env::temp_dir();
+        let pcap = dir.join(format!("zmap-rs-replay-{}.pcap", std::process::id()));
+        let output = dir.join(format!("zmap-rs-replay-{}.csv", std::process::id()));
+        let config = replay_config(&pcap, &output, &[]);
+        let ctx = Context::new(config, get_probe_module("tcp_synscan").unwrap());
+
+        let target = |last| Ipv4Addr::new(192, 0, 2, last);
+        let packets = [
+            reply(&ctx, target(1), OURS, false, true),
+            // Repeats are counted but not written
+            reply(&ctx, target(1), OURS, false, true),
+            // Fails validation
+            reply(&ctx, target(2), OURS, false, false),
+            // Filtered, as it isn't addressed to us
+            reply(&ctx, target(3), Ipv4Addr::new(10, 0, 0, 9), false, true),
+            reply(&ctx, target(4), OURS, true, true),
+        ];
+        let mut file = Vec::new();
+        file.extend(0xa1b2c3d4u32.to_le_bytes());
+        file.extend([2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 1, 0, 0, 0]);
+        for (seconds, packet) in (1u32..).zip(&packets) {
+            file.extend(seconds.to_le_bytes());
+            file.extend(250_000u32.to_le_bytes());
+            file.extend((packet.len() as u32).to_le_bytes());
+            file.extend((packet.len() as u32).to_le_bytes());
+            file.extend(packet);
+        }
+        std::fs::write(&pcap, file).unwrap();
+
+        Receiver::new(ctx.clone(), Arc::new(Results::new(&ctx))).run();
+        let results = std::fs::read_to_string(&output).unwrap();
+
+        // The RST is counted as a failure, but not written
+        assert_eq!(
+            results,
+            "saddr,classification,timestamp\n\
+             192.0.2.1,synack,1970-01-01T00:00:01.250Z\n"
+        );
+        let zrecv = ctx.receiver_state.lock().unwrap();
+        assert_eq!(zrecv.pcap_recv, 5);
+        assert_eq!(zrecv.success_total, 2);
+        assert_eq!(zrecv.success_unique, 1);
+        assert_eq!(zrecv.failure_total, 1);
+        drop(zrecv);
+
+        let config = replay_config(&pcap, &output, &["--output-unsuccessful"]);
+        let ctx = Context::new(config, get_probe_module("tcp_synscan").unwrap());
+        Receiver::new(ctx.clone(), Arc::new(Results::new(&ctx))).run();
+        let results = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&pcap).unwrap();
+        std::fs::remove_file(&output).unwrap();
+
+        assert_eq!(
+            results,
+            "saddr,classification,timestamp\n\
+             192.0.2.1,synack,1970-01-01T00:00:01.250Z\n\
+             192.0.2.4,rst,1970-01-01T00:00:05.250Z\n"
+        );
     }
 }
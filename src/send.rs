@@ -1,57 +1,235 @@
-use std::net::Ipv4Addr;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+use eui48::MacAddress;
 use log::{debug, info, warn};
 
-use crate::config::Context;
-use crate::crypto::Cyclic;
+use crate::config::{Config, Context, SendBackend};
+use crate::crypto::{shard_size, Cyclic};
 use crate::lib::blacklist::Blacklist;
+use crate::lib::ipv6_target_file::Ipv6TargetFile;
 use crate::lib::validate;
-use crate::net::socket::RawEthSocket;
+use crate::net::socket::{PacketBatch, RawEthSocket};
+use crate::net::tx_ring::TxRing;
 use crate::net::{get_interface_index, get_interface_mac};
-use crate::probe_modules::module_tcp_synscan::{
-    synscan_print_packet, NaiveProbeGenerator, PrecomputedProbeGenerator,
-};
-use crate::probe_modules::probe_modules::{self, ProbeGenerator};
+use crate::probe_modules::probe_modules::ProbeGenerator;
+use crate::state::SenderThreadState;
+
+pub type Ipv6Targets = Arc<Mutex<Ipv6TargetFile<BufReader<File>>>>;
+
+/// Hands probes to the kernel a batch at a time, with the selected --send-backend
+enum Transmitter {
+    Socket {
+        socket: RawEthSocket,
+        batch: PacketBatch,
+        // Target of each probe in the batch, for error messages
+        targets: Vec<(IpAddr, u16)>,
+        interface_index: i32,
+        gateway_mac: MacAddress,
+    },
+    TxRing {
+        ring: TxRing,
+        batch_size: usize,
+        // Frames written since the last flush
+        queued: usize,
+    },
+}
+
+impl Transmitter {
+    fn new(config: &Config, interface_index: i32, gateway_mac: MacAddress) -> Self {
+        let batch_size = config.batch as usize;
+        match config.send_backend {
+            SendBackend::Socket => Self::Socket {
+                socket: RawEthSocket::new(),
+                batch: PacketBatch::new(batch_size),
+                targets: Vec::with_capacity(batch_size),
+                interface_index,
+                gateway_mac,
+            },
+            SendBackend::TxRing => Self::TxRing {
+                ring: TxRing::new(interface_index)
+                    .expect("Failed to set up transmit ring, are you running as root?"),
+                batch_size,
+                queued: 0,
+            },
+        }
+    }
+
+    fn queue(
+        &mut self,
+        probe_generator: &mut dyn ProbeGenerator,
+        (destination_ip, destination_port): (IpAddr, u16),
+        validation: &[u32],
+        probe_num: u32,
+        state: &SenderThreadState,
+    ) {
+        match self {
+            Self::Socket { batch, targets, .. } => {
+                batch.push_with(|buffer| {
+                    probe_generator.write_packet(
+                        &destination_ip,
+                        destination_port,
+                        validation,
+                        probe_num,
+                        buffer,
+                    )
+                });
+                targets.push((destination_ip, destination_port));
+                if batch.is_full() {
+                    self.flush(state);
+                }
+            }
+            Self::TxRing {
+                ring,
+                batch_size,
+                queued,
+            } => {
+                let (frame, wait) = ring.next_frame();
+                let len = probe_generator.write_packet(
+                    &destination_ip,
+                    destination_port,
+                    validation,
+                    probe_num,
+                    frame,
+                );
+                ring.commit(len);
+                if wait.ring_full {
+                    state.ring_full.fetch_add(1, Ordering::Relaxed);
+                }
+                if wait.failed {
+                    warn!("Sender transmit ring rejected a probe");
+                    state.sendto_failures.fetch_add(1, Ordering::Relaxed);
+                }
+
+                *queued += 1;
+                if *queued == *batch_size {
+                    self.flush(state);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self, state: &SenderThreadState) {
+        match self {
+            Self::Socket {
+                socket,
+                batch,
+                targets,
+                interface_index,
+                gateway_mac,
+            } => {
+                if batch.is_empty() {
+                    return;
+                }
+                for (i, e) in socket.send_batch(batch, *interface_index, gateway_mac) {
+                    let (destination_ip, destination_port) = targets[i];
+                    warn!(
+                        "Sender sendto failed for {destination_ip}:{destination_port}. Reason: {}",
+                        e
+                    );
+                    state.sendto_failures.fetch_add(1, Ordering::Relaxed);
+                }
+                batch.clear();
+                targets.clear();
+            }
+            Self::TxRing { ring, queued, .. } => {
+                if *queued == 0 {
+                    return;
+                }
+                // Frames stay queued if the kernel can't take them yet, and are sent with the
+                // next flush
+                if let Err(e) = ring.flush() {
+                    warn!("Sender transmit ring flush failed. Reason: {}", e);
+                }
+                *queued = 0;
+            }
+        }
+    }
+}
 
 pub struct Sender {
     ctx: Context,
-    cyclic: Arc<Mutex<Cyclic>>,
+    // Index of this sender thread, which walks its own sub-shard of the permutation
+    thread: usize,
+    cyclic: Cyclic,
     blacklist: Blacklist,
+    ipv6_targets: Option<Ipv6Targets>,
+    // This thread's part of --max-targets
+    max_targets: u64,
 }
 
 impl Sender {
-    pub fn new(ctx: Context, cyclic: Arc<Mutex<Cyclic>>, blacklist: Blacklist) -> Self {
-        let mut zsend = ctx.sender_state.lock().unwrap();
+    pub fn new(ctx: Context, thread: usize, blacklist: Blacklist) -> Self {
+        let seed = ctx.config.seed.unwrap();
+        let num_threads = ctx.config.sender_threads as u32;
+
+        // Thread t of shard s walks sub-shard t * shards + s, so the threads of a shard together
+        // visit exactly the shard's targets, whatever the number of threads
+        let sub_shard = thread as u32 * ctx.config.shards + ctx.config.shard;
+        let num_sub_shards = num_threads * ctx.config.shards;
+        let mut cyclic = Cyclic::new(seed, ctx.num_targets()).with_shard(sub_shard, num_sub_shards);
+        if let Some(checkpoint) = &ctx.checkpoint {
+            let position = checkpoint.threads[thread];
+            cyclic = cyclic.with_position(position.current, position.remaining);
+        }
+        let (current, remaining) = cyclic.position();
+        let state = &ctx.sender_threads.threads[thread];
+        state.position.store(current, remaining);
+
+        // IPv6 targets are read from a file shared by all threads of the scan rather than sharded
+        let max_targets = ctx.max_targets();
+        let ipv6 = ctx.probe_module.ipv6();
+        let sender = Self {
+            max_targets: if ipv6 {
+                shard_size(max_targets, thread as u32, num_threads)
+            } else {
+                shard_size(max_targets, sub_shard, num_sub_shards)
+            },
+            ctx,
+            thread,
+            cyclic,
+            blacklist,
+            ipv6_targets: None,
+        };
 
-        // If we've already initialized the senders, just return
-        if zsend.first_scanned != Ipv4Addr::new(0, 0, 0, 0) {
+        // The first sender thread initializes the shared state
+        let ctx = &sender.ctx;
+        let mut zsend = ctx.sender_state.lock().unwrap();
+        if zsend.initialized {
             drop(zsend);
-            return Self {
-                ctx,
-                cyclic,
-                blacklist,
-            };
+            return sender;
         }
 
-        // Advance past any blacklisted addresses
-        zsend.first_scanned = cyclic.lock().unwrap().current_ip();
-        while !blacklist.is_allowed(zsend.first_scanned) {
-            zsend.blacklisted += 1;
-            zsend.first_scanned = cyclic.lock().unwrap().next_ip();
+        if let Some(checkpoint) = &ctx.checkpoint {
+            info!(
+                "Resuming scan after {} addresses from {}",
+                checkpoint.sent(),
+                ctx.checkpoint_file().unwrap()
+            );
+            checkpoint.restore(&mut zsend, &ctx.sender_threads);
+        } else if !ipv6 {
+            // The first target of the whole shard is the first one of thread 0
+            let index = Cyclic::new(seed, ctx.num_targets())
+                .with_shard(ctx.config.shard, ctx.config.shards)
+                .next_index();
+            if let Some(index) = index {
+                zsend.first_scanned = ctx.address(&sender.blacklist, index % ctx.num_addresses);
+            }
+            zsend.blacklisted = sender.blacklist.count_not_allowed();
         }
 
-        let allowed = blacklist.count_allowed();
-        if allowed == (1u64 << 32) {
-            zsend.targets = u32::MAX;
+        // Each shard scans its share of the allowed (address, port) targets, while IPv6 probe
+        // modules scan the whole target file
+        zsend.targets = if ipv6 {
+            max_targets
         } else {
-            zsend.targets = allowed as u32;
-        }
-
-        if zsend.targets > ctx.config.max_targets {
-            zsend.targets = ctx.config.max_targets;
-        }
+            let shard_targets = shard_size(ctx.num_targets(), ctx.config.shard, ctx.config.shards);
+            shard_targets.min(shard_size(max_targets, ctx.config.shard, ctx.config.shards))
+        };
 
         assert!(
             ctx.config.source_ip_first == ctx.config.source_ip_last,
@@ -68,12 +246,49 @@ impl Sender {
         }
 
         zsend.start = Instant::now();
+        zsend.initialized = true;
         drop(zsend);
 
-        Self {
-            ctx,
-            cyclic,
-            blacklist,
+        sender
+    }
+
+    /// Probe the IPv6 targets read from a file (shared between sender threads) instead of walking
+    /// the IPv4 address space
+    pub fn with_ipv6_targets(mut self, targets: Ipv6Targets) -> Self {
+        self.ipv6_targets = Some(targets);
+        self
+    }
+
+    // Returns the next address and port to probe, or None once there are no targets left
+    fn next_target(&mut self) -> Option<(IpAddr, u16)> {
+        let target_ports = self.ctx.config.target_ports();
+        if let Some(targets) = &self.ipv6_targets {
+            let ip = targets.lock().unwrap().next()?;
+            return Some((ip.into(), target_ports[0]));
+        }
+
+        // The cyclic only yields indexes of allowed targets, and runs out once every target of
+        // this thread has been visited. Consecutive indexes are the same port on consecutive
+        // addresses.
+        let index = self.cyclic.next_index()?;
+        // Published as soon as the target is queued, see Checkpoint for what a crash can skip
+        let (current, remaining) = self.cyclic.position();
+        let state = &self.ctx.sender_threads.threads[self.thread];
+        state.position.store(current, remaining);
+
+        let num_addresses = self.ctx.num_addresses;
+        let destination_ip = self.ctx.address(&self.blacklist, index % num_addresses);
+        let port_index = (index / num_addresses) as usize;
+        Some((destination_ip.into(), target_ports[port_index]))
+    }
+
+    // The scan is complete once the last sender thread finishes
+    fn finish(&self) {
+        let mut zsend = self.ctx.sender_state.lock().unwrap();
+        zsend.finished_threads += 1;
+        if zsend.finished_threads == self.ctx.config.sender_threads as u32 {
+            zsend.complete = true;
+            zsend.finish = Instant::now();
         }
     }
 
@@ -81,134 +296,90 @@ impl Sender {
         debug!("Sender thread started and running");
         let zsend = self.ctx.sender_state.lock().unwrap();
 
-        let mut probe_module: Box<dyn ProbeGenerator> = if self.ctx.config.naive_probes {
-            debug!("Using naive probe generator");
-            Box::new(NaiveProbeGenerator::default())
-        } else {
-            debug!("Using optimized probe generator");
-            Box::new(PrecomputedProbeGenerator::default())
-        };
+        let mut probe_generator = self.ctx.probe_module.make_generator(&self.ctx.config);
 
-        let socket = RawEthSocket::new();
         let interface_index = get_interface_index(&self.ctx.config.interface).unwrap();
         let source_mac = get_interface_mac(&self.ctx.config.interface).unwrap();
         let gateway_mac = self.ctx.config.gw_mac;
+        let source_ip = self.ctx.source_ip();
 
         // We don't currently cache packets, so this is a no-op
-        probe_module.thread_initialize(
+        probe_generator.thread_initialize(
             &source_mac,
             &gateway_mac,
-            &self.ctx.config.source_ip_first,
+            &source_ip,
             self.ctx.config.source_port_first,
             self.ctx.config.source_port_last,
-            self.ctx.config.target_port,
         );
+        let start = zsend.start;
         drop(zsend);
 
-        let mut count: u32 = 0;
-        let mut last_count = count;
-        let mut last_time = Instant::now();
-        let mut delay: f64 = 0.0;
-        let mut interval: u32 = 0;
-
-        if self.ctx.config.rate > 0 {
-            // Estimate initial rate
-            delay = 10000.0;
-            for _ in 0..delay as u32 {
-                std::hint::spin_loop();
-            }
-
-            let duration = (Instant::now() - last_time).as_secs_f64();
-            delay *=
-                (1.0 / duration / (self.ctx.config.rate / self.ctx.config.sender_threads) as f64);
+        // Only this thread's counters are updated per probe, without taking any lock
+        let sender_threads = self.ctx.sender_threads.clone();
+        let state = &sender_threads.threads[self.thread];
 
-            interval = ((self.ctx.config.rate / self.ctx.config.sender_threads) / 20) as u32;
-            last_time = Instant::now();
-        }
+        let mut transmitter = Transmitter::new(&self.ctx.config, interface_index, gateway_mac);
 
         loop {
-            if delay > 0.0 {
-                count += 1;
-                for _ in 0..delay as u32 {
-                    std::hint::spin_loop();
-                }
-
-                if interval == 0 || (count % interval == 0) {
-                    let t = Instant::now();
-                    let duration = (t - last_time).as_secs_f64();
-                    delay *= (count - last_count) as f64
-                        / duration
-                        / (self.ctx.config.rate / self.ctx.config.sender_threads) as f64;
-
-                    if delay < 1.0 {
-                        delay = 1.0;
-                    }
-
-                    last_count = count;
-                    last_time = t;
-                }
-            }
-
-            // Generate next ip from cyclic group and update global state
-            let mut zsend = self.ctx.sender_state.lock().unwrap();
-            if zsend.complete {
+            if sender_threads.stop.load(Ordering::Relaxed) {
                 break;
             }
 
-            if zsend.sent >= self.ctx.config.max_targets {
-                zsend.complete = true;
-                zsend.finish = Instant::now();
+            if state.sent.load(Ordering::Relaxed) >= self.max_targets {
                 break;
             }
 
             if self.ctx.config.max_runtime > 0
-                && self.ctx.config.max_runtime <= (Instant::now() - zsend.start).as_secs() as u32
+                && self.ctx.config.max_runtime <= (Instant::now() - start).as_secs() as u32
             {
-                zsend.complete = true;
-                zsend.finish = Instant::now();
                 break;
             }
 
-            let mut destination_ip = self.cyclic.lock().unwrap().next_ip();
-            while !self.blacklist.is_allowed(destination_ip) {
-                destination_ip = self.cyclic.lock().unwrap().next_ip();
-                zsend.blacklisted += 1;
-            }
+            // Generate next target from this thread's part of the cyclic group
+            let (destination_ip, destination_port) = match self.next_target() {
+                Some(target) => target,
+                None => break,
+            };
+            state.sent.fetch_add(1, Ordering::Relaxed);
 
-            if destination_ip == zsend.first_scanned {
-                zsend.complete = true;
-                zsend.finish = Instant::now();
+            // The rate covers every probe sent, so that --bandwidth holds. At low rates, probes
+            // are sent before waiting rather than once the batch is full, which could take
+            // seconds and send them all at once.
+            if let Some(rate_limiter) = &self.ctx.rate_limiter {
+                rate_limiter.wait(self.ctx.config.probes as u64, || transmitter.flush(state));
             }
 
-            zsend.sent += 1;
-            drop(zsend);
-
             for i in 0..self.ctx.config.probes {
-                let validation = validate::gen(
-                    &self.ctx.validate_ctx,
-                    &self.ctx.config.source_ip_first,
-                    &destination_ip,
-                );
-                let validation = [
-                    u32::from_be_bytes(validation[0..4].try_into().unwrap()),
-                    u32::from_be_bytes(validation[4..8].try_into().unwrap()),
-                ];
+                let validation =
+                    validate::gen_words(&self.ctx.validate_ctx, &source_ip, &destination_ip)
+                        .expect("Source and destination addresses of different families");
 
-                let packet = probe_module.make_packet(&destination_ip, &validation, i);
                 if self.ctx.config.dryrun {
+                    let packet = probe_generator.make_packet(
+                        &destination_ip,
+                        destination_port,
+                        &validation,
+                        i,
+                    );
                     if !self.ctx.config.quiet {
-                        synscan_print_packet(packet);
-                    }
-                } else {
-                    let res = socket.sendto(packet, interface_index, &gateway_mac);
-                    if let Err(e) = res {
-                        warn!("Sender sendto failed for {destination_ip}. Reason: {}", e);
-                        self.ctx.sender_state.lock().unwrap().sendto_failures += 1;
+                        self.ctx.probe_module.print_packet(packet);
                     }
+                    continue;
                 }
+
+                transmitter.queue(
+                    probe_generator.as_mut(),
+                    (destination_ip, destination_port),
+                    &validation,
+                    i,
+                    state,
+                );
             }
         }
 
+        transmitter.flush(state);
+
+        self.finish();
         debug!("Sender finished");
     }
 }
@@ -1,36 +1,141 @@
-use std::{net::Ipv4Addr, time::Instant};
+use std::{
+    net::Ipv4Addr,
+    sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Instant,
+};
 
 #[derive(Debug)]
 pub struct SenderState {
+    // Set by the first sender thread, which initializes the rest of the state
+    pub initialized: bool,
+    // Set once every sender thread has finished
     pub complete: bool,
+    pub finished_threads: u32,
     pub start: Instant,
     pub finish: Instant,
-    pub sent: u32,
-    pub blacklisted: u32,
+    pub blacklisted: u64,
     pub first_scanned: Ipv4Addr,
-    pub targets: u32,
-    pub sendto_failures: u32,
+    pub targets: u64,
+    // Addresses sent before resuming from a checkpoint
+    pub resumed: u64,
 }
 
 impl Default for SenderState {
     fn default() -> Self {
         Self {
+            initialized: false,
             complete: false,
+            finished_threads: 0,
             start: Instant::now(),
             finish: Instant::now(),
-            sent: 0,
             blacklisted: 0,
             first_scanned: Ipv4Addr::new(0, 0, 0, 0),
             targets: 0,
-            sendto_failures: 0,
+            resumed: 0,
         }
     }
 }
 
+/// Position of a sender thread's cyclic, see Cyclic::position. Checkpoints need both halves from
+/// the same step, or the resumed thread would walk past its part of the permutation, so they are
+/// published together as a sequence lock.
+#[derive(Debug, Default)]
+pub struct CyclicPosition {
+    // Odd while the position is being written
+    sequence: AtomicU64,
+    current: AtomicU64,
+    remaining: AtomicU64,
+}
+
+impl CyclicPosition {
+    /// Only called by the thread the position belongs to
+    pub fn store(&self, current: u64, remaining: u64) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.current.store(current, Ordering::Relaxed);
+        self.remaining.store(remaining, Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    /// Returns `(current, remaining)`, retrying while the thread is writing
+    pub fn load(&self) -> (u64, u64) {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence.is_multiple_of(2) {
+                let position = (
+                    self.current.load(Ordering::Relaxed),
+                    self.remaining.load(Ordering::Relaxed),
+                );
+                fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == sequence {
+                    return position;
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Counters of a single sender thread, only ever updated by that thread
+#[derive(Debug, Default)]
+pub struct SenderThreadState {
+    pub sent: AtomicU64,
+    pub sendto_failures: AtomicU32,
+    // Times the transmit ring was full and the thread had to wait for the kernel
+    pub ring_full: AtomicU64,
+    pub position: CyclicPosition,
+}
+
+/// Sender state that is updated for every probe, kept in atomics rather than behind the
+/// SenderState lock so that sender threads never wait on each other. Readers sum up the threads.
+#[derive(Debug)]
+pub struct SenderThreads {
+    // Tells the sender threads to stop early, e.g. once --max-results is reached
+    pub stop: AtomicBool,
+    pub threads: Box<[SenderThreadState]>,
+}
+
+impl SenderThreads {
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            stop: AtomicBool::new(false),
+            threads: (0..num_threads).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.threads
+            .iter()
+            .map(|thread| thread.sent.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn sendto_failures(&self) -> u32 {
+        self.threads
+            .iter()
+            .map(|thread| thread.sendto_failures.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn ring_full(&self) -> u64 {
+        self.threads
+            .iter()
+            .map(|thread| thread.ring_full.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
 #[derive(Debug)]
 pub struct ReceiverState {
+    // Set once every receiver thread is capturing
     pub ready: bool,
+    // Set once every receiver thread has finished
     pub complete: bool,
+    pub ready_threads: u32,
+    pub finished_threads: u32,
+    // PACKET_FANOUT group shared by the receiver threads
+    pub fanout_group: Option<u16>,
     pub success_unique: u32,
     pub success_total: u32,
     pub cooldown_unique: u32,
@@ -48,6 +153,9 @@ impl Default for ReceiverState {
         Self {
             ready: false,
             complete: false,
+            ready_threads: 0,
+            finished_threads: 0,
+            fanout_group: None,
             success_unique: 0,
             success_total: 0,
             cooldown_unique: 0,
@@ -61,3 +169,31 @@ impl Default for ReceiverState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cyclic_position() {
+        let position = CyclicPosition::default();
+        assert_eq!(position.load(), (0, 0));
+        position.store(0, u64::MAX);
+
+        // Readers never see half of an update
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for step in 0..=100_000 {
+                    position.store(step, u64::MAX - step);
+                }
+            });
+            loop {
+                let (current, remaining) = position.load();
+                assert_eq!(remaining, u64::MAX - current);
+                if current == 100_000 {
+                    break;
+                }
+            }
+        });
+    }
+}